@@ -0,0 +1,217 @@
+//! Guest configuration.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::guest_mem::Endian;
+use crate::loader::{GuestBlob, ImageCheck, DEFAULT_TEST_DATA};
+use crate::vcpu::BootArgs;
+use crate::VM_ENTRY;
+
+/// How a guest is loaded and booted.
+///
+/// The defaults load the exercise guest `/sbin/skernel2` at [`VM_ENTRY`]
+/// with the [`DEFAULT_TEST_DATA`], the `with_*` methods change them.
+#[derive(Debug, Clone)]
+pub struct GuestConfig {
+    /// The guest image file, `-` for the standard input.
+    pub image: String,
+    /// The load address and entry point of raw images.
+    pub entry: usize,
+    /// The size of the header skipped at the start of the image.
+    pub image_offset: usize,
+    /// The checks done on the image and how it is mapped.
+    pub check: ImageCheck,
+    /// The `(offset, value)` pairs written into the page at guest address 0.
+    pub test_data: Vec<(usize, usize)>,
+    /// The size of the guest RAM mapped at `entry` before the image is
+    /// loaded, 0 to map only the image.
+    pub mem_size: usize,
+    /// The byte order of the test data.
+    pub endian: Endian,
+    /// An initrd file and the guest physical address to load it at.
+    pub initrd_image: Option<(String, usize)>,
+    /// The ID of the hart the guest boots on, passed in `a0`.
+    pub hart_id: usize,
+    /// The guest physical address of the DTB, passed in `a1`, 0 if none.
+    pub dtb: usize,
+    /// The initrd, passed in `a2` and `a3`.
+    pub initrd: Option<GuestBlob>,
+    /// The initial stack pointer, 0 to leave `sp` to the guest.
+    pub stack_top: usize,
+    /// The kernel address passed to firmware in `a4`, 0 if none, see
+    /// [`crate::loader::load_firmware_and_kernel`].
+    pub kernel: usize,
+}
+
+impl Default for GuestConfig {
+    fn default() -> Self {
+        Self::new("/sbin/skernel2")
+    }
+}
+
+impl GuestConfig {
+    /// Creates the default config for the guest image `image`.
+    pub fn new(image: &str) -> Self {
+        Self {
+            image: String::from(image),
+            entry: VM_ENTRY,
+            image_offset: 0,
+            check: ImageCheck::default(),
+            test_data: DEFAULT_TEST_DATA.to_vec(),
+            mem_size: 0,
+            endian: Endian::Little,
+            initrd_image: None,
+            hart_id: 0,
+            dtb: 0,
+            initrd: None,
+            stack_top: 0,
+            kernel: 0,
+        }
+    }
+
+    /// Sets the load address and entry point of raw images.
+    pub fn with_entry(mut self, entry: usize) -> Self {
+        self.entry = entry;
+        self
+    }
+
+    /// Sets the size of the image header to skip.
+    pub fn with_image_offset(mut self, image_offset: usize) -> Self {
+        self.image_offset = image_offset;
+        self
+    }
+
+    /// Sets the image checks.
+    pub fn with_check(mut self, check: ImageCheck) -> Self {
+        self.check = check;
+        self
+    }
+
+    /// Sets the test data.
+    pub fn with_test_data(mut self, test_data: &[(usize, usize)]) -> Self {
+        self.test_data = test_data.to_vec();
+        self
+    }
+
+    /// Returns the test value written at `offset`, if any.
+    pub fn test_value(&self, offset: usize) -> Option<usize> {
+        self.test_data
+            .iter()
+            .rev()
+            .find(|&&(o, _)| o == offset)
+            .map(|&(_, value)| value)
+    }
+
+    /// Sets the size of the guest RAM.
+    pub fn with_mem_size(mut self, mem_size: usize) -> Self {
+        self.mem_size = mem_size;
+        self
+    }
+
+    /// Sets the byte the uninitialized guest memory is filled with, see
+    /// [`ImageCheck::fill_pattern`].
+    pub fn with_fill_pattern(mut self, byte: u8) -> Self {
+        self.check.fill_pattern = Some(byte);
+        self
+    }
+
+    /// Reads the image back after it is copied, see
+    /// [`ImageCheck::verify_copy`].
+    pub fn with_verify_copy(mut self, verify: bool) -> Self {
+        self.check.verify_copy = verify;
+        self
+    }
+
+    /// Logs each range mapped for the image, see [`ImageCheck::verbose`].
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.check.verbose = verbose;
+        self
+    }
+
+    /// Sets the byte order of the test data.
+    pub fn with_endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self
+    }
+
+    /// Loads the initrd `fname` at `gpa` along with the image.
+    pub fn with_initrd(mut self, fname: &str, gpa: usize) -> Self {
+        self.initrd_image = Some((String::from(fname), gpa));
+        self
+    }
+
+    /// Returns the boot arguments of a guest entering at `entry`.
+    pub fn boot_args(&self, entry: usize) -> BootArgs {
+        let initrd = self.initrd.unwrap_or(GuestBlob { base: 0, size: 0 });
+        BootArgs {
+            entry,
+            hart_id: self.hart_id,
+            dtb: self.dtb,
+            initrd_base: initrd.base,
+            initrd_size: initrd.size,
+            sp: self.stack_top,
+            kernel: self.kernel,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regs::GprIndex;
+    use crate::vcpu::VmCpuRegisters;
+
+    #[test]
+    fn defaults() {
+        let config = GuestConfig::default();
+        assert_eq!(config.image, "/sbin/skernel2");
+        assert_eq!(config.entry, VM_ENTRY);
+        assert_eq!(config.test_data, DEFAULT_TEST_DATA);
+        assert_eq!(config.test_value(0x40), Some(0x6688));
+        assert_eq!(config.test_value(0x50), None);
+    }
+
+    #[test]
+    fn builder() {
+        let config = GuestConfig::new("/guest.bin")
+            .with_entry(0x8000_0000)
+            .with_image_offset(0x40)
+            .with_mem_size(0x10_0000)
+            .with_fill_pattern(0xa5)
+            .with_verify_copy(true)
+            .with_endian(Endian::Big)
+            .with_initrd("/initrd", 0x8400_0000);
+        assert_eq!(config.image, "/guest.bin");
+        assert_eq!((config.entry, config.image_offset), (0x8000_0000, 0x40));
+        assert_eq!(config.mem_size, 0x10_0000);
+        assert_eq!(config.check.fill_pattern, Some(0xa5));
+        assert!(config.check.verify_copy && !config.check.verbose);
+        assert_eq!(config.endian, Endian::Big);
+        assert_eq!(config.initrd_image, Some((String::from("/initrd"), 0x8400_0000)));
+    }
+
+    #[test]
+    fn test_value_takes_the_last_write() {
+        let config = GuestConfig::new("/guest.bin")
+            .with_test_data(&[(0x40, 1), (0x48, 2), (0x40, 3)]);
+        assert_eq!(config.test_value(0x40), Some(3));
+        assert_eq!(config.test_value(0x48), Some(2));
+    }
+
+    #[test]
+    fn boot_regs() {
+        let mut config = GuestConfig::new("/guest.bin");
+        config.hart_id = 1;
+        config.dtb = 0x8700_0000;
+        config.initrd = Some(GuestBlob { base: 0x8400_0000, size: 0x1000 });
+        let mut vcpu = VmCpuRegisters::default();
+        vcpu.setup_boot_regs(&config.boot_args(VM_ENTRY));
+        let gprs = &vcpu.guest_regs.gprs;
+        assert_eq!(vcpu.guest_regs.sepc, VM_ENTRY);
+        let args = [GprIndex::A0, GprIndex::A1, GprIndex::A2, GprIndex::A3, GprIndex::A4];
+        assert_eq!(args.map(|r| gprs.reg(r)), [1, 0x8700_0000, 0x8400_0000, 0x1000, 0]);
+        // No stack top, `sp` is left as is.
+        assert_eq!(gprs.reg(GprIndex::SP), 0);
+    }
+}
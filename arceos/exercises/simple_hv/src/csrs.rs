@@ -12,6 +12,16 @@ pub struct CSR {
     pub hideleg: ReadWriteCsr<hideleg::Register, CSR_HIDELEG>,
     pub hcounteren: ReadWriteCsr<hcounteren::Register, CSR_HCOUNTEREN>,
     pub hvip: ReadWriteCsr<hvip::Register, CSR_HVIP>,
+    pub htval: ReadWriteCsr<(), CSR_HTVAL>,
+    pub htinst: ReadWriteCsr<(), CSR_HTINST>,
+    pub vsstatus: ReadWriteCsr<(), CSR_VSSTATUS>,
+    pub vstvec: ReadWriteCsr<(), CSR_VSTVEC>,
+    pub vsepc: ReadWriteCsr<(), CSR_VSEPC>,
+    pub vscause: ReadWriteCsr<(), CSR_VSCAUSE>,
+    pub vstval: ReadWriteCsr<(), CSR_VSTVAL>,
+    pub vsatp: ReadWriteCsr<(), CSR_VSATP>,
+    pub vsscratch: ReadWriteCsr<(), CSR_VSSCRATCH>,
+    pub vsie: ReadWriteCsr<(), CSR_VSIE>,
 }
 
 #[allow(clippy::identity_op, clippy::erasing_op)]
@@ -22,6 +32,16 @@ pub const CSR: &CSR = &CSR {
     hideleg: ReadWriteCsr::new(),
     hcounteren: ReadWriteCsr::new(),
     hvip: ReadWriteCsr::new(),
+    htval: ReadWriteCsr::new(),
+    htinst: ReadWriteCsr::new(),
+    vsstatus: ReadWriteCsr::new(),
+    vstvec: ReadWriteCsr::new(),
+    vsepc: ReadWriteCsr::new(),
+    vscause: ReadWriteCsr::new(),
+    vstval: ReadWriteCsr::new(),
+    vsatp: ReadWriteCsr::new(),
+    vsscratch: ReadWriteCsr::new(),
+    vsie: ReadWriteCsr::new(),
 };
 
 /// Trait defining the possible operations on a RISC-V CSR.
@@ -0,0 +1,199 @@
+//! Walks of the guest's own page tables.
+//!
+//! The hardware may leave the accessed and dirty bits of the VS-stage page
+//! tables to software, raising a page fault when one has to be set. Those
+//! faults are not delegated to the guest, the bits are set here instead.
+
+use crate::guest_mem::GuestMemory;
+
+/// The kind of access which faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// An instruction fetch.
+    Fetch,
+    /// A load.
+    Load,
+    /// A store or an AMO.
+    Store,
+}
+
+/// The guest physical memory holding the guest page tables.
+pub trait PteMemory {
+    /// Reads the PTE at `gpa`, `None` if it isn't mapped.
+    fn read_pte(&self, gpa: usize) -> Option<u64>;
+    /// Writes the PTE at `gpa`, returns whether it is mapped.
+    fn write_pte(&self, gpa: usize, pte: u64) -> bool;
+}
+
+impl PteMemory for GuestMemory<'_> {
+    fn read_pte(&self, gpa: usize) -> Option<u64> {
+        self.read_u64(gpa).ok()
+    }
+
+    fn write_pte(&self, gpa: usize, pte: u64) -> bool {
+        self.write_u64(gpa, pte).is_ok()
+    }
+}
+
+const SATP_MODE_SHIFT: usize = 60;
+const SATP_MODE_BARE: usize = 0;
+const SATP_MODE_SV39: usize = 8;
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+const PTE_PPN_SHIFT: usize = 10;
+const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+
+const PAGE_SHIFT: usize = 12;
+const LEVELS: usize = 3;
+const VPN_BITS: usize = 9;
+
+/// Sets the accessed bit, and the dirty bit for a store, of the guest PTE
+/// mapping `gva` in the Sv39 page table of `vsatp`.
+///
+/// Returns `false` if the fault is not one of missing A/D bits, e.g. the
+/// page isn't mapped or the access isn't allowed, it is then the guest's to
+/// handle. The guest TLB must be flushed if `true` is returned.
+pub fn update_access_bits(mem: &impl PteMemory, vsatp: usize, gva: usize, access: Access) -> bool {
+    if vsatp >> SATP_MODE_SHIFT != SATP_MODE_SV39 {
+        return false;
+    }
+    let Some((pte_addr, pte, _)) = walk(mem, vsatp, gva) else {
+        return false;
+    };
+    let bits = match access {
+        Access::Store => PTE_A | PTE_D,
+        _ => PTE_A,
+    };
+    if !allowed(pte, access) || pte & bits == bits {
+        return false;
+    }
+    mem.write_pte(pte_addr, pte | bits)
+}
+
+/// Translates the guest virtual address `gva` to a guest physical one with
+/// the page table of `vsatp`, for an `access` by the guest kernel.
+///
+/// Addresses are not translated when `vsatp` is bare. Returns `None` if the
+/// page isn't mapped, the access isn't allowed, or the translation mode
+/// isn't supported.
+pub fn translate(mem: &impl PteMemory, vsatp: usize, gva: usize, access: Access) -> Option<usize> {
+    match vsatp >> SATP_MODE_SHIFT {
+        SATP_MODE_BARE => Some(gva),
+        SATP_MODE_SV39 => {
+            let (_, pte, level) = walk(mem, vsatp, gva)?;
+            if !allowed(pte, access) {
+                return None;
+            }
+            let page_shift = PAGE_SHIFT + level * VPN_BITS;
+            let base = ((pte >> PTE_PPN_SHIFT & PTE_PPN_MASK) as usize) << PAGE_SHIFT;
+            Some(base & !((1 << page_shift) - 1) | gva & ((1 << page_shift) - 1))
+        }
+        _ => None,
+    }
+}
+
+/// Walks the Sv39 page table of `vsatp` down to the leaf PTE mapping `gva`,
+/// returns its guest physical address, its value and its level.
+fn walk(mem: &impl PteMemory, vsatp: usize, gva: usize) -> Option<(usize, u64, usize)> {
+    let mut table = (vsatp & SATP_PPN_MASK) << PAGE_SHIFT;
+    for level in (0..LEVELS).rev() {
+        let vpn = (gva >> (PAGE_SHIFT + level * VPN_BITS)) & ((1 << VPN_BITS) - 1);
+        let pte_addr = table + vpn * 8;
+        let pte = mem.read_pte(pte_addr)?;
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+            return None;
+        }
+        if pte & (PTE_R | PTE_X) == 0 {
+            // A pointer to the next level.
+            table = ((pte >> PTE_PPN_SHIFT & PTE_PPN_MASK) as usize) << PAGE_SHIFT;
+            continue;
+        }
+        return Some((pte_addr, pte, level));
+    }
+    None
+}
+
+/// Returns whether the leaf `pte` allows `access`.
+fn allowed(pte: u64, access: Access) -> bool {
+    match access {
+        Access::Fetch => pte & PTE_X != 0,
+        Access::Load => pte & PTE_R != 0,
+        Access::Store => pte & PTE_W != 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+    use core::cell::RefCell;
+
+    use super::*;
+
+    impl PteMemory for RefCell<BTreeMap<usize, u64>> {
+        fn read_pte(&self, gpa: usize) -> Option<u64> {
+            self.borrow().get(&gpa).copied()
+        }
+
+        fn write_pte(&self, gpa: usize, pte: u64) -> bool {
+            self.borrow_mut().insert(gpa, pte).is_some()
+        }
+    }
+
+    const VSATP: usize = SATP_MODE_SV39 << SATP_MODE_SHIFT | 1;
+    /// The leaf PTE of [`GVA`], in the last level table at `0x3000`.
+    const LEAF: usize = 0x3008;
+    const GVA: usize = 0x4000_1234;
+
+    fn pte(gpa: usize, flags: u64) -> u64 {
+        ((gpa >> PAGE_SHIFT) << PTE_PPN_SHIFT) as u64 | flags | PTE_V
+    }
+
+    /// Page tables at `0x1000` mapping the page of [`GVA`] with `flags`,
+    /// and a gigapage at `0x8000_0000`.
+    fn tables(flags: u64) -> RefCell<BTreeMap<usize, u64>> {
+        RefCell::new(BTreeMap::from([
+            (0x1008, pte(0x2000, 0)),
+            (0x1010, pte(0x8000_0000, PTE_R | PTE_X | PTE_A)),
+            (0x2000, pte(0x3000, 0)),
+            (LEAF, pte(0x8020_0000, flags)),
+        ]))
+    }
+
+    #[test]
+    fn translate_pages() {
+        let mem = tables(PTE_R | PTE_W);
+        assert_eq!(translate(&mem, VSATP, GVA, Access::Load), Some(0x8020_0234));
+        assert_eq!(translate(&mem, VSATP, GVA, Access::Fetch), None);
+        assert_eq!(translate(&mem, VSATP, 0x8765_4321, Access::Fetch), Some(0x8765_4321));
+        assert_eq!(translate(&mem, VSATP, 0xc000_0000, Access::Load), None);
+        // Bare mode doesn't translate.
+        assert_eq!(translate(&mem, 0, GVA, Access::Store), Some(GVA));
+    }
+
+    #[test]
+    fn sets_accessed_and_dirty() {
+        let mem = tables(PTE_R | PTE_W);
+        assert!(update_access_bits(&mem, VSATP, GVA, Access::Load));
+        assert_eq!(mem.borrow()[&LEAF], pte(0x8020_0000, PTE_R | PTE_W | PTE_A));
+        // Nothing to set, the fault is the guest's.
+        assert!(!update_access_bits(&mem, VSATP, GVA, Access::Load));
+        assert!(update_access_bits(&mem, VSATP, GVA, Access::Store));
+        assert_eq!(mem.borrow()[&LEAF], pte(0x8020_0000, PTE_R | PTE_W | PTE_A | PTE_D));
+        assert!(!update_access_bits(&mem, VSATP, GVA, Access::Store));
+    }
+
+    #[test]
+    fn leaves_genuine_faults() {
+        let mem = tables(PTE_R);
+        assert!(!update_access_bits(&mem, VSATP, GVA, Access::Store));
+        assert_eq!(mem.borrow()[&LEAF], pte(0x8020_0000, PTE_R));
+        assert!(!update_access_bits(&mem, VSATP, 0xc000_0000, Access::Load));
+        assert!(!update_access_bits(&mem, 0, GVA, Access::Load));
+    }
+}
@@ -0,0 +1,252 @@
+//! Accesses to guest physical memory from the hypervisor.
+
+use core::cell::Cell;
+
+use axerrno::{ax_err, ax_err_type};
+use axhal::mem::{phys_to_virt, PAGE_SIZE_4K};
+use axmm::AddrSpace;
+use std::io;
+
+/// A view of the guest physical memory backed by a guest address space.
+///
+/// The guest RAM is not contiguous in host memory, accesses are translated
+/// and split page by page. They fail before any byte is accessed if any page
+/// of the range isn't mapped.
+///
+/// The last translated pages are cached. The address space is borrowed for
+/// the lifetime of the view, so it can't be remapped under the cache: a
+/// view created after a remap starts with an empty cache.
+pub struct GuestMemory<'a> {
+    aspace: &'a AddrSpace,
+    endian: Endian,
+    tlb: Tlb,
+    /// The number of page table walks, for statistics.
+    walks: Cell<usize>,
+}
+
+/// The number of entries of the translation cache.
+const TLB_ENTRIES: usize = 8;
+
+/// Direct-mapped cache of `(gpa page, host virtual address of the page)`.
+#[derive(Default)]
+struct Tlb([Cell<Option<(usize, usize)>>; TLB_ENTRIES]);
+
+impl Tlb {
+    fn slot(&self, page: usize) -> &Cell<Option<(usize, usize)>> {
+        &self.0[(page / PAGE_SIZE_4K) % TLB_ENTRIES]
+    }
+
+    /// Returns the cached host address of the page `page`.
+    fn lookup(&self, page: usize) -> Option<usize> {
+        self.slot(page)
+            .get()
+            .filter(|&(cached, _)| cached == page)
+            .map(|(_, hva)| hva)
+    }
+
+    /// Caches the translation of `page`, evicting the one in its slot.
+    fn insert(&self, page: usize, hva: usize) {
+        self.slot(page).set(Some((page, hva)));
+    }
+}
+
+/// Splits `[gpa, gpa + size)` at page boundaries, yields the
+/// `(address, offset from gpa, length)` of each chunk.
+fn page_chunks(gpa: usize, size: usize) -> impl Iterator<Item = (usize, usize, usize)> {
+    let mut done = 0;
+    core::iter::from_fn(move || {
+        if done >= size {
+            return None;
+        }
+        let addr = gpa + done;
+        let len = (PAGE_SIZE_4K - addr % PAGE_SIZE_4K).min(size - done);
+        let chunk = (addr, done, len);
+        done += len;
+        Some(chunk)
+    })
+}
+
+/// The byte order of integers in guest memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endian {
+    /// Little endian, the byte order of RISC-V guests.
+    #[default]
+    Little,
+    /// Big endian.
+    Big,
+}
+
+impl Endian {
+    /// Converts between little-endian bytes and bytes in this order, the
+    /// conversion is its own inverse.
+    fn order<const N: usize>(self, mut bytes: [u8; N]) -> [u8; N] {
+        if self == Endian::Big {
+            bytes.reverse();
+        }
+        bytes
+    }
+}
+
+/// Defines the `write_uN`/`read_uN` helpers and their variants with an
+/// explicit byte order.
+macro_rules! int_accessors {
+    ($($ty:ty: $write:ident, $read:ident, $write_endian:ident, $read_endian:ident;)*) => {$(
+        #[doc = concat!("Writes a `", stringify!($ty), "` in the guest byte order at `gpa`.")]
+        pub fn $write(&self, gpa: usize, val: $ty) -> io::Result<()> {
+            self.$write_endian(gpa, val, self.endian)
+        }
+
+        #[doc = concat!("Reads a `", stringify!($ty), "` in the guest byte order at `gpa`.")]
+        pub fn $read(&self, gpa: usize) -> io::Result<$ty> {
+            self.$read_endian(gpa, self.endian)
+        }
+
+        #[doc = concat!("Writes a `", stringify!($ty), "` in the byte order `endian` at `gpa`.")]
+        pub fn $write_endian(&self, gpa: usize, val: $ty, endian: Endian) -> io::Result<()> {
+            self.write_at(gpa, &endian.order(val.to_le_bytes()))
+        }
+
+        #[doc = concat!("Reads a `", stringify!($ty), "` in the byte order `endian` at `gpa`.")]
+        pub fn $read_endian(&self, gpa: usize, endian: Endian) -> io::Result<$ty> {
+            let mut buf = [0; core::mem::size_of::<$ty>()];
+            self.read_at(gpa, &mut buf)?;
+            Ok(<$ty>::from_le_bytes(endian.order(buf)))
+        }
+    )*};
+}
+
+impl<'a> GuestMemory<'a> {
+    /// Creates a view of the memory of `aspace`, for a little-endian guest.
+    pub fn new(aspace: &'a AddrSpace) -> Self {
+        Self {
+            aspace,
+            endian: Endian::Little,
+            tlb: Default::default(),
+            walks: Cell::new(0),
+        }
+    }
+
+    /// Returns the number of page table walks done by this view, i.e. the
+    /// translations which missed the cache.
+    pub fn walks(&self) -> usize {
+        self.walks.get()
+    }
+
+    /// Sets the byte order of the guest, used by `write_u64` and alike.
+    pub fn with_endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self
+    }
+
+    int_accessors! {
+        u64: write_u64, read_u64, write_u64_endian, read_u64_endian;
+        u32: write_u32, read_u32, write_u32_endian, read_u32_endian;
+        u16: write_u16, read_u16, write_u16_endian, read_u16_endian;
+    }
+
+    /// Returns whether all of `[gpa, gpa + size)` is mapped.
+    pub fn is_mapped(&self, gpa: usize, size: usize) -> bool {
+        gpa.checked_add(size).is_some()
+            && self.aspace.contains_range(gpa.into(), size)
+            && self.aspace.is_range_mapped(gpa.into(), size)
+    }
+
+    /// Copies `data` to guest memory at `gpa`.
+    pub fn write_at(&self, gpa: usize, data: &[u8]) -> io::Result<()> {
+        self.for_each_chunk(gpa, data.len(), |hva, off, len| unsafe {
+            core::ptr::copy_nonoverlapping(data[off..].as_ptr(), hva, len);
+        })
+    }
+
+    /// Copies guest memory at `gpa` to `buf`.
+    pub fn read_at(&self, gpa: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.for_each_chunk(gpa, buf.len(), |hva, off, len| unsafe {
+            core::ptr::copy_nonoverlapping(hva, buf[off..].as_mut_ptr(), len);
+        })
+    }
+
+    /// Sets `len` bytes of guest memory at `gpa` to `byte`.
+    pub fn fill(&self, gpa: usize, byte: u8, len: usize) -> io::Result<()> {
+        self.for_each_chunk(gpa, len, |hva, _, len| unsafe {
+            core::ptr::write_bytes(hva, byte, len);
+        })
+    }
+
+    /// Calls `f(hva, offset, len)` for each page-bounded chunk of
+    /// `[gpa, gpa + size)`, where `offset` is from `gpa`.
+    fn for_each_chunk(
+        &self,
+        gpa: usize,
+        size: usize,
+        mut f: impl FnMut(*mut u8, usize, usize),
+    ) -> io::Result<()> {
+        // Check the whole range first, so that an access running past the
+        // mapping fails without touching anything.
+        if !self.is_mapped(gpa, size) {
+            return ax_err!(BadAddress, "guest range not mapped");
+        }
+        for (addr, offset, len) in page_chunks(gpa, size) {
+            f(self.translate(addr)? as *mut u8, offset, len);
+        }
+        Ok(())
+    }
+
+    /// Returns the host virtual address of `gpa`, from the cache if its page
+    /// was translated recently.
+    fn translate(&self, gpa: usize) -> io::Result<usize> {
+        let page = gpa & !(PAGE_SIZE_4K - 1);
+        if let Some(hva) = self.tlb.lookup(page) {
+            return Ok(hva + gpa % PAGE_SIZE_4K);
+        }
+        self.walks.set(self.walks.get() + 1);
+        let (paddr, _, _) = self.aspace.page_table().query(page.into()).map_err(|_| {
+            ax_err_type!(BadAddress, format_args!("guest address {:#x} not mapped", gpa))
+        })?;
+        let hva = phys_to_virt(paddr).as_usize();
+        self.tlb.insert(page, hva);
+        Ok(hva + gpa % PAGE_SIZE_4K)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn chunks_split_at_pages() {
+        let chunks: Vec<_> = page_chunks(0x1ffc, 0x1008).collect();
+        assert_eq!(
+            chunks,
+            [(0x1ffc, 0, 4), (0x2000, 4, 0x1000), (0x3000, 0x1004, 4)]
+        );
+        assert_eq!(page_chunks(0x3000, 0x1000).count(), 1);
+        assert_eq!(page_chunks(0x3000, 0).count(), 0);
+    }
+
+    #[test]
+    fn tlb_hits_and_evicts() {
+        let tlb = Tlb::default();
+        assert_eq!(tlb.lookup(0x1000), None);
+        tlb.insert(0x1000, 0xffff_0000);
+        assert_eq!(tlb.lookup(0x1000), Some(0xffff_0000));
+        // Same slot, different page.
+        let alias = 0x1000 + TLB_ENTRIES * PAGE_SIZE_4K;
+        assert_eq!(tlb.lookup(alias), None);
+        tlb.insert(alias, 0xeeee_0000);
+        assert_eq!(tlb.lookup(0x1000), None);
+        assert_eq!(tlb.lookup(alias), Some(0xeeee_0000));
+        // Other slots are untouched.
+        tlb.insert(0x2000, 0xdddd_0000);
+        assert_eq!(tlb.lookup(alias), Some(0xeeee_0000));
+    }
+
+    #[test]
+    fn endian_order() {
+        let le = 0x1234_5678u32.to_le_bytes();
+        assert_eq!(Endian::Little.order(le), le);
+        assert_eq!(Endian::Big.order(le), 0x1234_5678u32.to_be_bytes());
+        assert_eq!(u32::from_le_bytes(Endian::Big.order(Endian::Big.order(le))), 0x1234_5678);
+    }
+}
@@ -0,0 +1,111 @@
+//! Decompression of gzip-compressed guest images.
+
+use alloc::vec::Vec;
+use axerrno::{ax_err, ax_err_type};
+use std::io;
+
+use crate::loader::crc32;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const CM_DEFLATE: u8 = 8;
+
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+const HEADER_SIZE: usize = 10;
+const TRAILER_SIZE: usize = 8;
+
+/// Returns whether `image` starts with the gzip magic.
+pub fn is_gzip(image: &[u8]) -> bool {
+    image.starts_with(GZIP_MAGIC)
+}
+
+/// Decompresses a gzip member, checking its CRC32 and size.
+pub fn decompress(image: &[u8]) -> io::Result<Vec<u8>> {
+    if image.len() < HEADER_SIZE + TRAILER_SIZE || image[2] != CM_DEFLATE {
+        return ax_err!(InvalidData, "bad gzip header");
+    }
+    let flags = image[3];
+    let mut pos = HEADER_SIZE;
+    if flags & FEXTRA != 0 {
+        let xlen = image
+            .get(pos..pos + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize);
+        pos += 2 + xlen.unwrap_or(image.len());
+    }
+    // The file name and the comment are NUL terminated.
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            match image.get(pos..).and_then(|s| s.iter().position(|&b| b == 0)) {
+                Some(len) => pos += len + 1,
+                None => return ax_err!(InvalidData, "truncated gzip header"),
+            }
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    let Some(body_end) = image.len().checked_sub(TRAILER_SIZE).filter(|&end| end >= pos) else {
+        return ax_err!(InvalidData, "truncated gzip image");
+    };
+
+    let data = miniz_oxide::inflate::decompress_to_vec(&image[pos..body_end])
+        .map_err(|_| ax_err_type!(InvalidData, "corrupt gzip image"))?;
+
+    let trailer = &image[body_end..];
+    let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+    if crc32(&data) != crc || data.len() as u32 != size {
+        return ax_err!(InvalidData, "gzip CRC32 or size mismatch");
+    }
+    ax_println!("gzip image: {} -> {} bytes", image.len(), data.len());
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compresses `data` into a gzip member with the file name `name`.
+    fn gzip(data: &[u8], name: Option<&[u8]>) -> Vec<u8> {
+        let mut image = Vec::from(GZIP_MAGIC);
+        image.extend([CM_DEFLATE, name.map_or(0, |_| FNAME), 0, 0, 0, 0, 0, 3]);
+        if let Some(name) = name {
+            image.extend(name);
+            image.push(0);
+        }
+        image.extend(miniz_oxide::deflate::compress_to_vec(data, 6));
+        image.extend(crc32(data).to_le_bytes());
+        image.extend((data.len() as u32).to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let image = gzip(&data, None);
+        assert!(is_gzip(&image));
+        assert_eq!(decompress(&image).unwrap(), data);
+        let image = gzip(&data, Some(b"skernel2"));
+        assert_eq!(decompress(&image).unwrap(), data);
+    }
+
+    #[test]
+    fn corrupt() {
+        let image = gzip(b"guest image", None);
+        assert!(decompress(&image[..HEADER_SIZE + TRAILER_SIZE - 1]).is_err());
+        // A wrong CRC32.
+        let mut bad = image.clone();
+        let crc = bad.len() - TRAILER_SIZE;
+        bad[crc] ^= 1;
+        assert!(decompress(&bad).is_err());
+        // A file name running off the end.
+        let mut bad = Vec::from(&image[..HEADER_SIZE]);
+        bad[3] = FNAME;
+        bad.extend([b'x'; TRAILER_SIZE]);
+        assert!(decompress(&bad).is_err());
+        assert!(!is_gzip(b"\x7fELF"));
+    }
+}
@@ -0,0 +1,65 @@
+//! Hypercalls, i.e. calls from the guest to the hypervisor itself.
+//!
+//! A hypercall is an `ecall` with the hypervisor extension ID in `a7`, which
+//! is in the firmware specific range of SBI, and the call number in `a6`.
+//! Arguments are passed in `a0`-`a5`, the result is returned in `a0`.
+
+use crate::regs::GprIndex::{A0, A6, A7};
+use crate::vcpu::VmCpuRegisters;
+use crate::vmexit::VmExitAction;
+
+/// The SBI extension ID of hypercalls.
+pub const HYPERCALL_EID: usize = 0x0A00_0000;
+
+/// Prints the byte in `a0` on the host console.
+pub const HC_PUTCHAR: usize = 0;
+/// Stops the guest, with the exit code in `a0`.
+pub const HC_SHUTDOWN: usize = 1;
+
+/// Returned in `a0` for an unknown call number.
+const HC_ERR_UNKNOWN: usize = -1isize as usize;
+
+type HypercallHandler = fn(&mut VmCpuRegisters) -> VmExitAction;
+
+/// Hypercall dispatch table, sorted by call number.
+static HYPERCALL_TABLE: &[(usize, HypercallHandler)] = &[
+    (HC_PUTCHAR, hc_putchar),
+    (HC_SHUTDOWN, hc_shutdown),
+];
+
+/// Returns whether the `ecall` in `ctx` is a hypercall.
+pub fn is_hypercall(ctx: &VmCpuRegisters) -> bool {
+    ctx.guest_regs.gprs.reg(A7) == HYPERCALL_EID
+}
+
+/// Handles the hypercall in `ctx`.
+///
+/// If the guest is resumed, its PC is moved past the `ecall`.
+pub fn handle_hypercall(ctx: &mut VmCpuRegisters) -> VmExitAction {
+    let num = ctx.guest_regs.gprs.reg(A6);
+    let action = match HYPERCALL_TABLE.binary_search_by_key(&num, |(n, _)| *n) {
+        Ok(idx) => (HYPERCALL_TABLE[idx].1)(ctx),
+        Err(_) => {
+            warn!("Unknown hypercall: {}", num);
+            ctx.guest_regs.gprs.set_reg(A0, HC_ERR_UNKNOWN);
+            VmExitAction::Resume
+        }
+    };
+    if action == VmExitAction::Resume {
+        ctx.guest_regs.sepc += 4;
+    }
+    action
+}
+
+fn hc_putchar(ctx: &mut VmCpuRegisters) -> VmExitAction {
+    let c = ctx.guest_regs.gprs.reg(A0) as u8;
+    ax_print!("{}", c as char);
+    ctx.guest_regs.gprs.set_reg(A0, 0);
+    VmExitAction::Resume
+}
+
+fn hc_shutdown(ctx: &mut VmCpuRegisters) -> VmExitAction {
+    let code = ctx.guest_regs.gprs.reg(A0);
+    ax_println!("Guest shutdown by hypercall, code {}", code);
+    VmExitAction::Shutdown(code)
+}
@@ -0,0 +1,178 @@
+//! Decoding of the guest loads and stores which trap to the hypervisor.
+
+use crate::regs::GprIndex;
+
+const OPCODE_LOAD: u32 = 0x03;
+const OPCODE_STORE: u32 = 0x23;
+
+/// The quadrants of compressed instructions, in their two low bits.
+const C_QUADRANT_0: u16 = 0b00;
+const C_QUADRANT_2: u16 = 0b10;
+
+/// A decoded load or store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    /// Whether it is a store.
+    pub write: bool,
+    /// The access width in bytes.
+    pub width: usize,
+    /// The destination register of a load, the source one of a store.
+    pub reg: GprIndex,
+    /// Whether a load sign extends the value, i.e. `lb`, `lh` and `lw`.
+    pub sign_extend: bool,
+    /// The length of the original instruction in bytes.
+    pub len: usize,
+}
+
+impl MemAccess {
+    /// Extends a `width`-byte value read by a load to the register width.
+    pub fn extend(&self, value: u64) -> u64 {
+        let bits = self.width * 8;
+        if bits >= 64 {
+            value
+        } else if self.sign_extend {
+            (((value << (64 - bits)) as i64) >> (64 - bits)) as u64
+        } else {
+            value & ((1 << bits) - 1)
+        }
+    }
+}
+
+/// Decodes `lb/lh/lw/ld`, their unsigned variants and `sb/sh/sw/sd`.
+///
+/// `inst` is either a 32-bit instruction or one transformed by the hardware
+/// into `htinst`, in which bit 1 is clear if the original was compressed.
+/// Returns `None` for any other instruction.
+pub fn decode_load_store(inst: u32) -> Option<MemAccess> {
+    // Bit 0 is set for both forms, a raw compressed instruction can't be
+    // told apart from a transformed one and isn't supported.
+    if inst & 1 == 0 {
+        return None;
+    }
+    let len = if inst & 0b10 != 0 { 4 } else { 2 };
+    let inst = inst | 0b10;
+
+    let funct3 = (inst >> 12) & 0b111;
+    let width = 1usize << (funct3 & 0b11);
+    match inst & 0x7f {
+        // There is no `ldu`.
+        OPCODE_LOAD if funct3 != 0b111 => Some(MemAccess {
+            write: false,
+            width,
+            reg: GprIndex::from_raw((inst >> 7) & 0x1f)?,
+            sign_extend: funct3 & 0b100 == 0,
+            len,
+        }),
+        OPCODE_STORE if funct3 & 0b100 == 0 => Some(MemAccess {
+            write: true,
+            width,
+            reg: GprIndex::from_raw((inst >> 20) & 0x1f)?,
+            sign_extend: false,
+            len,
+        }),
+        _ => None,
+    }
+}
+
+/// Decodes a load or store as it is in guest memory, compressed or not.
+///
+/// Besides the instructions of [`decode_load_store`], it supports the
+/// compressed integer loads and stores, i.e. `c.lw/c.ld/c.sw/c.sd` and their
+/// stack-pointer relative forms. Returns `None` for any other instruction.
+pub fn decode_raw(inst: u32) -> Option<MemAccess> {
+    if inst & 0b11 == 0b11 {
+        return decode_load_store(inst);
+    }
+    let inst = inst as u16;
+    let funct3 = inst >> 13;
+    // `c.lw/c.sw` and `c.ld/c.sd`, no FP ones.
+    let width = match funct3 & 0b11 {
+        0b10 => 4,
+        0b11 => 8,
+        _ => return None,
+    };
+    let write = funct3 & 0b100 != 0;
+    let quadrant = inst & 0b11;
+    let reg = match quadrant {
+        // `rd'` or `rs2'` in bits 4:2, one of `x8-x15`.
+        C_QUADRANT_0 => ((inst >> 2) & 0b111) + 8,
+        // `rd` in bits 11:7 for loads, `rs2` in bits 6:2 for stores.
+        C_QUADRANT_2 if write => (inst >> 2) & 0x1f,
+        C_QUADRANT_2 => (inst >> 7) & 0x1f,
+        _ => return None,
+    };
+    // `c.lwsp` and `c.ldsp` with `rd` 0 are reserved.
+    if quadrant == C_QUADRANT_2 && !write && reg == 0 {
+        return None;
+    }
+    Some(MemAccess {
+        write,
+        width,
+        reg: GprIndex::from_raw(reg as u32)?,
+        sign_extend: !write && width < 8,
+        len: 2,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_lw() {
+        // lw a0, 8(a1)
+        let access = decode_load_store(0x0085_a503).unwrap();
+        assert_eq!(
+            access,
+            MemAccess { write: false, width: 4, reg: GprIndex::A0, sign_extend: true, len: 4 }
+        );
+        assert_eq!(access.extend(0x8000_0000), 0xffff_ffff_8000_0000);
+    }
+
+    #[test]
+    fn decode_unsigned_load_and_store() {
+        // lhu t0, 0(a0)
+        let access = decode_load_store(0x0005_5283).unwrap();
+        assert_eq!((access.width, access.reg, access.sign_extend), (2, GprIndex::T0, false));
+        assert_eq!(access.extend(0xffff_8000), 0x8000);
+        // sd a2, 16(sp)
+        let access = decode_load_store(0x00c1_3823).unwrap();
+        assert_eq!((access.write, access.width, access.reg), (true, 8, GprIndex::A2));
+    }
+
+    #[test]
+    fn decode_transformed() {
+        // The transformed `c.lw a0, 0(a1)`: `lw a0, 0(a1)` with bit 1 clear.
+        let access = decode_load_store(0x0005_a501).unwrap();
+        assert_eq!((access.width, access.reg, access.len), (4, GprIndex::A0, 2));
+    }
+
+    #[test]
+    fn decode_other() {
+        // addi a0, a0, 1, `ldu` and a raw compressed instruction.
+        assert_eq!(decode_load_store(0x0015_0513), None);
+        assert_eq!(decode_load_store(0x0005_7503), None);
+        assert_eq!(decode_load_store(0x4188), None);
+    }
+
+    #[test]
+    fn decode_compressed() {
+        // c.lw a0, 0(a1)
+        let access = decode_raw(0x4188).unwrap();
+        assert_eq!(
+            access,
+            MemAccess { write: false, width: 4, reg: GprIndex::A0, sign_extend: true, len: 2 }
+        );
+        // c.sd a5, 8(a0)
+        let access = decode_raw(0xe51c).unwrap();
+        assert_eq!((access.write, access.width, access.reg), (true, 8, GprIndex::A5));
+        // c.ldsp ra, 8(sp) and c.swsp a0, 4(sp)
+        let access = decode_raw(0x60a2).unwrap();
+        assert_eq!((access.write, access.width, access.reg), (false, 8, GprIndex::RA));
+        let access = decode_raw(0xc22a).unwrap();
+        assert_eq!((access.write, access.width, access.reg), (true, 4, GprIndex::A0));
+        // c.fld and a full-size load.
+        assert_eq!(decode_raw(0x2188), None);
+        assert_eq!(decode_raw(0x0085_a503).map(|a| a.len), Some(4));
+    }
+}
@@ -0,0 +1,115 @@
+//! Virtual interrupts and exceptions injected into the guest.
+//!
+//! The pending virtual interrupts of a guest are kept in [`VmCpuRegisters`]
+//! and written to `hvip` right before entering it, the guest then takes them
+//! as VS-level interrupts if it has enabled them. Exceptions are injected
+//! by doing what the hardware does on a trap to VS-mode.
+
+use crate::csrs::traps::interrupt;
+use crate::csrs::{RiscvCsrTrait, CSR};
+use crate::vcpu::VmCpuRegisters;
+
+/// The virtual interrupts the hypervisor injects, delegated to VS-mode.
+const VIRTUAL_IRQS: usize = interrupt::VIRTUAL_SUPERVISOR_TIMER
+    | interrupt::VIRTUAL_SUPERVISOR_EXTERNAL
+    | interrupt::VIRTUAL_SUPERVISOR_SOFT;
+
+/// Delegates the virtual interrupts to VS-mode.
+pub fn init_irq_delegation() {
+    CSR.hideleg.read_and_set_bits(VIRTUAL_IRQS);
+}
+
+/// Makes a timer interrupt pending for the guest.
+pub fn inject_timer_interrupt(ctx: &mut VmCpuRegisters) {
+    ctx.pending_irqs |= interrupt::VIRTUAL_SUPERVISOR_TIMER;
+}
+
+/// Clears the pending timer interrupt of the guest.
+pub fn clear_timer_interrupt(ctx: &mut VmCpuRegisters) {
+    ctx.pending_irqs &= !interrupt::VIRTUAL_SUPERVISOR_TIMER;
+}
+
+/// Returns whether a timer interrupt is pending for the guest.
+#[allow(dead_code)] // For the host side, the guest sees it in `vsip`.
+pub fn timer_interrupt_pending(ctx: &VmCpuRegisters) -> bool {
+    ctx.pending_irqs & interrupt::VIRTUAL_SUPERVISOR_TIMER != 0
+}
+
+/// Writes the pending virtual interrupts of the guest to `hvip`.
+pub fn sync_pending_irqs(ctx: &VmCpuRegisters) {
+    CSR.hvip.read_and_clear_bits(VIRTUAL_IRQS & !ctx.pending_irqs);
+    CSR.hvip.read_and_set_bits(ctx.pending_irqs & VIRTUAL_IRQS);
+}
+
+/// Arms the timer of the guest for `deadline`, in timer ticks.
+///
+/// The guest time is the host time. A pending timer interrupt of the guest
+/// is cleared, as the SBI requires.
+pub fn set_guest_timer(ctx: &mut VmCpuRegisters, deadline: u64) {
+    clear_timer_interrupt(ctx);
+    ctx.timer_deadline = Some(deadline);
+    arm_host_timer(ctx);
+}
+
+/// Returns whether the guest timer is armed.
+pub fn guest_timer_armed(ctx: &VmCpuRegisters) -> bool {
+    ctx.timer_deadline.is_some()
+}
+
+/// Sets the host timer for whichever of the guest timer and the ArceOS tick
+/// comes first.
+///
+/// ArceOS sets the host timer for its tick on its own, so this is done again
+/// right before entering the guest.
+pub fn arm_host_timer(ctx: &VmCpuRegisters) {
+    let host_deadline = axhal::time::oneshot_deadline_ticks();
+    sbi_rt::set_timer(ctx.timer_deadline.map_or(host_deadline, |d| d.min(host_deadline)));
+}
+
+/// Handles a host timer interrupt taken while the guest was running.
+///
+/// The interrupt goes to the guest if its deadline has passed, and to the
+/// ArceOS timer handler if the tick is due, which sets the next tick. The
+/// host timer is then set for the next deadline, which also clears the
+/// pending host interrupt.
+pub fn handle_host_timer(ctx: &mut VmCpuRegisters) {
+    let now = axhal::time::current_ticks();
+    if ctx.timer_deadline.is_some_and(|deadline| now >= deadline) {
+        ctx.timer_deadline = None;
+        inject_timer_interrupt(ctx);
+    }
+    if now >= axhal::time::oneshot_deadline_ticks() {
+        if let Some(handler) = axhal::trap::IRQ.first() {
+            handler(axhal::time::TIMER_IRQ_NUM);
+        }
+    }
+    arm_host_timer(ctx);
+}
+
+const SSTATUS_SIE: usize = 1 << 1;
+const SSTATUS_SPIE: usize = 1 << 5;
+const SSTATUS_SPP: usize = 1 << 8;
+
+/// Makes the guest take the exception `cause` with the trap value `tval`.
+///
+/// The guest trap CSRs are set up as the hardware would for a trap to
+/// VS-mode, and the guest resumes at its trap vector.
+pub fn inject_exception(ctx: &mut VmCpuRegisters, cause: usize, tval: usize) {
+    CSR.vsepc.write_value(ctx.guest_regs.sepc);
+    CSR.vscause.write_value(cause);
+    CSR.vstval.write_value(tval);
+
+    // `sstatus.SPP` of the host tells whether the guest was in VS or VU-mode,
+    // it becomes the previous mode of the guest.
+    let mut vsstatus = CSR.vsstatus.get_value() & !(SSTATUS_SPIE | SSTATUS_SPP | SSTATUS_SIE);
+    if CSR.vsstatus.get_value() & SSTATUS_SIE != 0 {
+        vsstatus |= SSTATUS_SPIE;
+    }
+    vsstatus |= ctx.guest_regs.sstatus & SSTATUS_SPP;
+    CSR.vsstatus.write_value(vsstatus);
+
+    // The handler runs in VS-mode. Exceptions always go to the base address,
+    // even in vectored mode.
+    ctx.guest_regs.sstatus |= SSTATUS_SPP;
+    ctx.guest_regs.sepc = CSR.vstvec.get_value() & !0b11;
+}
@@ -1,56 +1,934 @@
 use std::io::{self, Read};
 use std::fs::File;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use axerrno::{ax_err, ax_err_type};
 use axhal::paging::MappingFlags;
-use axhal::mem::{PAGE_SIZE_4K, phys_to_virt};
+use axhal::mem::PAGE_SIZE_4K;
 use axmm::AddrSpace;
+use elf::abi::{
+    DT_NULL, DT_RELA, DT_RELAENT, DT_RELASZ, ELFMAGIC, EM_RISCV, ET_DYN, PF_R, PF_W, PF_X,
+    PT_LOAD, R_RISCV_RELATIVE,
+};
+use elf::endian::LittleEndian;
+use elf::file::Class;
+use elf::relocation::{Rela, RelaIterator};
+use elf::segment::ProgramHeader;
+use elf::ElfBytes;
+use crate::config::GuestConfig;
+use crate::gzip;
+use crate::guest_mem::{Endian, GuestMemory};
+use crate::vm::Vm;
 use crate::VM_ENTRY;
 
-pub fn load_vm_image(fname: &str, uspace: &mut AddrSpace) -> io::Result<()> {
-    let mut buf = [0u8; 64];
-    load_file(fname, &mut buf)?;
+/// Checks done on a guest image before anything is mapped, and how it is
+/// mapped.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImageCheck {
+    /// If set, raw images must start with this magic. ELF images are always
+    /// accepted.
+    pub magic: Option<&'static [u8]>,
+    /// If set, the last 4 bytes of the image are the little-endian CRC32 of
+    /// the rest, they are verified and stripped.
+    pub crc32: bool,
+    /// If set, no page of the image is both writable and executable: raw
+    /// images are mapped read-only and executable, ELF segments asking for
+    /// both are rejected. Otherwise raw images are mapped RWX.
+    pub w_xor_x: bool,
+    /// If set, the pages newly mapped for the image are filled with this
+    /// byte before it is copied, so that guest reads of memory the image
+    /// doesn't cover stand out. The BSS is still zeroed.
+    pub fill_pattern: Option<u8>,
+    /// If set, the image is read back from guest memory after it is copied
+    /// and compared to the file, to catch a wrong translation. A debugging
+    /// aid, it doubles the cost of the copy.
+    pub verify_copy: bool,
+    /// If set, each range mapped for the image is logged with its host
+    /// physical base and flags.
+    pub verbose: bool,
+}
+
+/// The format of a guest image, detected from its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// A flat binary.
+    Raw,
+    /// An ELF file.
+    Elf,
+}
+
+/// A guest image loaded by [`load_vm_image`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadedImage {
+    /// The detected image format.
+    pub format: ImageFormat,
+    /// The guest entry point.
+    pub entry: usize,
+    /// The number of image bytes copied into guest memory.
+    pub size: usize,
+    /// The initrd loaded along with the image, if any.
+    pub initrd: Option<GuestBlob>,
+}
+
+/// Test data of the exercise guest, which reads `0x6688` from `0x40`.
+pub const DEFAULT_TEST_DATA: &[(usize, usize)] = &[(0x40, 0x6688), (0x48, 0x1234)];
+
+/// The offset of the test value the exercise guest reads and exits with.
+pub const TEST_RESULT_OFFSET: usize = 0x40;
+
+/// Loads the guest image `fname` into `uspace` with the default entry
+/// [`VM_ENTRY`], no header, no image check and the [`DEFAULT_TEST_DATA`].
+pub fn load_default_vm_image(fname: &str, uspace: &mut AddrSpace) -> io::Result<LoadedImage> {
+    load_vm_image(&GuestConfig::new(fname), uspace)
+}
 
-    // 映射客户机代码页
-    uspace.map_alloc(VM_ENTRY.into(), PAGE_SIZE_4K, MappingFlags::READ|MappingFlags::WRITE|MappingFlags::EXECUTE|MappingFlags::USER, true).unwrap();
+/// Loads the guest image of `config` into `uspace`, returns its entry point
+/// and how many bytes were copied.
+/// An image file of `-` reads the image from the standard input.
+///
+/// ELF images are loaded segment by segment and enter at `e_entry`, any other
+/// file is taken as a raw binary which is loaded at and enters at
+/// `config.entry`. Position-independent ELF images are loaded with the bias
+/// `config.entry`. The first `config.image_offset` bytes of the image are a
+/// header which is skipped.
+///
+/// Each `(offset, value)` pair of the test data is written as a `usize` into
+/// the page at guest address 0. The initrd of `config`, if any, is loaded
+/// last.
+pub fn load_vm_image(config: &GuestConfig, uspace: &mut AddrSpace) -> io::Result<LoadedImage> {
+    let image = load_file(&config.image)?;
+    load_vm_image_from_bytes(&image, config, uspace)
+}
 
-    let (paddr, _, _) = uspace
-        .page_table()
-        .query(VM_ENTRY.into())
-        .unwrap_or_else(|_| panic!("Mapping failed for segment: {:#x}", VM_ENTRY));
+/// Loads the guest image `fname` with positional arguments, see
+/// [`load_vm_image`].
+pub fn load_vm_image_at(
+    fname: &str,
+    entry: usize,
+    image_offset: usize,
+    check: &ImageCheck,
+    test_data: &[(usize, usize)],
+    uspace: &mut AddrSpace,
+) -> io::Result<LoadedImage> {
+    let config = GuestConfig::new(fname)
+        .with_entry(entry)
+        .with_image_offset(image_offset)
+        .with_check(*check)
+        .with_test_data(test_data);
+    load_vm_image(&config, uspace)
+}
 
-    ax_println!("paddr: {:#x}", paddr);
+/// Loads a guest image already in memory, e.g. one embedded with
+/// `include_bytes!`, the same way as [`load_vm_image`]. The image file of
+/// `config` is ignored.
+pub fn load_vm_image_from_bytes(
+    image: &[u8],
+    config: &GuestConfig,
+    uspace: &mut AddrSpace,
+) -> io::Result<LoadedImage> {
+    load_image_bytes(image, config, false, uspace)
+}
+
+/// Loads the guest image `fname` over the one previously loaded into `vm`,
+/// with the same config.
+///
+/// The existing guest mappings are reused, nothing is mapped: the new image
+/// must fit in the pages of the old one. Its BSS and the test data are
+/// written again, the initrd is left as is.
+pub fn reload_vm_image(fname: &str, vm: &mut Vm) -> io::Result<LoadedImage> {
+    let Some(config) = vm.load_config.clone() else {
+        return ax_err!(BadState, "no guest image to reload");
+    };
+    let image = load_file(fname)?;
+    load_image_bytes(&image, &config, true, &mut vm.aspace)
+}
+
+/// Loads `image` with `config`, reusing the existing mappings if `reuse` is
+/// set.
+fn load_image_bytes(
+    image: &[u8],
+    config: &GuestConfig,
+    reuse: bool,
+    uspace: &mut AddrSpace,
+) -> io::Result<LoadedImage> {
+    let GuestConfig { entry, image_offset, ref check, ref test_data, mem_size, endian, .. } = *config;
+    if entry % PAGE_SIZE_4K != 0 {
+        return ax_err!(InvalidInput, "guest entry not page aligned");
+    }
+    let word = core::mem::size_of::<usize>();
+    if test_data
+        .iter()
+        .any(|&(off, _)| off % word != 0 || off + word > PAGE_SIZE_4K)
+    {
+        return ax_err!(InvalidInput, "test data out of the zero page");
+    }
+    // Compressed images are inflated first, the checks are on the result.
+    let inflated;
+    let image = if gzip::is_gzip(image) {
+        inflated = gzip::decompress(image)?;
+        &inflated[..]
+    } else {
+        image
+    };
+    let image = verify_image(image, check)?;
+    let Some(image) = image.get(image_offset..) else {
+        return ax_err!(InvalidData, "guest image shorter than its header");
+    };
+    if image.is_empty() {
+        return ax_err!(InvalidData, "empty guest image");
+    }
 
-    unsafe {
-        core::ptr::copy_nonoverlapping(
-            buf.as_ptr(),
-            phys_to_virt(paddr).as_mut_ptr(),
-            PAGE_SIZE_4K,
-        );
+    // The image is loaded into the RAM pages, see `map_guest_ram`.
+    if mem_size != 0 && !reuse {
+        map_guest_ram(entry, mem_size, uspace)?;
     }
 
+    // The format is told by the magic, anything which isn't ELF is raw.
+    let mut loaded = if is_elf(image) {
+        load_elf(image, entry, check, reuse, uspace)?
+    } else {
+        load_raw(image, entry, check, reuse, uspace)?;
+        LoadedImage {
+            format: ImageFormat::Raw,
+            entry,
+            size: image.len(),
+            initrd: None,
+        }
+    };
+    ax_println!("{:?} image: entry {:#x}, {} bytes", loaded.format, loaded.entry, loaded.size);
+
     // 映射地址 0，用于存放测试数据
-    // 客户机会从地址 0x40 读取数据
-    uspace.map_alloc(0.into(), PAGE_SIZE_4K, MappingFlags::READ|MappingFlags::WRITE|MappingFlags::USER, true).unwrap();
-    
-    let (paddr_zero, _, _) = uspace
-        .page_table()
-        .query(0.into())
-        .unwrap_or_else(|_| panic!("Mapping failed for address 0"));
-    
-    // 在地址 0x40 处写入测试数据 0x6688
-    unsafe {
-        let data_ptr = phys_to_virt(paddr_zero).as_mut_ptr() as *mut usize;
-        core::ptr::write(data_ptr.add(0x40 / 8), 0x6688);
-        core::ptr::write(data_ptr.add(0x48 / 8), 0x1234);
-    }
-    
-    ax_println!("Setup test data at address 0x40: 0x6688");
+    // 默认的客户机会从地址 0x40 读取数据（绝对地址，与入口无关）
+    // A reload reuses the page, `setup_guest_data` checks it is mapped.
+    if !reuse {
+        map_guest_pages(uspace, 0, PAGE_SIZE_4K, MappingFlags::READ|MappingFlags::WRITE|MappingFlags::USER)?;
+    }
+
+    // 写入测试数据
+    let values: Vec<_> = test_data
+        .iter()
+        .map(|&(_, value)| match endian {
+            Endian::Little => (value as u64).to_le_bytes(),
+            Endian::Big => (value as u64).to_be_bytes(),
+        })
+        .collect();
+    let regions: Vec<_> = test_data
+        .iter()
+        .zip(&values)
+        .map(|(&(offset, _), bytes)| (offset, &bytes[..]))
+        .collect();
+    setup_guest_data(&mut GuestMemory::new(uspace), &regions)?;
+    for &(offset, value) in test_data {
+        ax_println!("Setup test data at address {:#x}: {:#x}", offset, value);
+    }
+
+    if let Some((fname, gpa)) = config.initrd_image.as_ref().filter(|_| !reuse) {
+        loaded.initrd = Some(load_initrd(fname, *gpa, uspace)?);
+    }
+
+    Ok(loaded)
+}
+
+/// Writes each `(gpa, bytes)` blob of `regions` into guest memory.
+///
+/// All the regions are checked to be mapped before anything is written.
+pub fn setup_guest_data(mem: &mut GuestMemory, regions: &[(usize, &[u8])]) -> io::Result<()> {
+    if let Some(&(gpa, _)) = regions
+        .iter()
+        .find(|&&(gpa, data)| !mem.is_mapped(gpa, data.len()))
+    {
+        return Err(ax_err_type!(BadAddress, format_args!("guest data at {:#x} not mapped", gpa)));
+    }
+    for &(gpa, data) in regions {
+        mem.write_at(gpa, data)?;
+    }
+    Ok(())
+}
+
+/// A blob loaded into guest memory, e.g. an initrd.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestBlob {
+    /// The guest physical address it is loaded at.
+    pub base: usize,
+    /// The size in bytes.
+    pub size: usize,
+}
+
+/// Loads the initrd `fname` at the guest physical address `gpa`.
+///
+/// The returned base and size are meant to be handed to the guest at boot.
+pub fn load_initrd(fname: &str, gpa: usize, uspace: &mut AddrSpace) -> io::Result<GuestBlob> {
+    let initrd = load_file(fname)?;
+    load_blob("initrd", &initrd, gpa, uspace)
+}
+
+/// The firmware and kernel images loaded by [`load_firmware_and_kernel`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareBoot {
+    /// The firmware, the guest enters at its base.
+    pub firmware: GuestBlob,
+    /// The kernel, which the firmware jumps to.
+    pub kernel: GuestBlob,
+}
+
+/// Loads the raw firmware image `fw` at `fw_addr` and the raw kernel image
+/// `kernel` at `kernel_addr`, for guests booting through firmware such as
+/// OpenSBI.
+///
+/// The guest enters at the firmware, the kernel address is meant to be
+/// passed to it as a boot argument, see [`crate::config::GuestConfig`].
+pub fn load_firmware_and_kernel(
+    fw: &str,
+    fw_addr: usize,
+    kernel: &str,
+    kernel_addr: usize,
+    uspace: &mut AddrSpace,
+) -> io::Result<FirmwareBoot> {
+    if fw_addr % PAGE_SIZE_4K != 0 || kernel_addr % PAGE_SIZE_4K != 0 {
+        return ax_err!(InvalidInput, "firmware or kernel address not page aligned");
+    }
+    let fw_image = load_file(fw)?;
+    let kernel_image = load_file(kernel)?;
+    let fw_end = fw_addr + fw_image.len().div_ceil(PAGE_SIZE_4K) * PAGE_SIZE_4K;
+    let kernel_end = kernel_addr + kernel_image.len().div_ceil(PAGE_SIZE_4K) * PAGE_SIZE_4K;
+    if fw_addr < kernel_end && kernel_addr < fw_end {
+        return ax_err!(InvalidInput, "firmware and kernel overlap");
+    }
+
+    let check = ImageCheck::default();
+    load_raw(&fw_image, fw_addr, &check, false, uspace)?;
+    load_raw(&kernel_image, kernel_addr, &check, false, uspace)?;
+    ax_println!("firmware at {:#x}, kernel at {:#x}", fw_addr, kernel_addr);
+    Ok(FirmwareBoot {
+        firmware: GuestBlob {
+            base: fw_addr,
+            size: fw_image.len(),
+        },
+        kernel: GuestBlob {
+            base: kernel_addr,
+            size: kernel_image.len(),
+        },
+    })
+}
+
+/// The magic at the start of a flattened device tree, big endian.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// The size of the FDT header fields up to `totalsize`.
+const FDT_HEADER_MIN: usize = 8;
+
+/// Loads the device tree blob `fname` at the guest physical address `gpa`.
+///
+/// The blob must start with the FDT magic and be no shorter than the
+/// `totalsize` of its header. Its address is passed to the guest in `a1`.
+pub fn load_dtb(fname: &str, gpa: usize, uspace: &mut AddrSpace) -> io::Result<GuestBlob> {
+    let dtb = load_file(fname)?;
+    if dtb.len() < FDT_HEADER_MIN {
+        return ax_err!(InvalidData, "DTB too short");
+    }
+    let be32 = |off: usize| u32::from_be_bytes(dtb[off..off + 4].try_into().unwrap());
+    if be32(0) != FDT_MAGIC {
+        return ax_err!(InvalidData, "bad DTB magic");
+    }
+    let total = be32(4) as usize;
+    if total < FDT_HEADER_MIN || total > dtb.len() {
+        return ax_err!(InvalidData, "bad DTB size");
+    }
+    load_blob("dtb", &dtb[..total], gpa, uspace)
+}
+
+/// Copies `data` to the guest physical address `gpa`, mapping it if needed.
+fn load_blob(name: &str, data: &[u8], gpa: usize, uspace: &mut AddrSpace) -> io::Result<GuestBlob> {
+    if gpa % PAGE_SIZE_4K != 0 {
+        return ax_err!(InvalidInput, "blob address not page aligned");
+    }
+    let pages = data.len().div_ceil(PAGE_SIZE_4K).max(1);
+    map_guest_pages(uspace, gpa, pages * PAGE_SIZE_4K, MappingFlags::READ|MappingFlags::WRITE|MappingFlags::USER)?;
+    GuestMemory::new(uspace).write_at(gpa, data)?;
+
+    ax_println!("{}: {} bytes at {:#x}", name, data.len(), gpa);
+    Ok(GuestBlob {
+        base: gpa,
+        size: data.len(),
+    })
+}
+
+/// Verifies `image` against `check`, returns the payload to load.
+fn verify_image<'a>(image: &'a [u8], check: &ImageCheck) -> io::Result<&'a [u8]> {
+    let image = if check.crc32 {
+        let Some(split) = image.len().checked_sub(4) else {
+            return ax_err!(InvalidData, "guest image too short for CRC32");
+        };
+        let (payload, trailer) = image.split_at(split);
+        let expected = u32::from_le_bytes(trailer.try_into().unwrap());
+        if crc32(payload) != expected {
+            return ax_err!(InvalidData, "guest image CRC32 mismatch");
+        }
+        payload
+    } else {
+        image
+    };
+    if let Some(magic) = check.magic {
+        if !is_elf(image) && !image.starts_with(magic) {
+            return ax_err!(InvalidData, "bad guest image magic");
+        }
+    }
+    Ok(image)
+}
+
+/// CRC-32 (IEEE 802.3), computed bitwise as images are only checked once.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Copies a raw binary to `entry`.
+fn load_raw(
+    image: &[u8],
+    entry: usize,
+    check: &ImageCheck,
+    reuse: bool,
+    uspace: &mut AddrSpace,
+) -> io::Result<()> {
+    // 映射客户机代码页，按镜像大小向上取整到页
+    let pages = image.len().div_ceil(PAGE_SIZE_4K).max(1);
+    let size = pages * PAGE_SIZE_4K;
+    let flags = if check.w_xor_x {
+        MappingFlags::READ | MappingFlags::EXECUTE | MappingFlags::USER
+    } else {
+        MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE | MappingFlags::USER
+    };
+    prepare_guest_range(uspace, entry, size, flags, reuse, check.fill_pattern)?;
+    if check.verbose {
+        log_mapping(uspace, entry, size);
+    }
+
+    ax_println!("raw image: {} bytes, {} pages at {:#x}", image.len(), pages, entry);
+
+    // The copy goes through the host mapping of the frames, so it doesn't
+    // need the guest mapping to be writable.
+    let mem = GuestMemory::new(uspace);
+    write_image(&mem, entry, image, check)?;
+    // The pages may be reused from the guest RAM, clear the rest of the last
+    // one so that it doesn't keep stale data.
+    let tail = size - image.len();
+    mem.fill(entry + image.len(), check.fill_pattern.unwrap_or(0), tail)
+}
 
+/// Copies `data` to guest memory at `gpa`, reading it back if
+/// [`ImageCheck::verify_copy`] is set.
+fn write_image(mem: &GuestMemory, gpa: usize, data: &[u8], check: &ImageCheck) -> io::Result<()> {
+    mem.write_at(gpa, data)?;
+    if !check.verify_copy {
+        return Ok(());
+    }
+    let mut copied = vec![0; data.len()];
+    mem.read_at(gpa, &mut copied)?;
+    if let Some(pos) = copied.iter().zip(data).position(|(a, b)| a != b) {
+        return Err(ax_err_type!(
+            InvalidData,
+            format_args!("guest image mismatch at {:#x} after copy", gpa + pos)
+        ));
+    }
     Ok(())
 }
 
-fn load_file(fname: &str, buf: &mut [u8]) -> io::Result<usize> {
+/// Maps each `PT_LOAD` segment of an ELF image at its `p_vaddr`, the entry is
+/// `e_entry`.
+///
+/// Position-independent images are loaded with the bias `base`, and their
+/// `R_RISCV_RELATIVE` relocations are applied.
+fn load_elf(
+    image: &[u8],
+    base: usize,
+    check: &ImageCheck,
+    reuse: bool,
+    uspace: &mut AddrSpace,
+) -> io::Result<LoadedImage> {
+    let w_xor_x = check.w_xor_x;
+    let Some((file, segments)) = parse_elf(image) else {
+        return ax_err!(InvalidData, "bad ELF image");
+    };
+    let pie = file.ehdr.e_type == ET_DYN;
+    let bias = if pie { base } else { 0 };
+    let Some(entry) = (file.ehdr.e_entry as usize).checked_add(bias) else {
+        return ax_err!(InvalidData, "ELF entry out of the address space");
+    };
+
+    // The last page of the previous segment and its flags, a page shared by
+    // two segments gets the permissions of both.
+    let mut prev: Option<(usize, MappingFlags)> = None;
+    for ph in segments.iter().filter(|ph| ph.p_memsz != 0) {
+        let Some(vaddr) = (ph.p_vaddr as usize).checked_add(bias) else {
+            return ax_err!(InvalidData, "ELF segment out of the address space");
+        };
+        let Some(end) = vaddr
+            .checked_add(ph.p_memsz as usize)
+            .and_then(|end| end.checked_add(PAGE_SIZE_4K - 1))
+        else {
+            return ax_err!(InvalidData, "ELF segment out of the address space");
+        };
+        let start = vaddr & !(PAGE_SIZE_4K - 1);
+        let end = end & !(PAGE_SIZE_4K - 1);
+        let flags = segment_flags(ph.p_flags);
+        if w_xor_x && flags.contains(MappingFlags::WRITE | MappingFlags::EXECUTE) {
+            return ax_err!(InvalidData, "writable and executable ELF segment");
+        }
+        ax_println!("segment: [{:#x}, {:#x}) {:?}", start, end, flags);
+        // Reloaded images keep the permissions of the previous one.
+        let reused = prepare_guest_range(uspace, start, end - start, flags, reuse, check.fill_pattern)?;
+        if let Some((page, prev_flags)) = prev.filter(|&(page, _)| !reused && page == start) {
+            let shared = prev_flags | flags;
+            if w_xor_x && shared.contains(MappingFlags::WRITE | MappingFlags::EXECUTE) {
+                return ax_err!(InvalidData, "writable and executable ELF page");
+            }
+            uspace.protect(page.into(), PAGE_SIZE_4K, shared)?;
+        }
+        prev = Some((end - PAGE_SIZE_4K, flags));
+        if check.verbose {
+            log_mapping(uspace, start, end - start);
+        }
+
+        let mem = GuestMemory::new(uspace);
+        let data = &image[ph.p_offset as usize..(ph.p_offset + ph.p_filesz) as usize];
+        write_image(&mem, vaddr, data, check)?;
+        // The rest of the segment is the BSS. The pages may be reused, so it
+        // is cleared even when they were freshly allocated.
+        mem.fill(vaddr + data.len(), 0, ph.p_memsz as usize - data.len())?;
+    }
+    if pie {
+        relocate_elf(image, &file, &segments, bias, &GuestMemory::new(uspace))?;
+    }
+
+    Ok(LoadedImage {
+        format: ImageFormat::Elf,
+        entry,
+        size: segments.iter().map(|ph| ph.p_filesz as usize).sum(),
+        initrd: None,
+    })
+}
+
+/// Returns whether `image` starts with the ELF magic.
+fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(&ELFMAGIC)
+}
+
+/// Parses the headers of `image`, returns them with its `PT_LOAD` segments.
+///
+/// Returns `None` if `image` is not a little-endian ELF64 image for RISC-V,
+/// or if a segment is out of the bounds of the file.
+fn parse_elf(image: &[u8]) -> Option<(ElfBytes<'_, LittleEndian>, Vec<ProgramHeader>)> {
+    let file = ElfBytes::<LittleEndian>::minimal_parse(image).ok()?;
+    if file.ehdr.class != Class::ELF64 || file.ehdr.e_machine != EM_RISCV {
+        return None;
+    }
+    let segments: Vec<_> = file
+        .segments()
+        .map_or(Vec::new(), |phdrs| phdrs.iter().filter(|ph| ph.p_type == PT_LOAD).collect());
+    for ph in &segments {
+        if ph.p_filesz > ph.p_memsz || file.segment_data(ph).is_err() {
+            return None;
+        }
+    }
+    Some((file, segments))
+}
+
+/// Returns the `DT_RELA` relocations of the dynamic segment of `file`, an
+/// empty list if there is none.
+///
+/// Returns `None` if the dynamic segment or the relocation table is bad.
+fn elf_relocations(
+    image: &[u8],
+    file: &ElfBytes<'_, LittleEndian>,
+    segments: &[ProgramHeader],
+) -> Option<Vec<Rela>> {
+    let Some(dynamic) = file.dynamic().ok()? else {
+        return Some(Vec::new());
+    };
+    let (mut rela, mut relasz, mut relaent) = (None, 0, RELA_SIZE);
+    for entry in dynamic.iter() {
+        match entry.d_tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(entry.d_ptr() as usize),
+            DT_RELASZ => relasz = entry.d_val() as usize,
+            DT_RELAENT => relaent = entry.d_val() as usize,
+            _ => {}
+        }
+    }
+    let Some(rela) = rela else {
+        return Some(Vec::new());
+    };
+    if relaent != RELA_SIZE || relasz % RELA_SIZE != 0 {
+        return None;
+    }
+    // `DT_RELA` is an address, the table is in the file image of a loadable
+    // segment.
+    let off = segments.iter().find_map(|ph| {
+        let delta = rela.checked_sub(ph.p_vaddr as usize)?;
+        (delta < ph.p_filesz as usize).then_some(ph.p_offset as usize + delta)
+    })?;
+    let table = image.get(off..off.checked_add(relasz)?)?;
+    Some(RelaIterator::new(LittleEndian, Class::ELF64, table).collect())
+}
+
+/// The size of an ELF64 relocation with an addend.
+const RELA_SIZE: usize = 24;
+
+/// Applies the relocations of a position-independent ELF image loaded with
+/// the bias `bias`. Only `R_RISCV_RELATIVE` is supported, the guest is
+/// expected to be statically linked.
+fn relocate_elf(
+    image: &[u8],
+    file: &ElfBytes<'_, LittleEndian>,
+    segments: &[ProgramHeader],
+    bias: usize,
+    mem: &GuestMemory,
+) -> io::Result<()> {
+    let Some(relocs) = elf_relocations(image, file, segments) else {
+        return ax_err!(InvalidData, "bad ELF relocations");
+    };
+    for rela in &relocs {
+        if rela.r_type != R_RISCV_RELATIVE {
+            return Err(ax_err_type!(
+                Unsupported,
+                format_args!("unsupported ELF relocation type {}", rela.r_type)
+            ));
+        }
+        let addr = (rela.r_offset as usize).wrapping_add(bias);
+        let value = bias.wrapping_add(rela.r_addend as usize);
+        mem.write_u64(addr, value as u64)?;
+    }
+    if !relocs.is_empty() {
+        ax_println!("PIE image: {} relocations, bias {:#x}", relocs.len(), bias);
+    }
+    Ok(())
+}
+
+/// Logs the mapping of `[start, start + size)`, as the page table has it.
+///
+/// The host frames needn't be contiguous, the physical base is the one of
+/// the first page.
+fn log_mapping(uspace: &AddrSpace, start: usize, size: usize) {
+    match uspace.page_table().query(start.into()) {
+        Ok((paddr, flags, _)) => ax_println!(
+            "map: gpa [{:#x}, {:#x}) -> pa {:#x}, {:#x} bytes, {:?}",
+            start,
+            start + size,
+            paddr,
+            size,
+            flags
+        ),
+        Err(_) => ax_println!("map: gpa [{:#x}, {:#x}) not mapped", start, start + size),
+    }
+}
+
+/// Maps `size` bytes of guest RAM at the guest physical address `base`.
+///
+/// Images and data loaded afterwards into this range reuse its pages, so the
+/// guest gets a contiguous RAM region for its heap and stack.
+pub fn map_guest_ram(base: usize, size: usize, uspace: &mut AddrSpace) -> io::Result<()> {
+    if base % PAGE_SIZE_4K != 0 || size == 0 {
+        return ax_err!(InvalidInput, "bad guest RAM region");
+    }
+    let size = size
+        .checked_add(PAGE_SIZE_4K - 1)
+        .ok_or(io::Error::InvalidInput)?
+        & !(PAGE_SIZE_4K - 1);
+    if !uspace.contains_range(base.into(), size) {
+        return ax_err!(InvalidInput, "guest RAM out of the address space");
+    }
+    let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE | MappingFlags::USER;
+    uspace.map_alloc(base.into(), size, flags, true)?;
+    ax_println!("guest RAM: [{:#x}, {:#x}), {} pages", base, base + size, size / PAGE_SIZE_4K);
+    Ok(())
+}
+
+/// A guest stack set up by [`setup_guest_stack`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestStack {
+    /// The initial stack pointer, i.e. the top of the stack.
+    pub top: usize,
+    /// The lowest mapped address of the stack.
+    pub bottom: usize,
+    /// The guard page right below `bottom`.
+    pub guard: usize,
+}
+
+impl GuestStack {
+    /// Returns whether the guest physical address `gpa` is in the guard
+    /// page, i.e. an access to it overflowed the stack.
+    pub fn is_guard(&self, gpa: usize) -> bool {
+        (self.guard..self.bottom).contains(&gpa)
+    }
+}
+
+/// Maps a `pages`-page guest stack ending at `top`, with a guard page below.
+///
+/// The guard page is left unmapped, so that a guest running off its stack
+/// takes a guest page fault instead of writing to whatever lies below.
+pub fn setup_guest_stack(top: usize, pages: usize, uspace: &mut AddrSpace) -> io::Result<GuestStack> {
+    if top % PAGE_SIZE_4K != 0 || pages == 0 {
+        return ax_err!(InvalidInput, "bad guest stack");
+    }
+    let Some(guard) = pages
+        .checked_add(1)
+        .and_then(|n| n.checked_mul(PAGE_SIZE_4K))
+        .and_then(|size| top.checked_sub(size))
+    else {
+        return ax_err!(InvalidInput, "guest stack out of the address space");
+    };
+    let bottom = guard + PAGE_SIZE_4K;
+    if uspace.page_table().query(guard.into()).is_ok() {
+        return ax_err!(AlreadyExists, "guest stack guard page already mapped");
+    }
+    uspace.map_alloc(bottom.into(), top - bottom, MappingFlags::READ|MappingFlags::WRITE|MappingFlags::USER, true)?;
+
+    ax_println!("guest stack: [{:#x}, {:#x}), guard page at {:#x}", bottom, top, guard);
+    Ok(GuestStack { top, bottom, guard })
+}
+
+/// Maps `[start, start + size)` with `flags` for loading into it, returns
+/// whether the existing mappings are reused.
+///
+/// If `reuse` is set, the range is only checked to be mapped already.
+/// Otherwise the pages may be reused from the RWX guest RAM or from a
+/// previous segment, their permissions are set to `flags`. Newly mapped
+/// pages are filled with `fill`, if any.
+fn prepare_guest_range(
+    uspace: &mut AddrSpace,
+    start: usize,
+    size: usize,
+    flags: MappingFlags,
+    reuse: bool,
+    fill: Option<u8>,
+) -> io::Result<bool> {
+    if reuse {
+        if !GuestMemory::new(uspace).is_mapped(start, size) {
+            return Err(ax_err_type!(
+                BadAddress,
+                format_args!("guest range at {:#x} not mapped for reload", start)
+            ));
+        }
+        return Ok(true);
+    }
+    map_guest_pages_filled(uspace, start, size, flags, fill)?;
+    uspace.protect(start.into(), size, flags)?;
+    Ok(false)
+}
+
+/// Maps the pages of `[start, start + size)` which aren't mapped yet,
+/// e.g. because they are part of the guest RAM already.
+pub(crate) fn map_guest_pages(
+    uspace: &mut AddrSpace,
+    start: usize,
+    size: usize,
+    flags: MappingFlags,
+) -> io::Result<()> {
+    map_guest_pages_filled(uspace, start, size, flags, None)
+}
+
+/// Like [`map_guest_pages`], but fills the newly mapped pages with `fill`.
+fn map_guest_pages_filled(
+    uspace: &mut AddrSpace,
+    start: usize,
+    size: usize,
+    flags: MappingFlags,
+    fill: Option<u8>,
+) -> io::Result<()> {
+    let end = start + size;
+    let mut addr = start;
+    while addr < end {
+        if uspace.page_table().query(addr.into()).is_ok() {
+            addr += PAGE_SIZE_4K;
+            continue;
+        }
+        // Map the whole run of unmapped pages at once.
+        let run_start = addr;
+        while addr < end && uspace.page_table().query(addr.into()).is_err() {
+            addr += PAGE_SIZE_4K;
+        }
+        uspace.map_alloc(run_start.into(), addr - run_start, flags, true)?;
+        if let Some(byte) = fill {
+            GuestMemory::new(uspace).fill(run_start, byte, addr - run_start)?;
+        }
+    }
+    Ok(())
+}
+
+/// Converts ELF segment flags to mapping flags.
+fn segment_flags(p_flags: u32) -> MappingFlags {
+    let mut flags = MappingFlags::USER;
+    if p_flags & PF_R != 0 {
+        flags |= MappingFlags::READ;
+    }
+    if p_flags & PF_W != 0 {
+        flags |= MappingFlags::WRITE;
+    }
+    if p_flags & PF_X != 0 {
+        flags |= MappingFlags::EXECUTE;
+    }
+    flags
+}
+
+/// Reads the whole file `fname`, or the standard input if it is `-`.
+fn load_file(fname: &str) -> io::Result<Vec<u8>> {
     ax_println!("app: {}", fname);
-    let mut file = File::open(fname)?;
-    let n = file.read(buf)?;
-    Ok(n)
+    let buf = if fname == "-" {
+        read_stdin_image()?
+    } else {
+        let mut file = File::open(fname).map_err(|e| {
+            ax_println!("failed to open guest image '{}': {:?}", fname, e);
+            e
+        })?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).map_err(|e| {
+            ax_println!("failed to read guest image '{}': {:?}", fname, e);
+            e
+        })?;
+        buf
+    };
+    if buf.is_empty() {
+        return Err(ax_err_type!(InvalidData, format_args!("guest image '{}' is empty", fname)));
+    }
+    ax_println!("size: {} bytes", buf.len());
+    Ok(buf)
+}
+
+/// The largest image read from the standard input, the default RAM size of
+/// a QEMU `virt` machine.
+const MAX_STDIN_IMAGE: usize = 128 << 20;
+
+/// Reads an image streamed on the standard input.
+///
+/// The console never reports an end of file, so the image is preceded by a
+/// line with its size in decimal, at most [`MAX_STDIN_IMAGE`].
+fn read_stdin_image() -> io::Result<Vec<u8>> {
+    let mut stdin = std::io::stdin();
+    let mut line = String::new();
+    stdin.read_line(&mut line)?;
+    let Ok(size) = line.trim().parse::<usize>() else {
+        return ax_err!(InvalidData, "bad image size on stdin");
+    };
+    if size > MAX_STDIN_IMAGE {
+        return ax_err!(InvalidData, "image on stdin too large");
+    }
+    let mut buf = vec![0; size];
+    stdin.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use elf::abi::{EM_X86_64, ET_EXEC};
+
+    use super::*;
+
+    /// A loadable segment of [`elf_image`]: `(p_flags, p_offset, p_vaddr,
+    /// p_filesz, p_memsz)`.
+    type Segment = (u32, u64, u64, u64, u64);
+
+    /// Builds an ELF64 image for `machine` entering at `entry`, whose
+    /// program headers are followed by `data_size` bytes of data.
+    fn elf_image(machine: u16, entry: u64, segments: &[Segment], data_size: usize) -> Vec<u8> {
+        let mut image = Vec::from(ELFMAGIC);
+        image.extend([2, 1, 1]); // ELFCLASS64, little endian, EV_CURRENT
+        image.resize(16, 0);
+        image.extend(ET_EXEC.to_le_bytes());
+        image.extend(machine.to_le_bytes());
+        image.extend(1u32.to_le_bytes());
+        image.extend(entry.to_le_bytes());
+        image.extend(64u64.to_le_bytes()); // e_phoff
+        image.extend(0u64.to_le_bytes()); // e_shoff
+        image.extend(0u32.to_le_bytes()); // e_flags
+        for half in [64, 56, segments.len() as u16, 64, 0, 0] {
+            image.extend(u16::to_le_bytes(half));
+        }
+        for &(flags, offset, vaddr, filesz, memsz) in segments {
+            image.extend(PT_LOAD.to_le_bytes());
+            image.extend(flags.to_le_bytes());
+            for val in [offset, vaddr, vaddr, filesz, memsz, PAGE_SIZE_4K as u64] {
+                image.extend(val.to_le_bytes());
+            }
+        }
+        image.resize(image.len() + data_size, 0xaa);
+        image
+    }
+
+    #[test]
+    fn stack_guard() {
+        let stack = GuestStack { top: 0x8040_0000, bottom: 0x803f_c000, guard: 0x803f_b000 };
+        assert!(stack.is_guard(0x803f_bff8));
+        assert!(stack.is_guard(0x803f_b000));
+        assert!(!stack.is_guard(0x803f_c000));
+        assert!(!stack.is_guard(0x803f_aff8));
+    }
+
+    #[test]
+    fn parse_elf_headers() {
+        let segments = [
+            (PF_R | PF_X, 0, VM_ENTRY as u64, 0x100, 0x100),
+            (PF_R | PF_W, 0x100, 0x8030_0000, 0x20, 0x1000),
+        ];
+        let image = elf_image(EM_RISCV, VM_ENTRY as u64 + 0x78, &segments, 0x100);
+        assert!(is_elf(&image));
+        let (file, phdrs) = parse_elf(&image).unwrap();
+        assert_eq!(file.ehdr.e_entry, VM_ENTRY as u64 + 0x78);
+        assert_eq!(phdrs.len(), 2);
+        let data = (phdrs[1].p_vaddr, phdrs[1].p_filesz, phdrs[1].p_memsz);
+        assert_eq!(data, (0x8030_0000, 0x20, 0x1000));
+    }
+
+    #[test]
+    fn reject_bad_elf() {
+        let segment = |filesz, memsz| [(PF_R | PF_X, 0, VM_ENTRY as u64, filesz, memsz)];
+        let entry = VM_ENTRY as u64;
+        // Not for RISC-V.
+        assert!(parse_elf(&elf_image(EM_X86_64, entry, &segment(0x10, 0x10), 0x10)).is_none());
+        // A segment past the end of the file, or larger in the file than in
+        // memory.
+        assert!(parse_elf(&elf_image(EM_RISCV, entry, &segment(0x1000, 0x1000), 0x10)).is_none());
+        assert!(parse_elf(&elf_image(EM_RISCV, entry, &segment(0x20, 0x10), 0x100)).is_none());
+        // Truncated headers.
+        let image = elf_image(EM_RISCV, entry, &segment(0x10, 0x10), 0x10);
+        assert!(parse_elf(&image[..100]).is_none());
+        assert!(!is_elf(b"\x7fELG"));
+    }
+
+    #[test]
+    fn crc32_check() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        let mut image = Vec::from(&b"GUEST image"[..]);
+        image.extend(crc32(&image).to_le_bytes());
+        let check = ImageCheck { magic: Some(b"GUEST"), crc32: true, ..Default::default() };
+        assert_eq!(verify_image(&image, &check).unwrap(), b"GUEST image");
+        image[6] ^= 1;
+        assert!(verify_image(&image, &check).is_err());
+        assert!(verify_image(&image[..3], &check).is_err());
+    }
+
+    #[test]
+    fn magic_check() {
+        let check = ImageCheck { magic: Some(b"GUEST"), ..Default::default() };
+        assert!(verify_image(b"GUEST image", &check).is_ok());
+        assert!(verify_image(b"guest image", &check).is_err());
+        // ELF images needn't have the magic.
+        assert!(verify_image(&ELFMAGIC, &check).is_ok());
+        assert!(verify_image(b"guest image", &ImageCheck::default()).is_ok());
+    }
+
+    #[test]
+    fn elf_segment_flags() {
+        let (r, w, x) = (MappingFlags::READ, MappingFlags::WRITE, MappingFlags::EXECUTE);
+        let u = MappingFlags::USER;
+        assert_eq!(segment_flags(PF_R | PF_X), r | x | u);
+        assert_eq!(segment_flags(PF_R | PF_W), r | w | u);
+        assert_eq!(segment_flags(PF_R | PF_W | PF_X), r | w | x | u);
+        assert_eq!(segment_flags(0), u);
+    }
 }
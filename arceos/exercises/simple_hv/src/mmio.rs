@@ -0,0 +1,161 @@
+//! Emulation of guest MMIO accesses.
+//!
+//! Device ranges are left unmapped in the guest page table, so that an access
+//! traps as a guest page fault, which is forwarded to the device model
+//! registered for the faulting address.
+
+use core::ops::Range;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use axerrno::{ax_err, AxResult};
+
+use crate::csrs::{RiscvCsrTrait, CSR};
+use crate::gpt::{self, Access};
+use crate::guest_mem::GuestMemory;
+use crate::insn::{decode_load_store, decode_raw, MemAccess};
+use crate::vcpu::VmCpuRegisters;
+
+/// A device model behind an MMIO range.
+pub trait MmioDevice: Send {
+    /// Reads `width` bytes at `offset` from the start of the range.
+    fn read(&mut self, offset: usize, width: usize) -> u64;
+    /// Writes the low `width` bytes of `value` at `offset`.
+    fn write(&mut self, offset: usize, width: usize, value: u64);
+    /// Like [`MmioDevice::write`], for devices which access the guest memory
+    /// in response, e.g. to process a DMA request.
+    fn write_with_memory(&mut self, offset: usize, width: usize, value: u64, _mem: &GuestMemory) {
+        self.write(offset, width, value);
+    }
+}
+
+struct MmioRegion {
+    range: Range<usize>,
+    device: Box<dyn MmioDevice>,
+}
+
+/// The MMIO devices of a guest.
+#[derive(Default)]
+pub struct MmioBus {
+    regions: Vec<MmioRegion>,
+}
+
+impl MmioBus {
+    /// Registers `device` for the guest physical range `range`.
+    pub fn register_mmio_region(&mut self, range: Range<usize>, device: Box<dyn MmioDevice>) -> AxResult {
+        if range.is_empty() {
+            return ax_err!(InvalidInput, "empty MMIO range");
+        }
+        if self
+            .regions
+            .iter()
+            .any(|r| r.range.start < range.end && range.start < r.range.end)
+        {
+            return ax_err!(AlreadyExists, "overlapping MMIO range");
+        }
+        self.regions.push(MmioRegion { range, device });
+        Ok(())
+    }
+
+    fn find(&mut self, gpa: usize) -> Option<&mut MmioRegion> {
+        self.regions.iter_mut().find(|r| r.range.contains(&gpa))
+    }
+}
+
+/// Emulates the access at `gpa` which made the guest trap.
+///
+/// The access is decoded from `htinst`, which holds the trapping load or
+/// store in transformed form, or from the instruction at the guest PC if the
+/// hardware left `htinst` 0. Returns `false` if `gpa` isn't in a range of
+/// `bus` or the access can't be decoded, the guest PC is left unchanged then.
+pub fn handle_mmio(
+    bus: &mut MmioBus,
+    gpa: usize,
+    write: bool,
+    ctx: &mut VmCpuRegisters,
+    mem: &GuestMemory,
+) -> bool {
+    let Some(access) = decode_access(ctx, mem) else {
+        return false;
+    };
+    if access.write != write {
+        return false;
+    }
+
+    let Some(region) = bus.find(gpa) else {
+        return false;
+    };
+    let offset = gpa - region.range.start;
+
+    if write {
+        let value = ctx.guest_regs.gprs.reg(access.reg) as u64;
+        region.device.write_with_memory(offset, access.width, value, mem);
+    } else {
+        let value = region.device.read(offset, access.width);
+        ctx.guest_regs.gprs.set_reg(access.reg, access.extend(value) as usize);
+    }
+    ctx.guest_regs.sepc += access.len;
+    true
+}
+
+/// Decodes the load or store which trapped.
+fn decode_access(ctx: &VmCpuRegisters, mem: &GuestMemory) -> Option<MemAccess> {
+    let htinst = ctx.trap_csrs.htinst as u32;
+    if htinst != 0 {
+        return decode_load_store(htinst);
+    }
+    // Instructions are 2-byte aligned, a 4-byte one may cross a page.
+    let vsatp = CSR.vsatp.get_value();
+    let fetch = |gva: usize| {
+        let gpa = gpt::translate(mem, vsatp, gva, Access::Fetch)?;
+        mem.read_u16(gpa).ok()
+    };
+    let pc = ctx.guest_regs.sepc;
+    let low = fetch(pc)? as u32;
+    let inst = if low & 0b11 == 0b11 {
+        low | (fetch(pc + 2)? as u32) << 16
+    } else {
+        low
+    };
+    decode_raw(inst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device whose registers read back as their offset.
+    struct Echo;
+
+    impl MmioDevice for Echo {
+        fn read(&mut self, offset: usize, _width: usize) -> u64 {
+            offset as u64
+        }
+
+        fn write(&mut self, _offset: usize, _width: usize, _value: u64) {}
+    }
+
+    #[test]
+    fn register_regions() {
+        let mut bus = MmioBus::default();
+        bus.register_mmio_region(0x1000_0000..0x1000_0100, Box::new(Echo)).unwrap();
+        bus.register_mmio_region(0x1000_1000..0x1000_2000, Box::new(Echo)).unwrap();
+        // Adjacent ranges don't overlap.
+        bus.register_mmio_region(0x1000_0100..0x1000_0200, Box::new(Echo)).unwrap();
+        assert!(bus.register_mmio_region(0x1000_0080..0x1000_0180, Box::new(Echo)).is_err());
+        assert!(bus.register_mmio_region(0x1000_0000..0x1000_3000, Box::new(Echo)).is_err());
+        assert!(bus.register_mmio_region(0x2000_0000..0x2000_0000, Box::new(Echo)).is_err());
+    }
+
+    #[test]
+    fn find_region() {
+        let mut bus = MmioBus::default();
+        bus.register_mmio_region(0x1000_0000..0x1000_0100, Box::new(Echo)).unwrap();
+        bus.register_mmio_region(0x1000_1000..0x1000_2000, Box::new(Echo)).unwrap();
+        let region = bus.find(0x1000_1ff8).unwrap();
+        assert_eq!(region.range, 0x1000_1000..0x1000_2000);
+        assert_eq!(region.device.read(0x1000_1ff8 - region.range.start, 8), 0xff8);
+        assert!(bus.find(0x1000_0100).is_none());
+        assert!(bus.find(0x0fff_ffff).is_none());
+    }
+}
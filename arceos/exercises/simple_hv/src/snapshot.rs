@@ -0,0 +1,274 @@
+//! Snapshots of the guest memory, saved to host files.
+//!
+//! A snapshot is the magic, the number of regions, then for each mapped
+//! region its guest physical start, size and mapping flags followed by its
+//! contents, then the vCPU registers and the VS-level CSRs. All numbers are
+//! little-endian `u64`s.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use axerrno::ax_err;
+use axhal::mem::PAGE_SIZE_4K;
+use axhal::paging::MappingFlags;
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use crate::csrs::{RiscvCsrTrait, CSR};
+use crate::guest_mem::GuestMemory;
+use crate::loader::map_guest_pages;
+use crate::regs::GprIndex;
+use crate::vcpu::VmCpuRegisters;
+use crate::vcsr::CSR_MHARTID;
+use crate::vm::Vm;
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"HVSNAP\0\x01";
+
+/// The most guest memory a snapshot may hold, which bounds what is read
+/// from it.
+const MAX_GUEST_MEMORY: usize = 1 << 30;
+
+/// A mapped region of guest memory, as recorded in a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotRegion {
+    pub start: usize,
+    pub size: usize,
+    pub flags: MappingFlags,
+}
+
+/// Returns the mapped guest memory regions of `vm`.
+pub fn guest_regions(vm: &Vm) -> Vec<SnapshotRegion> {
+    vm.aspace
+        .mapped_areas()
+        .map(|(range, flags)| SnapshotRegion {
+            start: range.start.as_usize(),
+            size: range.size(),
+            flags,
+        })
+        .collect()
+}
+
+/// Writes the region `r` of a snapshot, followed by its contents `data`.
+fn write_region(out: &mut impl Write, r: &SnapshotRegion, data: &[u8]) -> io::Result<()> {
+    for val in [r.start, r.size, r.flags.bits()] {
+        out.write_all(&(val as u64).to_le_bytes())?;
+    }
+    out.write_all(data)
+}
+
+/// Dumps all the mapped guest memory of `vm` to the host file `out`.
+pub fn snapshot_guest_memory(vm: &Vm, out: &str) -> io::Result<()> {
+    let regions = guest_regions(vm);
+    let mem = GuestMemory::new(&vm.aspace);
+    let mut file = File::create(out)?;
+    file.write_all(SNAPSHOT_MAGIC)?;
+    file.write_all(&(regions.len() as u64).to_le_bytes())?;
+
+    let mut buf = Vec::new();
+    for r in &regions {
+        buf.resize(r.size, 0);
+        mem.read_at(r.start, &mut buf)?;
+        write_region(&mut file, r, &buf)?;
+    }
+    for val in save_regs(&vm.vcpu) {
+        file.write_all(&val.to_le_bytes())?;
+    }
+    ax_println!("snapshot: {} regions to {}", regions.len(), out);
+    Ok(())
+}
+
+/// Writes the guest memory saved by [`snapshot_guest_memory`] in the host
+/// file `path` back into `vm`, returns the restored regions.
+///
+/// Regions which aren't mapped in `vm` yet are mapped with their saved flags.
+#[allow(dead_code)] // `main` only resumes whole snapshots.
+pub fn restore_guest_memory(vm: &mut Vm, path: &str) -> io::Result<Vec<SnapshotRegion>> {
+    let mut file = open_snapshot(path)?;
+    let contents = read_regions(&mut file)?;
+    write_regions(vm, &contents)?;
+    ax_println!("restore: {} regions from {}", contents.len(), path);
+    Ok(contents.into_iter().map(|(r, _)| r).collect())
+}
+
+/// Resumes `vm` from the snapshot `snapshot` saved by
+/// [`snapshot_guest_memory`]: its guest memory, vCPU registers and VS-level
+/// CSRs are restored.
+///
+/// The regions which aren't mapped in `vm` are mapped with their saved
+/// flags, so a new VM can be resumed. Nothing is written if the snapshot
+/// can't be read.
+pub fn restore_vm_image(snapshot: &str, vm: &mut Vm) -> io::Result<()> {
+    let mut file = open_snapshot(snapshot)?;
+    let contents = read_regions(&mut file)?;
+    let mut regs = [0; SAVED_REGS];
+    for val in &mut regs {
+        *val = read_u64(&mut file)?;
+    }
+
+    write_regions(vm, &contents)?;
+    restore_regs(&mut vm.vcpu, &regs);
+    ax_println!("restore: {} regions and registers from {}", contents.len(), snapshot);
+    Ok(())
+}
+
+/// Reads the regions of a snapshot and their contents.
+fn read_regions(file: &mut impl Read) -> io::Result<Vec<(SnapshotRegion, Vec<u8>)>> {
+    let count = read_u64(file)? as usize;
+    let mut contents = Vec::new();
+    let mut total = 0;
+    for _ in 0..count {
+        let start = read_u64(file)? as usize;
+        let size = read_u64(file)? as usize;
+        let Some(flags) = MappingFlags::from_bits(read_u64(file)? as usize) else {
+            return ax_err!(InvalidData, "bad snapshot region flags");
+        };
+        total += size.min(MAX_GUEST_MEMORY + 1);
+        if total > MAX_GUEST_MEMORY
+            || (start | size) % PAGE_SIZE_4K != 0
+            || start.checked_add(size).is_none()
+        {
+            return ax_err!(InvalidData, "bad snapshot region");
+        }
+        let mut data = vec![0; size];
+        file.read_exact(&mut data)?;
+        contents.push((SnapshotRegion { start, size, flags }, data));
+    }
+    Ok(contents)
+}
+
+/// Writes the regions read by [`read_regions`] into `vm`, mapping them first
+/// if needed.
+fn write_regions(vm: &mut Vm, contents: &[(SnapshotRegion, Vec<u8>)]) -> io::Result<()> {
+    for (r, _) in contents {
+        map_guest_pages(&mut vm.aspace, r.start, r.size, r.flags)?;
+    }
+    let mem = GuestMemory::new(&vm.aspace);
+    for (r, data) in contents {
+        mem.write_at(r.start, data)?;
+    }
+    Ok(())
+}
+
+/// The number of registers in a snapshot: the GPRs, `sstatus`, `sepc`, the
+/// pending interrupts, the shadow `mhartid`, the guest timer deadline, then
+/// `hvip` and the VS-level CSRs in [`save_regs`] order.
+const SAVED_REGS: usize = 32 + 5 + 9;
+
+/// The guest timer deadline of a snapshot when it isn't armed.
+const TIMER_DISARMED: u64 = u64::MAX;
+
+fn save_regs(ctx: &VmCpuRegisters) -> [u64; SAVED_REGS] {
+    let mut regs = [0; SAVED_REGS];
+    for (i, val) in regs[..32].iter_mut().enumerate() {
+        *val = ctx.guest_regs.gprs.reg(GprIndex::from_raw(i as u32).unwrap()) as u64;
+    }
+    regs[32] = ctx.guest_regs.sstatus as u64;
+    regs[33] = ctx.guest_regs.sepc as u64;
+    regs[34] = ctx.pending_irqs as u64;
+    regs[35] = ctx.shadow_csrs.read(CSR_MHARTID).unwrap_or(0) as u64;
+    regs[36] = ctx.timer_deadline.unwrap_or(TIMER_DISARMED);
+    // The VS-level CSRs are not switched, they hold the state of the guest.
+    let csrs = [
+        CSR.hvip.get_value(),
+        CSR.vsatp.get_value(),
+        CSR.vstvec.get_value(),
+        CSR.vsstatus.get_value(),
+        CSR.vsepc.get_value(),
+        CSR.vscause.get_value(),
+        CSR.vstval.get_value(),
+        CSR.vsscratch.get_value(),
+        CSR.vsie.get_value(),
+    ];
+    for (val, csr) in regs[37..].iter_mut().zip(csrs) {
+        *val = csr as u64;
+    }
+    regs
+}
+
+fn restore_regs(ctx: &mut VmCpuRegisters, regs: &[u64; SAVED_REGS]) {
+    for (i, &val) in regs[..32].iter().enumerate() {
+        ctx.guest_regs.gprs.set_reg(GprIndex::from_raw(i as u32).unwrap(), val as usize);
+    }
+    ctx.guest_regs.sstatus = regs[32] as usize;
+    ctx.guest_regs.sepc = regs[33] as usize;
+    ctx.pending_irqs = regs[34] as usize;
+    ctx.shadow_csrs.write(CSR_MHARTID, regs[35] as usize);
+    ctx.timer_deadline = Some(regs[36]).filter(|&deadline| deadline != TIMER_DISARMED);
+    let csrs = &regs[37..];
+    CSR.hvip.write_value(csrs[0] as usize);
+    CSR.vsatp.write_value(csrs[1] as usize);
+    CSR.vstvec.write_value(csrs[2] as usize);
+    CSR.vsstatus.write_value(csrs[3] as usize);
+    CSR.vsepc.write_value(csrs[4] as usize);
+    CSR.vscause.write_value(csrs[5] as usize);
+    CSR.vstval.write_value(csrs[6] as usize);
+    CSR.vsscratch.write_value(csrs[7] as usize);
+    CSR.vsie.write_value(csrs[8] as usize);
+}
+
+/// Opens the snapshot `path` and checks its magic.
+fn open_snapshot(path: &str) -> io::Result<File> {
+    let mut file = File::open(path)?;
+    let mut magic = [0; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return ax_err!(InvalidData, "bad snapshot magic");
+    }
+    Ok(file)
+}
+
+fn read_u64(file: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    file.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RW: MappingFlags = MappingFlags::READ.union(MappingFlags::WRITE);
+
+    /// Encodes the region count and the regions of a snapshot.
+    fn encode(regions: &[(SnapshotRegion, Vec<u8>)]) -> Vec<u8> {
+        let mut out = (regions.len() as u64).to_le_bytes().to_vec();
+        for (r, data) in regions {
+            write_region(&mut out, r, data).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn regions_round_trip() {
+        let regions = vec![
+            (SnapshotRegion { start: 0, size: PAGE_SIZE_4K, flags: RW }, vec![0x5a; PAGE_SIZE_4K]),
+            (
+                SnapshotRegion {
+                    start: 0x8020_0000,
+                    size: 2 * PAGE_SIZE_4K,
+                    flags: MappingFlags::READ | MappingFlags::EXECUTE | MappingFlags::USER,
+                },
+                (0..2 * PAGE_SIZE_4K).map(|i| i as u8).collect(),
+            ),
+        ];
+        let bytes = encode(&regions);
+        assert_eq!(read_regions(&mut &bytes[..]).unwrap(), regions);
+        // Truncated contents.
+        assert!(read_regions(&mut &bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn bad_regions() {
+        let region = |start, size| (SnapshotRegion { start, size, flags: RW }, Vec::new());
+        // Unaligned, wrapping around and too large, none is read.
+        for r in [region(0x100, PAGE_SIZE_4K), region(usize::MAX & !0xfff, PAGE_SIZE_4K)] {
+            assert!(read_regions(&mut &encode(&[r])[..]).is_err());
+        }
+        let huge = region(0, MAX_GUEST_MEMORY + PAGE_SIZE_4K);
+        assert!(read_regions(&mut &encode(&[huge])[..]).is_err());
+        // Unknown flags.
+        let mut bytes = encode(&[region(0, 0)]);
+        bytes[24] = 0xff;
+        bytes[25] = 0xff;
+        assert!(read_regions(&mut &bytes[..]).is_err());
+    }
+}
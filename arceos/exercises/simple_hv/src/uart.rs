@@ -0,0 +1,103 @@
+//! A minimal 16550 UART model for the guest console.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use axsync::Mutex;
+
+use crate::mmio::MmioDevice;
+use crate::vm::Vm;
+
+/// The guest physical base of the UART on the QEMU `virt` machine.
+pub const UART_BASE: usize = 0x1000_0000;
+/// The size of the UART register range.
+pub const UART_SIZE: usize = 0x100;
+
+/// Transmit holding register (write) / receive buffer register (read).
+const REG_THR: usize = 0;
+/// Line status register.
+const REG_LSR: usize = 5;
+
+/// Data is ready in the receive buffer register.
+const LSR_DR: u64 = 1 << 0;
+/// The transmitter holding register is empty.
+const LSR_THRE: u64 = 1 << 5;
+/// The transmitter is idle.
+const LSR_TEMT: u64 = 1 << 6;
+
+/// The bytes waiting to be read by the guest from its console.
+pub type ConsoleInput = Arc<Mutex<VecDeque<u8>>>;
+
+/// A UART whose output goes to the host console.
+///
+/// Received bytes come from a host-side queue, see [`feed_guest_input`].
+/// Only the data and line status registers are modeled, writes to the other
+/// registers are ignored and they read as 0.
+pub struct Uart16550 {
+    rx: ConsoleInput,
+}
+
+impl Default for Uart16550 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Uart16550 {
+    /// Creates a UART with nothing to receive.
+    pub fn new() -> Self {
+        Self::with_input(ConsoleInput::default())
+    }
+
+    /// Creates a UART receiving the bytes queued in `rx`.
+    pub fn with_input(rx: ConsoleInput) -> Self {
+        Self { rx }
+    }
+}
+
+/// Queues `input` to be received by the guest on the console of `vm`.
+pub fn feed_guest_input(vm: &mut Vm, input: &[u8]) {
+    vm.console_input.lock().extend(input);
+}
+
+impl MmioDevice for Uart16550 {
+    fn read(&mut self, offset: usize, _width: usize) -> u64 {
+        match offset {
+            REG_THR => self.rx.lock().pop_front().map_or(0, u64::from),
+            // Always ready to transmit.
+            REG_LSR if self.rx.lock().is_empty() => LSR_THRE | LSR_TEMT,
+            REG_LSR => LSR_DR | LSR_THRE | LSR_TEMT,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: u64) {
+        if offset == REG_THR {
+            ax_print!("{}", value as u8 as char);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_status() {
+        let mut uart = Uart16550::new();
+        assert_eq!(uart.read(REG_LSR, 1), LSR_THRE | LSR_TEMT);
+        assert_eq!(uart.read(REG_THR, 1), 0);
+        // Other registers read as 0.
+        assert_eq!(uart.read(1, 1), 0);
+    }
+
+    #[test]
+    fn receive_input() {
+        let input = ConsoleInput::default();
+        let mut uart = Uart16550::with_input(input.clone());
+        input.lock().extend(b"ok");
+        assert_eq!(uart.read(REG_LSR, 1), LSR_DR | LSR_THRE | LSR_TEMT);
+        assert_eq!(uart.read(REG_THR, 1), b'o' as u64);
+        assert_eq!(uart.read(REG_THR, 1), b'k' as u64);
+        assert_eq!(uart.read(REG_LSR, 1) & LSR_DR, 0);
+    }
+}
@@ -3,6 +3,7 @@ use core::mem::size_of;
 
 use memoffset::offset_of;
 use super::regs::{GeneralPurposeRegisters, GprIndex};
+use super::vcsr::ShadowCsrs;
 
 /// Hypervisor GPR and CSR state which must be saved/restored when entering/exiting virtualization.
 #[derive(Default)]
@@ -83,6 +84,56 @@ pub struct VmCpuRegisters {
 
     // Read on VM exit.
     pub trap_csrs: VmCpuTrapState,
+
+    // Shadow values of the CSRs emulated by the hypervisor.
+    pub shadow_csrs: ShadowCsrs,
+
+    // Virtual interrupts pending for the guest, in the `hvip` layout.
+    pub pending_irqs: usize,
+
+    // The deadline of the guest timer in timer ticks, if armed.
+    pub timer_deadline: Option<u64>,
+}
+
+/// Boot arguments handed to the guest in registers.
+///
+/// It follows the RISC-V Linux boot protocol: `a0` holds the hart ID and `a1`
+/// the DTB address. The initrd base and size go to `a2` and `a3`, for guests
+/// which don't look for them in the DTB. Firmware gets the address of the
+/// kernel to jump to in `a4`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BootArgs {
+    /// The guest entry point.
+    pub entry: usize,
+    /// The ID of the hart the guest boots on.
+    pub hart_id: usize,
+    /// The guest physical address of the DTB, 0 if none.
+    pub dtb: usize,
+    /// The guest physical address of the initrd, 0 if none.
+    pub initrd_base: usize,
+    /// The size of the initrd.
+    pub initrd_size: usize,
+    /// The initial stack pointer, 0 to leave `sp` to the guest.
+    pub sp: usize,
+    /// The guest physical address of the kernel loaded after firmware, 0 if
+    /// none.
+    pub kernel: usize,
+}
+
+impl VmCpuRegisters {
+    /// Sets the guest PC and argument registers from `args`.
+    pub fn setup_boot_regs(&mut self, args: &BootArgs) {
+        let gprs = &mut self.guest_regs.gprs;
+        gprs.set_reg(GprIndex::A0, args.hart_id);
+        gprs.set_reg(GprIndex::A1, args.dtb);
+        gprs.set_reg(GprIndex::A2, args.initrd_base);
+        gprs.set_reg(GprIndex::A3, args.initrd_size);
+        gprs.set_reg(GprIndex::A4, args.kernel);
+        if args.sp != 0 {
+            gprs.set_reg(GprIndex::SP, args.sp);
+        }
+        self.guest_regs.sepc = args.entry;
+    }
 }
 
 #[allow(dead_code)]
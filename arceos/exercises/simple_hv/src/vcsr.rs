@@ -0,0 +1,100 @@
+//! Emulation of the CSR accesses which trap to the hypervisor.
+//!
+//! Each guest has a set of shadow CSRs that the trapping `csrr*`
+//! instructions read and write instead of the real ones.
+
+use crate::regs::GprIndex;
+use crate::vcpu::VmCpuRegisters;
+
+pub const CSR_TIME: u16 = 0xc01;
+pub const CSR_MHARTID: u16 = 0xf14;
+
+/// The emulated CSRs, indexes into [`ShadowCsrs`].
+const EMULATED_CSRS: [u16; 2] = [CSR_TIME, CSR_MHARTID];
+
+/// Shadow values of the emulated CSRs of a guest.
+#[derive(Debug, Default, Clone)]
+#[repr(C)]
+pub struct ShadowCsrs {
+    vals: [usize; EMULATED_CSRS.len()],
+}
+
+impl ShadowCsrs {
+    fn index(csr: u16) -> Option<usize> {
+        EMULATED_CSRS.iter().position(|&c| c == csr)
+    }
+
+    /// Reads the shadow value of `csr`, `None` if it isn't emulated.
+    ///
+    /// `time` is modeled as the host time plus the shadow value, so that it
+    /// keeps increasing.
+    pub fn read(&self, csr: u16) -> Option<usize> {
+        let val = self.vals[Self::index(csr)?];
+        Some(match csr {
+            CSR_TIME => (axhal::time::current_ticks() as usize).wrapping_add(val),
+            _ => val,
+        })
+    }
+
+    /// Sets the shadow value of `csr`, returns `false` if it isn't emulated.
+    pub fn write(&mut self, csr: u16, val: usize) -> bool {
+        let Some(idx) = Self::index(csr) else {
+            return false;
+        };
+        self.vals[idx] = val;
+        true
+    }
+}
+
+const OPCODE_SYSTEM: u32 = 0x73;
+
+const CSRRW: u32 = 1;
+const CSRRS: u32 = 2;
+const CSRRC: u32 = 3;
+
+/// Emulates `inst` if it is a CSR access to an emulated CSR.
+///
+/// Returns `false` if it isn't, the guest state is left unchanged then.
+pub fn handle_csr_access(inst: usize, ctx: &mut VmCpuRegisters) -> bool {
+    let inst = inst as u32;
+    if inst & 0x7f != OPCODE_SYSTEM {
+        return false;
+    }
+    let funct3 = (inst >> 12) & 0b111;
+    let op = funct3 & 0b11;
+    if op == 0 {
+        return false; // Not a CSR instruction.
+    }
+    let csr = (inst >> 20) as u16;
+    let rd = GprIndex::from_raw((inst >> 7) & 0x1f).unwrap();
+    let rs1 = (inst >> 15) & 0x1f;
+    // `csrr*i` take the 5-bit immediate in place of `rs1`.
+    let src = if funct3 & 0b100 != 0 {
+        rs1 as usize
+    } else {
+        ctx.guest_regs.gprs.reg(GprIndex::from_raw(rs1).unwrap())
+    };
+
+    let Some(old) = ctx.shadow_csrs.read(csr) else {
+        return false;
+    };
+    // `csrrs` and `csrrc` with `x0` don't write.
+    let new = match op {
+        CSRRW => Some(src),
+        CSRRS => (rs1 != 0).then_some(old | src),
+        CSRRC => (rs1 != 0).then_some(old & !src),
+        _ => unreachable!(),
+    };
+    if let Some(new) = new {
+        // CSRs with the top two bits set are read-only.
+        if csr >> 10 == 0b11 {
+            return false;
+        }
+        ctx.shadow_csrs.write(csr, new);
+    }
+
+    ctx.guest_regs.gprs.set_reg(rd, old);
+    ctx.guest_regs.sepc += 4;
+    ax_println!("Emulated: csr {:#x} -> {:#x}", csr, old);
+    true
+}
@@ -0,0 +1,423 @@
+//! A virtio-mmio block device backed by a host file.
+//!
+//! It follows the virtio 1.x MMIO transport (version 2) with a single
+//! request queue. Only `VIRTIO_F_VERSION_1` is offered, requests are
+//! processed synchronously when the guest notifies the queue, and their
+//! completion is reported in `InterruptStatus`, there is no interrupt line.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use alloc::vec;
+use alloc::vec::Vec;
+use axerrno::{ax_err, ax_err_type};
+
+use crate::guest_mem::GuestMemory;
+use crate::mmio::MmioDevice;
+
+/// The guest physical base of the first virtio-mmio slot on QEMU `virt`.
+pub const VIRTIO_MMIO_BASE: usize = 0x1000_1000;
+/// The size of the virtio-mmio register range.
+pub const VIRTIO_MMIO_SIZE: usize = 0x200;
+
+const SECTOR_SIZE: usize = 512;
+const QUEUE_SIZE: u16 = 16;
+
+const MAGIC: u64 = 0x7472_6976; // "virt"
+const VERSION: u64 = 2;
+const DEVICE_ID_BLOCK: u64 = 2;
+const VENDOR_ID: u64 = 0x554d_4551; // "QEMU"
+
+/// `VIRTIO_F_VERSION_1`, bit 0 of the second feature word.
+const FEATURES_HI: u32 = 1;
+
+const REG_MAGIC: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_VENDOR_ID: usize = 0x00c;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC_LOW: usize = 0x080;
+const REG_QUEUE_DESC_HIGH: usize = 0x084;
+const REG_QUEUE_DRIVER_LOW: usize = 0x090;
+const REG_QUEUE_DRIVER_HIGH: usize = 0x094;
+const REG_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const REG_CONFIG_GENERATION: usize = 0x0fc;
+/// The device config space, starting with the capacity in sectors.
+const REG_CONFIG: usize = 0x100;
+
+const STATUS_FEATURES_OK: u32 = 8;
+
+const INTERRUPT_USED_RING: u32 = 1;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// A guest descriptor, see `struct virtq_desc`.
+struct Desc {
+    addr: usize,
+    len: usize,
+    flags: u16,
+    next: u16,
+}
+
+/// A block request read from the queue.
+struct Request {
+    req_type: u32,
+    sector: u64,
+    /// The `(gpa, len, device writes)` data buffers.
+    data: Vec<(usize, usize, bool)>,
+    status_addr: usize,
+}
+
+/// The state of the request queue.
+#[derive(Default)]
+struct Queue {
+    num: u16,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    last_avail: u16,
+}
+
+/// A virtio block device whose disk is a host file.
+pub struct VirtioBlk {
+    disk: File,
+    capacity: u64,
+    status: u32,
+    device_features_sel: u32,
+    driver_features: [u32; 2],
+    driver_features_sel: u32,
+    interrupt_status: u32,
+    queue: Queue,
+}
+
+impl VirtioBlk {
+    /// Creates a device with the disk image `path`.
+    ///
+    /// The disk size is rounded down to whole sectors. The image is opened
+    /// for writing too, for the guest's `VIRTIO_BLK_T_OUT` requests.
+    pub fn new(path: &str) -> io::Result<Self> {
+        let mut disk = OpenOptions::new().read(true).write(true).open(path)?;
+        let size = disk.seek(SeekFrom::End(0))?;
+        if size < SECTOR_SIZE as u64 {
+            return ax_err!(InvalidData, "disk image smaller than a sector");
+        }
+        ax_println!("virtio-blk: {}, {} bytes", path, size);
+        Ok(Self {
+            disk,
+            capacity: size / SECTOR_SIZE as u64,
+            status: 0,
+            device_features_sel: 0,
+            driver_features: [0; 2],
+            driver_features_sel: 0,
+            interrupt_status: 0,
+            queue: Queue::default(),
+        })
+    }
+
+    fn reset(&mut self) {
+        self.status = 0;
+        self.driver_features = [0; 2];
+        self.interrupt_status = 0;
+        self.queue = Queue::default();
+    }
+
+    fn read_desc(&self, mem: &GuestMemory, idx: u16) -> io::Result<Desc> {
+        if idx >= self.queue.num {
+            return ax_err!(InvalidData, "virtio descriptor index out of the queue");
+        }
+        let base = self.queue.desc as usize + idx as usize * 16;
+        Ok(Desc {
+            addr: mem.read_u64(base)? as usize,
+            len: mem.read_u32(base + 8)? as usize,
+            flags: mem.read_u16(base + 12)?,
+            next: mem.read_u16(base + 14)?,
+        })
+    }
+
+    /// Processes all the requests made available by the driver.
+    ///
+    /// Every request is completed, a failed one with `VIRTIO_BLK_S_IOERR`,
+    /// so that the driver never waits for it. Only errors on the rings
+    /// themselves are returned.
+    fn process_queue(&mut self, mem: &GuestMemory) -> io::Result<()> {
+        if !self.queue.ready || self.queue.num == 0 {
+            return ax_err!(BadState, "virtio queue not ready");
+        }
+        let avail = self.queue.driver as usize;
+        let used = self.queue.device as usize;
+        let avail_idx = mem.read_u16(avail + 2)?;
+        while self.queue.last_avail != avail_idx {
+            let slot = (self.queue.last_avail % self.queue.num) as usize;
+            let head = mem.read_u16(avail + 4 + slot * 2)?;
+            let written = self.handle_request(mem, head);
+
+            let used_idx = mem.read_u16(used + 2)?;
+            let elem = used + 4 + (used_idx % self.queue.num) as usize * 8;
+            mem.write_u32(elem, head as u32)?;
+            mem.write_u32(elem + 4, written as u32)?;
+            mem.write_u16(used + 2, used_idx.wrapping_add(1))?;
+
+            self.queue.last_avail = self.queue.last_avail.wrapping_add(1);
+            self.interrupt_status |= INTERRUPT_USED_RING;
+        }
+        Ok(())
+    }
+
+    /// Handles the request whose chain starts at `head`, returns the number
+    /// of bytes written to guest memory.
+    ///
+    /// A request is a header descriptor, data descriptors and a one-byte
+    /// status descriptor. Without a well-formed chain there is no status to
+    /// report the failure in, the request is only completed.
+    fn handle_request(&mut self, mem: &GuestMemory, head: u16) -> usize {
+        let req = match self.read_request(mem, head) {
+            Ok(req) => req,
+            Err(e) => {
+                warn!("virtio-blk: bad request: {:?}", e);
+                return 0;
+            }
+        };
+        let (status, written) = match req.req_type {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => match self.transfer(mem, &req) {
+                Ok(written) => (VIRTIO_BLK_S_OK, written),
+                Err(e) => {
+                    warn!("virtio-blk: I/O error at sector {}: {:?}", req.sector, e);
+                    (VIRTIO_BLK_S_IOERR, 0)
+                }
+            },
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        };
+        if let Err(e) = mem.write_at(req.status_addr, &[status]) {
+            warn!("virtio-blk: cannot write the request status: {:?}", e);
+            return written;
+        }
+        written + 1
+    }
+
+    /// Reads the header and walks the descriptor chain of a request.
+    fn read_request(&self, mem: &GuestMemory, head: u16) -> io::Result<Request> {
+        let hdr = self.read_desc(mem, head)?;
+        if hdr.len < 16 || hdr.flags & VIRTQ_DESC_F_NEXT == 0 {
+            return ax_err!(InvalidData, "bad virtio-blk request header");
+        }
+        let req_type = mem.read_u32(hdr.addr)?;
+        let sector = mem.read_u64(hdr.addr + 8)?;
+
+        let mut data = Vec::new();
+        let mut desc = self.read_desc(mem, hdr.next)?;
+        // Bound the walk, a looping chain would otherwise never end.
+        for _ in 0..self.queue.num {
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            data.push((desc.addr, desc.len, desc.flags & VIRTQ_DESC_F_WRITE != 0));
+            desc = self.read_desc(mem, desc.next)?;
+        }
+        if desc.flags & VIRTQ_DESC_F_NEXT != 0 || desc.len < 1 {
+            return ax_err!(InvalidData, "bad virtio-blk descriptor chain");
+        }
+        Ok(Request {
+            req_type,
+            sector,
+            data,
+            status_addr: desc.addr,
+        })
+    }
+
+    /// Copies the data of a read or write request, returns the number of
+    /// bytes written to guest memory.
+    ///
+    /// The sector comes from the guest, the disk offsets are checked for
+    /// overflow before they are compared with the capacity.
+    fn transfer(&mut self, mem: &GuestMemory, req: &Request) -> io::Result<usize> {
+        let write = req.req_type == VIRTIO_BLK_T_OUT;
+        let disk_size = self.capacity * SECTOR_SIZE as u64;
+        let mut offset = req
+            .sector
+            .checked_mul(SECTOR_SIZE as u64)
+            .ok_or(ax_err_type!(InvalidInput, "virtio-blk sector out of range"))?;
+        let mut written = 0;
+        for &(addr, len, device_writes) in &req.data {
+            if device_writes == write {
+                return ax_err!(InvalidInput, "virtio-blk data buffer of the wrong direction");
+            }
+            let end = offset
+                .checked_add(len as u64)
+                .filter(|&end| end <= disk_size)
+                .ok_or(ax_err_type!(InvalidInput, "virtio-blk request past the disk end"))?;
+            let mut buf = vec![0; len];
+            self.disk.seek(SeekFrom::Start(offset))?;
+            if write {
+                mem.read_at(addr, &mut buf)?;
+                self.disk.write_all(&buf)?;
+            } else {
+                self.disk.read_exact(&mut buf)?;
+                mem.write_at(addr, &buf)?;
+                written += len;
+            }
+            offset = end;
+        }
+        Ok(written)
+    }
+}
+
+impl MmioDevice for VirtioBlk {
+    fn read(&mut self, offset: usize, _width: usize) -> u64 {
+        match offset {
+            REG_MAGIC => MAGIC,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => DEVICE_ID_BLOCK,
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match self.device_features_sel {
+                1 => FEATURES_HI as u64,
+                _ => 0,
+            },
+            REG_QUEUE_NUM_MAX => QUEUE_SIZE as u64,
+            REG_QUEUE_READY => self.queue.ready as u64,
+            REG_INTERRUPT_STATUS => self.interrupt_status as u64,
+            REG_STATUS => self.status as u64,
+            REG_CONFIG_GENERATION => 0,
+            REG_CONFIG => self.capacity & 0xffff_ffff,
+            o if o == REG_CONFIG + 4 => self.capacity >> 32,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: u64) {
+        let value = value as u32;
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            REG_DRIVER_FEATURES => {
+                if let Some(word) = self.driver_features.get_mut(self.driver_features_sel as usize) {
+                    *word = value;
+                }
+            }
+            REG_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            // There is a single queue, writes for others are ignored.
+            REG_QUEUE_SEL => {}
+            REG_QUEUE_NUM => self.queue.num = (value as u16).min(QUEUE_SIZE),
+            REG_QUEUE_READY => self.queue.ready = value & 1 != 0,
+            REG_INTERRUPT_ACK => self.interrupt_status &= !value,
+            REG_STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else if value & STATUS_FEATURES_OK != 0
+                    && self.driver_features[1] & !FEATURES_HI != 0
+                {
+                    // The driver accepted features which weren't offered.
+                    self.status = value & !STATUS_FEATURES_OK;
+                } else {
+                    self.status = value;
+                }
+            }
+            REG_QUEUE_DESC_LOW => set_low(&mut self.queue.desc, value),
+            REG_QUEUE_DESC_HIGH => set_high(&mut self.queue.desc, value),
+            REG_QUEUE_DRIVER_LOW => set_low(&mut self.queue.driver, value),
+            REG_QUEUE_DRIVER_HIGH => set_high(&mut self.queue.driver, value),
+            REG_QUEUE_DEVICE_LOW => set_low(&mut self.queue.device, value),
+            REG_QUEUE_DEVICE_HIGH => set_high(&mut self.queue.device, value),
+            _ => {}
+        }
+    }
+
+    fn write_with_memory(&mut self, offset: usize, width: usize, value: u64, mem: &GuestMemory) {
+        if offset != REG_QUEUE_NOTIFY {
+            self.write(offset, width, value);
+        } else if let Err(e) = self.process_queue(mem) {
+            warn!("virtio-blk: request failed: {:?}", e);
+        }
+    }
+}
+
+fn set_low(reg: &mut u64, value: u32) {
+    *reg = (*reg & !0xffff_ffff) | value as u64;
+}
+
+fn set_high(reg: &mut u64, value: u32) {
+    *reg = (*reg & 0xffff_ffff) | (value as u64) << 32;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a device over a disk of `sectors` sectors in a host
+    /// temporary file named `name`.
+    fn device(name: &str, sectors: usize) -> VirtioBlk {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, vec![0; sectors * SECTOR_SIZE]).unwrap();
+        VirtioBlk::new(path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn identification() {
+        let mut dev = device("simple_hv_virtio_id.img", 3);
+        assert_eq!(dev.read(REG_MAGIC, 4), MAGIC);
+        assert_eq!(dev.read(REG_VERSION, 4), VERSION);
+        assert_eq!(dev.read(REG_DEVICE_ID, 4), DEVICE_ID_BLOCK);
+        assert_eq!((dev.read(REG_CONFIG, 4), dev.read(REG_CONFIG + 4, 4)), (3, 0));
+        assert_eq!(dev.read(REG_QUEUE_NUM_MAX, 4), QUEUE_SIZE as u64);
+    }
+
+    #[test]
+    fn feature_negotiation() {
+        let mut dev = device("simple_hv_virtio_features.img", 1);
+        assert_eq!(dev.read(REG_DEVICE_FEATURES, 4), 0);
+        dev.write(REG_DEVICE_FEATURES_SEL, 4, 1);
+        assert_eq!(dev.read(REG_DEVICE_FEATURES, 4), FEATURES_HI as u64);
+        // Accepting a feature which isn't offered fails FEATURES_OK.
+        dev.write(REG_DRIVER_FEATURES_SEL, 4, 1);
+        dev.write(REG_DRIVER_FEATURES, 4, (FEATURES_HI | 2) as u64);
+        dev.write(REG_STATUS, 4, (STATUS_FEATURES_OK | 3) as u64);
+        assert_eq!(dev.read(REG_STATUS, 4), 3);
+        dev.write(REG_DRIVER_FEATURES, 4, FEATURES_HI as u64);
+        dev.write(REG_STATUS, 4, (STATUS_FEATURES_OK | 3) as u64);
+        assert_eq!(dev.read(REG_STATUS, 4), (STATUS_FEATURES_OK | 3) as u64);
+    }
+
+    #[test]
+    fn queue_setup_and_reset() {
+        let mut dev = device("simple_hv_virtio_queue.img", 1);
+        dev.write(REG_QUEUE_NUM, 4, 1024);
+        assert_eq!(dev.queue.num, QUEUE_SIZE);
+        dev.write(REG_QUEUE_DESC_LOW, 4, 0x8100_0000);
+        dev.write(REG_QUEUE_DESC_HIGH, 4, 1);
+        assert_eq!(dev.queue.desc, 0x1_8100_0000);
+        dev.write(REG_QUEUE_READY, 4, 1);
+        assert_eq!(dev.read(REG_QUEUE_READY, 4), 1);
+        dev.interrupt_status = INTERRUPT_USED_RING;
+        dev.write(REG_INTERRUPT_ACK, 4, INTERRUPT_USED_RING as u64);
+        assert_eq!(dev.read(REG_INTERRUPT_STATUS, 4), 0);
+        // Writing 0 to the status resets the device.
+        dev.write(REG_STATUS, 4, 0);
+        assert!(!dev.queue.ready && dev.queue.desc == 0 && dev.queue.num == 0);
+    }
+
+    #[test]
+    fn small_disk() {
+        let path = std::env::temp_dir().join("simple_hv_virtio_small.img");
+        std::fs::write(&path, [0; SECTOR_SIZE - 1]).unwrap();
+        assert!(VirtioBlk::new(path.to_str().unwrap()).is_err());
+    }
+}
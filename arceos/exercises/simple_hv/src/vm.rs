@@ -0,0 +1,209 @@
+//! A guest VM: its memory, vCPU state and devices.
+
+use core::ops::Range;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use axerrno::AxResult;
+use axhal::mem::PhysAddr;
+use axmm::AddrSpace;
+use riscv::register::sstatus;
+use std::io;
+use tock_registers::LocalRegisterCopy;
+
+use crate::config::GuestConfig;
+use crate::csrs::defs::hstatus;
+use crate::csrs::{RiscvCsrTrait, CSR};
+use crate::guest_mem::GuestMemory;
+use crate::irq;
+use crate::loader::{self, GuestBlob, GuestStack, LoadedImage};
+use crate::mmio::{MmioBus, MmioDevice};
+use crate::uart::ConsoleInput;
+use crate::virtio_blk::{VirtioBlk, VIRTIO_MMIO_SIZE};
+use crate::vcpu::{VmCpuRegisters, _run_guest};
+use crate::vmexit::{handle_vmexit, save_trap_csrs, ExitStats, VmExitAction, VmExitReason};
+
+/// A guest VM.
+///
+/// Each VM has its own guest physical address space, so several of them can
+/// be loaded and run independently. They run one at a time on the current
+/// hart, the VS-level CSRs are not switched between them.
+pub struct Vm {
+    /// The guest physical address space, used as the G-stage page table.
+    pub aspace: AddrSpace,
+    /// The vCPU state.
+    pub vcpu: VmCpuRegisters,
+    /// The emulated MMIO devices.
+    pub mmio: MmioBus,
+    /// The device tree blob loaded by [`Vm::load_dtb`].
+    pub dtb: Option<GuestBlob>,
+    /// The stack set up by [`Vm::setup_stack`], its guard page is watched
+    /// for overflows.
+    pub stack: Option<GuestStack>,
+    /// The input of the guest console, see [`crate::uart::feed_guest_input`].
+    pub console_input: ConsoleInput,
+    /// The initrd loaded by [`Vm::load_image`].
+    pub initrd: Option<GuestBlob>,
+    /// The config of the last [`Vm::load_image`], for reloading.
+    pub(crate) load_config: Option<GuestConfig>,
+    /// The VM exits so far, see [`crate::vmexit::exit_stats`].
+    pub(crate) exit_stats: ExitStats,
+}
+
+impl Vm {
+    /// Creates a VM with an empty address space.
+    pub fn new() -> AxResult<Self> {
+        let mut vcpu = VmCpuRegisters::default();
+        prepare_guest_context(&mut vcpu);
+        Ok(Self {
+            aspace: axmm::new_user_aspace()?,
+            vcpu,
+            mmio: MmioBus::default(),
+            dtb: None,
+            stack: None,
+            console_input: ConsoleInput::default(),
+            initrd: None,
+            load_config: None,
+            exit_stats: ExitStats::default(),
+        })
+    }
+
+    /// Loads a guest image into this VM, see [`loader::load_vm_image`].
+    pub fn load_image(&mut self, config: &GuestConfig) -> io::Result<LoadedImage> {
+        let image = loader::load_vm_image(config, &mut self.aspace)?;
+        if image.initrd.is_some() {
+            self.initrd = image.initrd;
+        }
+        self.load_config = Some(config.clone());
+        Ok(image)
+    }
+
+    /// Loads a device tree blob at `gpa`, see [`loader::load_dtb`].
+    ///
+    /// Its address is passed to the guest by [`Vm::setup_boot_regs`].
+    pub fn load_dtb(&mut self, fname: &str, gpa: usize) -> io::Result<GuestBlob> {
+        let dtb = loader::load_dtb(fname, gpa, &mut self.aspace)?;
+        self.dtb = Some(dtb);
+        Ok(dtb)
+    }
+
+    /// Sets up a guest stack, see [`loader::setup_guest_stack`].
+    ///
+    /// A guest access to its guard page stops the VM with
+    /// [`VmExitAction::StackOverflow`].
+    pub fn setup_stack(&mut self, top: usize, pages: usize) -> io::Result<GuestStack> {
+        let stack = loader::setup_guest_stack(top, pages, &mut self.aspace)?;
+        self.stack = Some(stack);
+        Ok(stack)
+    }
+
+    /// Sets up the vCPU boot registers of a guest entering at `entry` from
+    /// `config`, see [`VmCpuRegisters::setup_boot_regs`].
+    ///
+    /// If `config` has no DTB or initrd, the ones loaded into this VM are
+    /// used.
+    pub fn setup_boot_regs(&mut self, entry: usize, config: &GuestConfig) {
+        let mut args = config.boot_args(entry);
+        if args.dtb == 0 {
+            args.dtb = self.dtb.map_or(0, |dtb| dtb.base);
+        }
+        if let (None, Some(initrd)) = (config.initrd, self.initrd) {
+            args.initrd_base = initrd.base;
+            args.initrd_size = initrd.size;
+        }
+        self.vcpu.setup_boot_regs(&args);
+    }
+
+    /// Registers an MMIO device of this VM.
+    pub fn register_mmio_region(&mut self, range: Range<usize>, device: Box<dyn MmioDevice>) -> AxResult {
+        self.mmio.register_mmio_region(range, device)
+    }
+
+    /// Adds a virtio block device at `base` whose disk is the host file
+    /// `disk`.
+    pub fn add_virtio_blk(&mut self, base: usize, disk: &str) -> io::Result<()> {
+        let dev = VirtioBlk::new(disk)?;
+        self.register_mmio_region(base..base + VIRTIO_MMIO_SIZE, Box::new(dev))
+    }
+
+    /// Enters the guest once and handles the VM exit.
+    pub fn run_once(&mut self) -> VmExitAction {
+        // Setup pagetable for 2nd address mapping.
+        prepare_vm_pgtable(self.aspace.page_table_root());
+        // A host tick taken from here on would set the host timer for ArceOS
+        // only, host interrupts wait until the exit is handled.
+        let irqs_enabled = axhal::arch::irqs_enabled();
+        axhal::arch::disable_irqs();
+        irq::arm_host_timer(&self.vcpu);
+        irq::sync_pending_irqs(&self.vcpu);
+        unsafe {
+            _run_guest(&mut self.vcpu);
+        }
+
+        save_trap_csrs(&mut self.vcpu);
+        let reason = VmExitReason::from_trap(&self.vcpu.trap_csrs);
+        let action = handle_vmexit(reason, self);
+        if irqs_enabled {
+            axhal::arch::enable_irqs();
+        }
+        action
+    }
+
+    /// Runs the guest until it stops, returns why, i.e. anything but
+    /// [`VmExitAction::Resume`].
+    pub fn run(&mut self) -> VmExitAction {
+        loop {
+            match self.run_once() {
+                VmExitAction::Resume => {}
+                action => return action,
+            }
+        }
+    }
+}
+
+/// Reads `len` bytes of the guest memory of `vm` at `gpa`, for host tools
+/// such as debuggers. All of the range must be mapped.
+pub fn vm_mem_read(vm: &Vm, gpa: usize, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    GuestMemory::new(&vm.aspace).read_at(gpa, &mut buf)?;
+    Ok(buf)
+}
+
+/// Writes `data` to the guest memory of `vm` at `gpa`, see [`vm_mem_read`].
+pub fn vm_mem_write(vm: &mut Vm, gpa: usize, data: &[u8]) -> io::Result<()> {
+    GuestMemory::new(&vm.aspace).write_at(gpa, data)
+}
+
+fn prepare_vm_pgtable(ept_root: PhysAddr) {
+    let hgatp = 8usize << 60 | usize::from(ept_root) >> 12;
+    unsafe {
+        core::arch::asm!(
+            "csrw hgatp, {hgatp}",
+            hgatp = in(reg) hgatp,
+        );
+        core::arch::riscv64::hfence_gvma_all();
+    }
+}
+
+fn prepare_guest_context(ctx: &mut VmCpuRegisters) {
+    // Set hstatus
+    let mut hstatus = LocalRegisterCopy::<usize, hstatus::Register>::new(
+        riscv::register::hstatus::read().bits(),
+    );
+    // Set Guest bit in order to return to guest mode.
+    hstatus.modify(hstatus::spv::Guest);
+    // Set SPVP bit in order to accessing VS-mode memory from HS-mode.
+    hstatus.modify(hstatus::spvp::Supervisor);
+    // Set VTW bit so that `wfi` in the guest traps to the hypervisor.
+    hstatus.modify(hstatus::vtw::SET);
+    CSR.hstatus.write_value(hstatus.get());
+    ctx.guest_regs.hstatus = hstatus.get();
+
+    // Set sstatus in guest mode.
+    let mut sstatus = sstatus::read();
+    sstatus.set_spp(sstatus::SPP::Supervisor);
+    ctx.guest_regs.sstatus = sstatus.bits();
+
+    irq::init_irq_delegation();
+}
@@ -0,0 +1,334 @@
+//! Decoding and dispatching of VM exits.
+
+use riscv::register::{scause, stval};
+use sbi_spec::legacy::LEGACY_SHUTDOWN;
+
+use crate::csrs::traps::{exception, interrupt};
+use crate::csrs::{RiscvCsrTrait, CSR};
+use crate::gpt::{self, Access};
+use crate::guest_mem::GuestMemory;
+use crate::mmio::MmioBus;
+use crate::{hypercall, irq, mmio, vcsr, vsbi};
+use crate::regs::GprIndex::{self, A0, A1, A7};
+use crate::sbi::SbiMessage;
+use crate::vcpu::{VmCpuRegisters, VmCpuTrapState};
+use crate::vm::Vm;
+
+/// Why the guest trapped back to the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExitReason {
+    /// `ecall` from VS-mode, i.e. an SBI call or a hypercall.
+    Ecall,
+    /// An illegal instruction, with the instruction bits from `stval`.
+    IllegalInstruction(usize),
+    /// A virtual instruction, i.e. one VS-mode is not allowed to execute,
+    /// such as an access to a counter disabled by `hcounteren`.
+    VirtualInstruction(usize),
+    /// A guest page fault at the guest physical address `gpa`, MMIO accesses
+    /// are among them.
+    GuestPageFault {
+        /// The faulting guest physical address.
+        gpa: usize,
+        /// Whether the access was a store.
+        write: bool,
+    },
+    /// A page fault on the guest's own page tables, at the guest virtual
+    /// address `gva`.
+    PageFault {
+        /// The faulting guest virtual address.
+        gva: usize,
+        /// The faulting access.
+        access: Access,
+    },
+    /// A host timer interrupt taken while the guest was running.
+    TimerInterrupt,
+    /// Any other trap, with the raw `scause`.
+    Other(usize),
+}
+
+/// What to do after a VM exit has been handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExitAction {
+    /// Enter the guest again.
+    Resume,
+    /// The guest shut down, with its exit code.
+    Shutdown(usize),
+    /// The guest ran off its stack into the guard page, at the guest
+    /// physical address given.
+    StackOverflow(usize),
+}
+
+const INST_WFI: u32 = 0x1050_0073;
+
+const SCAUSE_INTERRUPT: usize = 1 << (usize::BITS - 1);
+
+impl VmExitReason {
+    /// Decodes the exit reason from the trap CSRs saved on VM exit.
+    pub fn from_trap(trap: &VmCpuTrapState) -> Self {
+        if trap.scause & SCAUSE_INTERRUPT != 0 {
+            let irq = 1usize.checked_shl((trap.scause & !SCAUSE_INTERRUPT) as u32).unwrap_or(0);
+            return match irq {
+                interrupt::SUPERVISOR_TIMER => Self::TimerInterrupt,
+                _ => Self::Other(trap.scause),
+            };
+        }
+        // The guest physical address is `htval << 2`, plus the page offset
+        // bits from `stval`.
+        let gpa = (trap.htval << 2) | (trap.stval & 0b11);
+        match 1usize.checked_shl(trap.scause as u32).unwrap_or(0) {
+            exception::ENV_CALL_FROM_VS => Self::Ecall,
+            exception::ILLEGAL_INST => Self::IllegalInstruction(trap.stval),
+            exception::VIRTUAL_INST => Self::VirtualInstruction(trap.stval),
+            exception::LOAD_GUEST_PAGE_FAULT | exception::INST_GUEST_PAGE_FAULT => {
+                Self::GuestPageFault { gpa, write: false }
+            }
+            exception::STORE_GUEST_PAGE_FAULT => Self::GuestPageFault { gpa, write: true },
+            exception::INST_PAGE_FAULT => Self::PageFault { gva: trap.stval, access: Access::Fetch },
+            exception::LOAD_PAGE_FAULT => Self::PageFault { gva: trap.stval, access: Access::Load },
+            exception::STORE_PAGE_FAULT => Self::PageFault { gva: trap.stval, access: Access::Store },
+            _ => Self::Other(trap.scause),
+        }
+    }
+}
+
+/// Saves the trap CSRs of the last VM exit into `ctx`.
+pub fn save_trap_csrs(ctx: &mut VmCpuRegisters) {
+    ctx.trap_csrs = VmCpuTrapState {
+        scause: scause::read().bits(),
+        stval: stval::read(),
+        htval: CSR.htval.get_value(),
+        htinst: CSR.htinst.get_value(),
+    };
+}
+
+/// Prints the guest PC, general purpose registers and the last exit reason.
+pub fn dump_guest_state(ctx: &VmCpuRegisters) {
+    let trap = &ctx.trap_csrs;
+    ax_println!("Guest state:");
+    ax_println!("  exit: {:?}", VmExitReason::from_trap(trap));
+    ax_println!(
+        "  scause: {:#x}, stval: {:#x}, htval: {:#x}, htinst: {:#x}",
+        trap.scause, trap.stval, trap.htval, trap.htinst
+    );
+    ax_println!("  pc: {:#018x}", ctx.guest_regs.sepc);
+    for i in (0..32).step_by(4) {
+        let reg = |i| {
+            let idx = GprIndex::from_raw(i).unwrap();
+            (idx, ctx.guest_regs.gprs.reg(idx))
+        };
+        let (r0, r1, r2, r3) = (reg(i), reg(i + 1), reg(i + 2), reg(i + 3));
+        ax_println!(
+            "  {:?}: {:#018x}  {:?}: {:#018x}  {:?}: {:#018x}  {:?}: {:#018x}",
+            r0.0, r0.1, r1.0, r1.1, r2.0, r2.1, r3.0, r3.1
+        );
+    }
+}
+
+/// Numbers of VM exits of a guest, by reason.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExitStats {
+    /// SBI calls and hypercalls.
+    pub ecall: u64,
+    /// Emulated CSR accesses and other trapped instructions.
+    pub instruction: u64,
+    /// Guest page faults handled as MMIO accesses.
+    pub mmio: u64,
+    /// Other guest page faults, and page faults on the guest page tables.
+    pub page_fault: u64,
+    /// Host timer interrupts.
+    pub timer: u64,
+    /// Any other exit.
+    pub other: u64,
+}
+
+/// Returns the VM exit statistics of `vm`.
+pub fn exit_stats(vm: &Vm) -> ExitStats {
+    vm.exit_stats
+}
+
+/// Routes a VM exit to its handler.
+pub fn handle_vmexit(reason: VmExitReason, vm: &mut Vm) -> VmExitAction {
+    let ctx = &mut vm.vcpu;
+    let stats = &mut vm.exit_stats;
+    match reason {
+        VmExitReason::Ecall => {
+            stats.ecall += 1;
+            handle_ecall(ctx)
+        }
+        VmExitReason::IllegalInstruction(inst) | VmExitReason::VirtualInstruction(inst) => {
+            stats.instruction += 1;
+            handle_illegal_instruction(inst, ctx)
+        }
+        VmExitReason::GuestPageFault { gpa, .. }
+            if vm.stack.is_some_and(|stack| stack.is_guard(gpa)) =>
+        {
+            stats.page_fault += 1;
+            ax_println!("Guest stack overflow at {:#x}", gpa);
+            dump_guest_state(ctx);
+            VmExitAction::StackOverflow(gpa)
+        }
+        VmExitReason::GuestPageFault { gpa, write } => {
+            let mem = GuestMemory::new(&vm.aspace);
+            handle_guest_page_fault(gpa, write, ctx, &mut vm.mmio, &mem, stats)
+        }
+        VmExitReason::PageFault { gva, access } => {
+            stats.page_fault += 1;
+            let mem = GuestMemory::new(&vm.aspace);
+            handle_page_fault(gva, access, ctx, &mem)
+        }
+        VmExitReason::TimerInterrupt => {
+            stats.timer += 1;
+            irq::handle_host_timer(ctx);
+            VmExitAction::Resume
+        }
+        VmExitReason::Other(scause) => {
+            stats.other += 1;
+            dump_guest_state(ctx);
+            panic!(
+                "Unhandled trap: scause {:#x}, sepc: {:#x}, stval: {:#x}",
+                scause, ctx.guest_regs.sepc, ctx.trap_csrs.stval
+            );
+        }
+    }
+}
+
+fn handle_ecall(ctx: &mut VmCpuRegisters) -> VmExitAction {
+    if hypercall::is_hypercall(ctx) {
+        return hypercall::handle_hypercall(ctx);
+    }
+    let sbi_msg = SbiMessage::from_regs(ctx.guest_regs.gprs.a_regs()).ok();
+    ax_println!("VmExit Reason: VSuperEcall: {:?}", sbi_msg);
+    match sbi_msg {
+        // The legacy shutdown takes no argument, `a0` is taken as the exit
+        // code of the guest.
+        Some(SbiMessage::Reset(_)) if ctx.guest_regs.gprs.reg(A7) == LEGACY_SHUTDOWN => {
+            let a0 = ctx.guest_regs.gprs.reg(A0);
+            let a1 = ctx.guest_regs.gprs.reg(A1);
+            ax_println!("a0 = {:#x}, a1 = {:#x}", a0, a1);
+            ax_println!("Shutdown vm normally!");
+            VmExitAction::Shutdown(a0)
+        }
+        Some(msg) => vsbi::handle_sbi_call(msg, ctx),
+        None => {
+            dump_guest_state(ctx);
+            panic!("bad sbi message! ");
+        }
+    }
+}
+
+fn handle_illegal_instruction(inst: usize, ctx: &mut VmCpuRegisters) -> VmExitAction {
+    // 尝试模拟特权指令
+    if vcsr::handle_csr_access(inst, ctx) {
+        return VmExitAction::Resume; // 继续运行 guest
+    }
+    // `wfi` traps when `hstatus.VTW` is set. With nothing that could wake the
+    // guest up, it would wait forever, so take it as a shutdown.
+    if inst as u32 == INST_WFI {
+        if ctx.pending_irqs == 0 && !irq::guest_timer_armed(ctx) {
+            ax_println!("Guest waits for interrupts with none pending, shutting down");
+            return VmExitAction::Shutdown(0);
+        }
+        ctx.guest_regs.sepc += 4;
+        return VmExitAction::Resume;
+    }
+
+    dump_guest_state(ctx);
+    panic!("Bad instruction: {:#x} sepc: {:#x}", inst, ctx.guest_regs.sepc);
+}
+
+fn handle_guest_page_fault(
+    gpa: usize,
+    write: bool,
+    ctx: &mut VmCpuRegisters,
+    bus: &mut MmioBus,
+    mem: &GuestMemory,
+    stats: &mut ExitStats,
+) -> VmExitAction {
+    // Device ranges are not mapped, so MMIO accesses end up here.
+    if mmio::handle_mmio(bus, gpa, write, ctx, mem) {
+        stats.mmio += 1;
+        return VmExitAction::Resume;
+    }
+    stats.page_fault += 1;
+    dump_guest_state(ctx);
+    panic!(
+        "{}GuestPageFault: gpa {:#x} sepc: {:#x}",
+        if write { "Store" } else { "Load" },
+        gpa,
+        ctx.guest_regs.sepc
+    );
+}
+
+fn handle_page_fault(gva: usize, access: Access, ctx: &mut VmCpuRegisters, mem: &GuestMemory) -> VmExitAction {
+    if gpt::update_access_bits(mem, CSR.vsatp.get_value(), gva, access) {
+        // Retry the access with the updated PTE.
+        unsafe { core::arch::riscv64::hfence_vvma_all() };
+        return VmExitAction::Resume;
+    }
+    // A genuine page fault, for the guest kernel to handle.
+    irq::inject_exception(ctx, ctx.trap_csrs.scause, gva);
+    VmExitAction::Resume
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trap(scause: usize, stval: usize, htval: usize) -> VmCpuTrapState {
+        VmCpuTrapState { scause, stval, htval, htinst: 0 }
+    }
+
+    #[test]
+    fn decode_exceptions() {
+        assert_eq!(VmExitReason::from_trap(&trap(10, 0, 0)), VmExitReason::Ecall);
+        assert_eq!(
+            VmExitReason::from_trap(&trap(2, 0xf140_25f3, 0)),
+            VmExitReason::IllegalInstruction(0xf140_25f3)
+        );
+        assert_eq!(
+            VmExitReason::from_trap(&trap(22, 0x1050_0073, 0)),
+            VmExitReason::VirtualInstruction(0x1050_0073)
+        );
+        assert_eq!(
+            VmExitReason::from_trap(&trap(13, 0x1234, 0)),
+            VmExitReason::PageFault { gva: 0x1234, access: Access::Load }
+        );
+        assert_eq!(
+            VmExitReason::from_trap(&trap(15, 0x5678, 0)),
+            VmExitReason::PageFault { gva: 0x5678, access: Access::Store }
+        );
+        // A breakpoint isn't handled.
+        assert_eq!(VmExitReason::from_trap(&trap(3, 0, 0)), VmExitReason::Other(3));
+    }
+
+    #[test]
+    fn decode_guest_page_faults() {
+        // `htval` is the address shifted right by 2, `stval` gives the rest.
+        assert_eq!(
+            VmExitReason::from_trap(&trap(21, 0x1000_0003, 0x0400_0000)),
+            VmExitReason::GuestPageFault { gpa: 0x1000_0003, write: false }
+        );
+        assert_eq!(
+            VmExitReason::from_trap(&trap(23, 0x1000_0000, 0x0400_0000)),
+            VmExitReason::GuestPageFault { gpa: 0x1000_0000, write: true }
+        );
+        assert_eq!(
+            VmExitReason::from_trap(&trap(20, 0x8020_0000, 0x2008_0000)),
+            VmExitReason::GuestPageFault { gpa: 0x8020_0000, write: false }
+        );
+    }
+
+    #[test]
+    fn decode_interrupts() {
+        assert_eq!(
+            VmExitReason::from_trap(&trap(SCAUSE_INTERRUPT | 5, 0, 0)),
+            VmExitReason::TimerInterrupt
+        );
+        assert_eq!(
+            VmExitReason::from_trap(&trap(SCAUSE_INTERRUPT | 9, 0, 0)),
+            VmExitReason::Other(SCAUSE_INTERRUPT | 9)
+        );
+        // Out of range causes don't overflow the shift.
+        assert_eq!(VmExitReason::from_trap(&trap(100, 0, 0)), VmExitReason::Other(100));
+    }
+}
@@ -0,0 +1,127 @@
+//! Emulation of the SBI calls made by the guest.
+//!
+//! Legacy extensions return a single value in `a0`, the others an error code
+//! in `a0` and a value in `a1`.
+
+use sbi_spec::{base, legacy, srst, time};
+
+use crate::irq;
+use crate::regs::GprIndex::{A0, A1, A7};
+use crate::sbi::{
+    BaseFunction, ResetFunction, SbiMessage, SBI_ERR_NOT_SUPPORTED, SBI_SUCCESS,
+};
+use crate::vcpu::VmCpuRegisters;
+use crate::vmexit::VmExitAction;
+
+/// The SBI specification version reported to the guest, 1.0.
+const SBI_SPEC_VERSION: usize = 1 << 24;
+
+/// The extensions the guest can probe for.
+const SUPPORTED_EXTENSIONS: &[usize] = &[base::EID_BASE, time::EID_TIME, srst::EID_SRST];
+
+/// Handles the SBI call `msg` of the guest.
+///
+/// If the guest is resumed, its PC is moved past the `ecall`.
+pub fn handle_sbi_call(msg: SbiMessage, ctx: &mut VmCpuRegisters) -> VmExitAction {
+    let eid = ctx.guest_regs.gprs.reg(A7);
+    let action = match msg {
+        SbiMessage::PutChar(c) => {
+            ax_print!("{}", c as u8 as char);
+            legacy_return(ctx, 0)
+        }
+        // No input is forwarded to the guest.
+        SbiMessage::GetChar => legacy_return(ctx, -1isize as usize),
+        SbiMessage::SetTimer(deadline) => {
+            irq::set_guest_timer(ctx, deadline as u64);
+            if eid == legacy::LEGACY_SET_TIMER {
+                legacy_return(ctx, 0)
+            } else {
+                sbi_return(ctx, SBI_SUCCESS, 0)
+            }
+        }
+        SbiMessage::Reset(ResetFunction::Reset { reset_type, reason }) => {
+            ax_println!("Guest system reset: {:?}, {:?}", reset_type, reason);
+            VmExitAction::Shutdown(reason as usize)
+        }
+        SbiMessage::Base(func) => {
+            let value = match func {
+                BaseFunction::GetSepcificationVersion => SBI_SPEC_VERSION,
+                BaseFunction::ProbeSbiExtension(eid) => {
+                    SUPPORTED_EXTENSIONS.contains(&(eid as usize)) as usize
+                }
+                _ => 0,
+            };
+            sbi_return(ctx, SBI_SUCCESS, value)
+        }
+        _ => {
+            warn!("Unsupported SBI call: {:?}", msg);
+            sbi_return(ctx, SBI_ERR_NOT_SUPPORTED as usize, 0)
+        }
+    };
+    if action == VmExitAction::Resume {
+        ctx.guest_regs.sepc += 4;
+    }
+    action
+}
+
+fn legacy_return(ctx: &mut VmCpuRegisters, value: usize) -> VmExitAction {
+    ctx.guest_regs.gprs.set_reg(A0, value);
+    VmExitAction::Resume
+}
+
+fn sbi_return(ctx: &mut VmCpuRegisters, error: usize, value: usize) -> VmExitAction {
+    ctx.guest_regs.gprs.set_reg(A0, error);
+    ctx.guest_regs.gprs.set_reg(A1, value);
+    VmExitAction::Resume
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes the call `msg` with the extension ID `eid`, returns the action
+    /// and `a0`, `a1`.
+    fn call(eid: usize, msg: SbiMessage) -> (VmExitAction, usize, usize) {
+        let mut ctx = VmCpuRegisters::default();
+        ctx.guest_regs.gprs.set_reg(A7, eid);
+        let action = handle_sbi_call(msg, &mut ctx);
+        if action == VmExitAction::Resume {
+            assert_eq!(ctx.guest_regs.sepc, 4);
+        }
+        (action, ctx.guest_regs.gprs.reg(A0), ctx.guest_regs.gprs.reg(A1))
+    }
+
+    /// Makes the base call `fid` with `a0` as its argument, decoded from the
+    /// registers as on an `ecall`.
+    fn base_call(fid: usize, a0: usize) -> (VmExitAction, usize, usize) {
+        let args = [a0, 0, 0, 0, 0, 0, fid, base::EID_BASE];
+        call(base::EID_BASE, SbiMessage::from_regs(&args).unwrap())
+    }
+
+    #[test]
+    fn base_replies() {
+        let ok = |value| (VmExitAction::Resume, SBI_SUCCESS, value);
+        assert_eq!(base_call(0, 0), ok(SBI_SPEC_VERSION));
+        assert_eq!(base_call(3, base::EID_BASE), ok(1));
+        assert_eq!(base_call(3, time::EID_TIME), ok(1));
+        assert_eq!(base_call(3, srst::EID_SRST), ok(1));
+        // Decoded but not emulated.
+        assert_eq!(base_call(3, sbi_spec::pmu::EID_PMU), ok(0));
+        assert_eq!(base_call(4, 0), ok(0));
+    }
+
+    #[test]
+    fn legacy_replies() {
+        // Legacy calls leave `a1` alone.
+        let putchar = SbiMessage::PutChar(b'x' as usize);
+        assert_eq!(call(legacy::LEGACY_CONSOLE_PUTCHAR, putchar), (VmExitAction::Resume, 0, 0));
+        let getchar = call(legacy::LEGACY_CONSOLE_GETCHAR, SbiMessage::GetChar);
+        assert_eq!(getchar, (VmExitAction::Resume, -1isize as usize, 0));
+    }
+
+    #[test]
+    fn reset_shuts_down() {
+        let (action, ..) = call(srst::EID_SRST, SbiMessage::Reset(ResetFunction::shutdown()));
+        assert_eq!(action, VmExitAction::Shutdown(0));
+    }
+}
@@ -0,0 +1,105 @@
+//! A minimal futex implementation, only `FUTEX_WAIT` and `FUTEX_WAKE`.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use axerrno::{LinuxError, LinuxResult};
+use axsync::Mutex;
+use axtask::WaitQueue;
+
+/// Waiters on a single futex word.
+struct FutexQueue {
+    wq: WaitQueue,
+    /// Bumped on every wake, so that a waiter which has checked the futex
+    /// word but not blocked yet won't miss the wake up.
+    seq: AtomicUsize,
+    /// The tasks waiting, or about to. Only changed with [`FUTEX_QUEUES`]
+    /// locked, the queue is removed when the last one leaves.
+    waiters: AtomicUsize,
+}
+
+/// Futex queues, keyed by user address.
+///
+/// Threads share the whole address space, so the address alone identifies
+/// a futex word.
+static FUTEX_QUEUES: Mutex<BTreeMap<usize, Arc<FutexQueue>>> = Mutex::new(BTreeMap::new());
+
+/// Returns the queue of `uaddr`, created if needed, as a waiter of it.
+fn join_queue(uaddr: usize) -> Arc<FutexQueue> {
+    let mut queues = FUTEX_QUEUES.lock();
+    let queue = queues.entry(uaddr).or_insert_with(|| {
+        Arc::new(FutexQueue {
+            wq: WaitQueue::new(),
+            seq: AtomicUsize::new(0),
+            waiters: AtomicUsize::new(0),
+        })
+    });
+    queue.waiters.fetch_add(1, Ordering::Relaxed);
+    queue.clone()
+}
+
+/// Leaves the queue of `uaddr` joined by [`join_queue`], which is removed
+/// if no one else waits on it.
+fn leave_queue(uaddr: usize, queue: &FutexQueue) {
+    let mut queues = FUTEX_QUEUES.lock();
+    if queue.waiters.fetch_sub(1, Ordering::Relaxed) == 1 {
+        queues.remove(&uaddr);
+    }
+}
+
+/// Blocks the current task on `uaddr` if it still contains `val`, until it
+/// is woken up or `interrupted` returns true. `interrupted` is called with
+/// the run queue locked, it must not block.
+///
+/// Returns `EAGAIN` if the value has changed, `EINTR` if interrupted.
+pub fn futex_wait(
+    uaddr: *const u32,
+    val: u32,
+    interrupted: impl Fn() -> bool,
+) -> LinuxResult<usize> {
+    let queue = join_queue(uaddr as usize);
+    let seq = queue.seq.load(Ordering::Acquire);
+    let ret = if unsafe { uaddr.read_volatile() } != val {
+        Err(LinuxError::EAGAIN)
+    } else {
+        // `futex_wake_all` only wakes the queues there are when it runs, a
+        // task joining one after that must notice the interruption itself.
+        queue
+            .wq
+            .wait_until(|| queue.seq.load(Ordering::Acquire) != seq || interrupted());
+        if queue.seq.load(Ordering::Acquire) != seq {
+            Ok(0)
+        } else {
+            Err(LinuxError::EINTR)
+        }
+    };
+    leave_queue(uaddr as usize, &queue);
+    ret
+}
+
+/// Wakes up at most `count` tasks waiting on `uaddr`.
+///
+/// Returns the number of woken tasks.
+pub fn futex_wake(uaddr: *const u32, count: usize) -> usize {
+    let Some(queue) = FUTEX_QUEUES.lock().get(&(uaddr as usize)).cloned() else {
+        return 0;
+    };
+    queue.seq.fetch_add(1, Ordering::Release);
+    let mut woken = 0;
+    while woken < count && queue.wq.notify_one(false) {
+        woken += 1;
+    }
+    woken
+}
+
+/// Wakes up all tasks waiting on any futex, for them to check whether they
+/// are interrupted.
+pub fn futex_wake_all() {
+    let queues: Vec<_> = FUTEX_QUEUES.lock().values().cloned().collect();
+    for queue in queues {
+        queue.seq.fetch_add(1, Ordering::Release);
+        queue.wq.notify_all(false);
+    }
+}
@@ -11,11 +11,15 @@ extern crate axlog;
 mod task;
 mod syscall;
 mod loader;
+mod futex;
+mod uaccess;
 
 use axstd::io;
 use axhal::paging::MappingFlags;
 use axhal::arch::UspaceContext;
 use axhal::mem::VirtAddr;
+use axhal::trap::{register_trap_handler, PAGE_FAULT};
+use axtask::TaskExtRef;
 use axsync::Mutex;
 use alloc::sync::Arc;
 use alloc::string::String;
@@ -23,8 +27,22 @@ use alloc::collections::BTreeMap;
 use axmm::AddrSpace;
 use loader::load_user_app;
 
+/// The user app run by the kernel, `SYS_MAP_APP` at build time to run another
+/// one, e.g. the `mmtest` payload.
+const APP_PATH: &str = match option_env!("SYS_MAP_APP") {
+    Some(path) => path,
+    None => "/sbin/mapfile",
+};
 const USER_STACK_SIZE: usize = 0x10000;
 const KERNEL_STACK_SIZE: usize = 0x40000; // 256 KiB
+/// Exit code of a task killed by SIGSEGV, as reported by a shell.
+const SIGSEGV_EXIT_CODE: i32 = 128 + 11;
+/// Size of the fd table, the highest `RLIMIT_NOFILE`.
+const FD_LIMIT: i32 = 1024;
+/// Maximum number of `mmap` regions per task, the default `vm.max_map_count` of Linux.
+const MAX_MAP_COUNT: usize = 65530;
+/// Whether `mmap` refuses shared file mappings both writable and executable.
+const DENY_SHARED_WX: bool = true;
 
 #[cfg_attr(feature = "axstd", no_mangle)]
 fn main() {
@@ -32,7 +50,7 @@ fn main() {
     let mut uspace = axmm::new_user_aspace().unwrap();
 
     // Load user app binary file into address space.
-    let entry = match load_user_app("/sbin/mapfile", &mut uspace) {
+    let entry = match load_user_app(APP_PATH, &mut uspace) {
         Ok(e) => e,
         Err(err) => panic!("Cannot load app! {:?}", err),
     };
@@ -44,6 +62,7 @@ fn main() {
 
     // Let's kick off the user process.
     let user_task = task::spawn_user_task(
+        APP_PATH,
         Arc::new(Mutex::new(uspace)),
         UspaceContext::new(entry, ustack_top),
     );
@@ -80,3 +99,45 @@ fn init_user_stack(uspace: &mut AddrSpace, populating: bool) -> io::Result<VirtA
 
     Ok(ustack_pointer.into())
 }
+
+#[register_trap_handler(PAGE_FAULT)]
+fn handle_page_fault(vaddr: VirtAddr, access_flags: MappingFlags, is_user: bool) -> bool {
+    if is_user {
+        let curr = axtask::current();
+        if !curr
+            .task_ext()
+            .aspace
+            .lock()
+            .handle_page_fault(vaddr, access_flags)
+        {
+            ax_println!(
+                "{}: segmentation fault at {:#x} ({:?}), exit!",
+                curr.id_name(),
+                vaddr,
+                access_flags
+            );
+            // There is no signal delivery yet, so the fault is always fatal
+            // for the whole process.
+            syscall::sys_exit_group(SIGSEGV_EXIT_CODE);
+        }
+        curr.task_ext().inc_page_faults();
+        true
+    } else {
+        // The kernel touches user buffers directly in some syscalls, the pages
+        // of lazy mappings are faulted in for it as well. A bad user address
+        // is still a kernel bug here, it should have been caught by uaccess.
+        let Some(curr) = axtask::current_may_uninit() else {
+            return false;
+        };
+        // Kernel tasks have no user address space.
+        if unsafe { curr.task_ext_ptr() }.is_null() {
+            return false;
+        }
+        let task_ext = curr.task_ext();
+        let handled = task_ext.aspace.lock().handle_page_fault(vaddr, access_flags);
+        if handled {
+            task_ext.inc_page_faults();
+        }
+        handled
+    }
+}
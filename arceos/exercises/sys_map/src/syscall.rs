@@ -1,32 +1,151 @@
 #![allow(dead_code)]
 
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::ffi::{c_char, c_int, c_void};
+use core::fmt::Write;
 use axerrno::{AxError, LinuxError};
 use axhal::arch::TrapFrame;
+#[cfg(target_arch = "riscv64")]
+use axhal::arch::UspaceContext;
 use axhal::mem::VirtAddr;
 use axhal::paging::MappingFlags;
 use axhal::trap::{register_trap_handler, SYSCALL};
+use axmm::AddrSpace;
 use arceos_posix_api as api;
 use axtask::current;
 use axtask::TaskExtRef;
-use memory_addr::{VirtAddrRange, PAGE_SIZE_4K};
+use axsync::Mutex;
+use crate::futex;
+use crate::FD_LIMIT;
+use crate::uaccess::{
+    check_user_range, copy_bytes_to_user, copy_from_user, copy_path_from_user, copy_to_user, PATH_MAX,
+};
+use crate::task::{
+    FdFlags, MmapRegion, RLimit, SigAction, NSIG, PAGE_SIZE_2M, RLIMIT_NOFILE, RLIM_NLIMITS,
+    TASK_COMM_LEN,
+};
+use memory_addr::{MemoryAddr, VirtAddrRange, PAGE_SIZE_4K};
 
+const SYS_GETCWD: usize = 17;
+const SYS_FCNTL: usize = 25;
 const SYS_IOCTL: usize = 29;
+const SYS_FACCESSAT: usize = 48;
 const SYS_OPENAT: usize = 56;
 const SYS_CLOSE: usize = 57;
 const SYS_READ: usize = 63;
 const SYS_WRITE: usize = 64;
 const SYS_WRITEV: usize = 66;
+const SYS_READLINKAT: usize = 78;
 const SYS_EXIT: usize = 93;
 const SYS_EXIT_GROUP: usize = 94;
 const SYS_SET_TID_ADDRESS: usize = 96;
+const SYS_FUTEX: usize = 98;
+const SYS_SET_ROBUST_LIST: usize = 99;
+const SYS_GET_ROBUST_LIST: usize = 100;
+const SYS_RT_SIGACTION: usize = 134;
+const SYS_RT_SIGPROCMASK: usize = 135;
+const SYS_TIMES: usize = 153;
+const SYS_GETRLIMIT: usize = 163;
+const SYS_SETRLIMIT: usize = 164;
+const SYS_PRCTL: usize = 167;
+const SYS_GETPID: usize = 172;
+const SYS_GETPPID: usize = 173;
+const SYS_GETUID: usize = 174;
+const SYS_GETEUID: usize = 175;
+const SYS_GETGID: usize = 176;
+const SYS_GETEGID: usize = 177;
+const SYS_SYSINFO: usize = 179;
+const SYS_MUNMAP: usize = 215;
+const SYS_MREMAP: usize = 216;
+#[cfg(target_arch = "riscv64")]
+const SYS_CLONE: usize = 220;
 const SYS_MMAP: usize = 222;
+const SYS_MPROTECT: usize = 226;
+const SYS_MLOCK: usize = 228;
+const SYS_MUNLOCK: usize = 229;
+const SYS_MLOCKALL: usize = 230;
+const SYS_PRLIMIT64: usize = 261;
+const SYS_MEMBARRIER: usize = 283;
+const SYS_STATX: usize = 291;
+/// Non-standard syscall to print the mmap regions of the current task, or to
+/// read them into a buffer.
+const SYS_DUMP_MAPS: usize = 0x1000;
+/// Non-standard syscall to get the number of page faults serviced for the current task.
+const SYS_PAGE_FAULTS: usize = 0x1001;
+/// Non-standard syscall to get how many times an unimplemented syscall was called.
+const SYS_UNIMPLEMENTED_HITS: usize = 0x1002;
 
 const AT_FDCWD: i32 = -100;
 const SEEK_SET: c_int = 0;
 const SEEK_CUR: c_int = 1;
 
+/// The only user, everything runs as root.
+const USER_ID: u32 = 0;
+const GROUP_ID: u32 = 0;
+
+/// Clock ticks per second reported by `times`, i.e. `sysconf(_SC_CLK_TCK)`.
+const CLOCK_TICKS_PER_SEC: u64 = 100;
+
+const IOV_MAX: c_int = 1024;
+
+const AT_EMPTY_PATH: c_int = 0x1000;
+
+/// The link to the executable of the calling process.
+const PROC_SELF_EXE: &[u8] = b"/proc/self/exe";
+
+const STATX_TYPE: u32 = 0x1;
+const STATX_MODE: u32 = 0x2;
+const STATX_NLINK: u32 = 0x4;
+const STATX_UID: u32 = 0x8;
+const STATX_GID: u32 = 0x10;
+const STATX_INO: u32 = 0x100;
+const STATX_SIZE: u32 = 0x200;
+const STATX_BLOCKS: u32 = 0x400;
+const STATX_RESERVED: u32 = 0x8000_0000;
+
+const TIOCGWINSZ: usize = 0x5413;
+const FIONBIO: usize = 0x5421;
+
+const SIGKILL: usize = 9;
+const SIGSTOP: usize = 19;
+
+const SIG_BLOCK: c_int = 0;
+const SIG_UNBLOCK: c_int = 1;
+const SIG_SETMASK: c_int = 2;
+
+const FUTEX_WAIT: c_int = 0;
+const FUTEX_WAKE: c_int = 1;
+const FUTEX_PRIVATE_FLAG: c_int = 128;
+const FUTEX_CLOCK_REALTIME: c_int = 256;
+
+/// The end of the addresses handed out for MAP_32BIT.
+const MAP_32BIT_END: usize = 0x1_0000_0000;
+
+const MREMAP_MAYMOVE: c_int = 1;
+const MREMAP_FIXED: c_int = 2;
+
+const PR_SET_NAME: c_int = 15;
+const PR_GET_NAME: c_int = 16;
+
+const MCL_CURRENT: c_int = 1;
+const MCL_FUTURE: c_int = 2;
+const MCL_ONFAULT: c_int = 4;
+
+const MEMBARRIER_CMD_QUERY: c_int = 0;
+const MEMBARRIER_CMD_GLOBAL: c_int = 1;
+const MEMBARRIER_CMD_GLOBAL_EXPEDITED: c_int = 2;
+const MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED: c_int = 4;
+const MEMBARRIER_CMD_PRIVATE_EXPEDITED: c_int = 8;
+const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED: c_int = 16;
+
+const F_OK: c_int = 0;
+const X_OK: c_int = 1;
+const W_OK: c_int = 2;
+const R_OK: c_int = 4;
+
 /// Macro to generate syscall body
 ///
 /// It will receive a function which return Result<_, LinuxError> and convert it to
@@ -94,46 +213,200 @@ bitflags::bitflags! {
         const MAP_FIXED = 1 << 4;
         /// Don't use a file.
         const MAP_ANONYMOUS = 1 << 5;
+        /// Only give out 32-bit addresses.
+        const MAP_32BIT = 1 << 6;
         /// Don't check for reservations.
         const MAP_NORESERVE = 1 << 14;
+        /// Populate (prefault) pagetables.
+        const MAP_POPULATE = 1 << 15;
         /// Allocation is for a stack.
         const MAP_STACK = 0x20000;
+        /// Create huge page mapping.
+        const MAP_HUGETLB = 0x40000;
     }
 }
 
-#[register_trap_handler(SYSCALL)]
-fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
-    ax_println!("handle_syscall [{}] ...", syscall_num);
-    let ret = match syscall_num {
-        SYS_IOCTL => sys_ioctl(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _) as _,
-        SYS_SET_TID_ADDRESS => sys_set_tid_address(tf.arg0() as _),
-        SYS_OPENAT => sys_openat(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _, tf.arg3() as _),
-        SYS_CLOSE => sys_close(tf.arg0() as _),
-        SYS_READ => sys_read(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _),
-        SYS_WRITE => sys_write(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _),
-        SYS_WRITEV => sys_writev(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _),
-        SYS_EXIT_GROUP => {
-            ax_println!("[SYS_EXIT_GROUP]: system is exiting ..");
-            axtask::exit(tf.arg0() as _)
-        },
-        SYS_EXIT => {
-            ax_println!("[SYS_EXIT]: system is exiting ..");
-            axtask::exit(tf.arg0() as _)
-        },
-        SYS_MMAP => sys_mmap(
+#[cfg(target_arch = "riscv64")]
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    /// flags for sys_clone, the supported ones
+    ///
+    /// See <https://github.com/torvalds/linux/blob/master/include/uapi/linux/sched.h>
+    struct CloneFlags: usize {
+        /// Share the address space.
+        const CLONE_VM = 0x100;
+        /// Share the filesystem information.
+        const CLONE_FS = 0x200;
+        /// Share the file descriptor table.
+        const CLONE_FILES = 0x400;
+        /// Share the signal handlers.
+        const CLONE_SIGHAND = 0x800;
+        /// Put the child in the thread group of the caller.
+        const CLONE_THREAD = 0x10000;
+        /// Share the System V semaphore undo values.
+        const CLONE_SYSVSEM = 0x40000;
+        /// Set the thread pointer of the child to `tls`.
+        const CLONE_SETTLS = 0x80000;
+        /// Store the child thread ID at `ptid`.
+        const CLONE_PARENT_SETTID = 0x100000;
+        /// Clear `ctid` and wake a futex on it when the child exits.
+        const CLONE_CHILD_CLEARTID = 0x200000;
+        /// Store the child thread ID at `ctid`, in the child.
+        const CLONE_CHILD_SETTID = 0x1000000;
+    }
+}
+
+/// A syscall handler, which extracts its arguments from the trap frame.
+type SyscallHandler = fn(&TrapFrame) -> isize;
+
+/// Syscall dispatch table, sorted by syscall number.
+const SYSCALL_TABLE: &[(usize, SyscallHandler)] = &[
+    (SYS_GETCWD, |tf| sys_getcwd(tf.arg0() as _, tf.arg1() as _)),
+    (SYS_FCNTL, |tf| sys_fcntl(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _)),
+    (SYS_IOCTL, |tf| sys_ioctl(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _)),
+    (SYS_FACCESSAT, |tf| sys_faccessat(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _, tf.arg3() as _)),
+    (SYS_OPENAT, |tf| sys_openat(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _, tf.arg3() as _)),
+    (SYS_CLOSE, |tf| sys_close(tf.arg0() as _)),
+    (SYS_READ, |tf| sys_read(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _)),
+    (SYS_WRITE, |tf| sys_write(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _)),
+    (SYS_WRITEV, |tf| sys_writev(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _)),
+    (SYS_READLINKAT, |tf| sys_readlinkat(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _, tf.arg3() as _)),
+    (SYS_EXIT, |tf| {
+        ax_println!("[SYS_EXIT]: system is exiting ..");
+        sys_exit(tf.arg0() as _)
+    }),
+    (SYS_EXIT_GROUP, |tf| {
+        ax_println!("[SYS_EXIT_GROUP]: system is exiting ..");
+        sys_exit_group(tf.arg0() as _)
+    }),
+    (SYS_SET_TID_ADDRESS, |tf| sys_set_tid_address(tf.arg0() as _)),
+    (SYS_FUTEX, |tf| sys_futex(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _)),
+    (SYS_SET_ROBUST_LIST, |tf| sys_set_robust_list(tf.arg0() as _, tf.arg1() as _)),
+    (SYS_GET_ROBUST_LIST, |tf| sys_get_robust_list(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _)),
+    (SYS_RT_SIGACTION, |tf| sys_rt_sigaction(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _, tf.arg3() as _)),
+    (SYS_RT_SIGPROCMASK, |tf| sys_rt_sigprocmask(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _, tf.arg3() as _)),
+    (SYS_TIMES, |tf| sys_times(tf.arg0() as _)),
+    (SYS_GETRLIMIT, |tf| sys_prlimit64(0, tf.arg0() as _, core::ptr::null(), tf.arg1() as _)),
+    (SYS_SETRLIMIT, |tf| sys_prlimit64(0, tf.arg0() as _, tf.arg1() as _, core::ptr::null_mut())),
+    (SYS_PRCTL, |tf| sys_prctl(tf.arg0() as _, tf.arg1() as _)),
+    (SYS_GETPID, |_| current().task_ext().proc_id as isize),
+    (SYS_GETPPID, |_| current().task_ext().parent_id as isize),
+    (SYS_GETUID, |_| USER_ID as isize),
+    (SYS_GETEUID, |_| USER_ID as isize),
+    (SYS_GETGID, |_| GROUP_ID as isize),
+    (SYS_GETEGID, |_| GROUP_ID as isize),
+    (SYS_SYSINFO, |tf| sys_sysinfo(tf.arg0() as _)),
+    (SYS_MUNMAP, |tf| sys_munmap(tf.arg0() as _, tf.arg1() as _)),
+    (SYS_MREMAP, |tf| sys_mremap(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _, tf.arg3() as _)),
+    #[cfg(target_arch = "riscv64")]
+    (SYS_CLONE, |tf| sys_clone(tf, tf.arg0() as _, tf.arg1() as _, tf.arg2() as _, tf.arg3() as _, tf.arg4() as _)),
+    (SYS_MMAP, |tf| {
+        sys_mmap(
             tf.arg0() as _,
             tf.arg1() as _,
             tf.arg2() as _,
             tf.arg3() as _,
             tf.arg4() as _,
             tf.arg5() as _,
-        ),
-        _ => {
+        )
+    }),
+    (SYS_MPROTECT, |tf| sys_mprotect(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _)),
+    (SYS_MLOCK, |tf| sys_mlock(tf.arg0() as _, tf.arg1() as _)),
+    (SYS_MUNLOCK, |tf| sys_munlock(tf.arg0() as _, tf.arg1() as _)),
+    (SYS_MLOCKALL, |tf| sys_mlockall(tf.arg0() as _)),
+    (SYS_PRLIMIT64, |tf| sys_prlimit64(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _, tf.arg3() as _)),
+    (SYS_MEMBARRIER, |tf| sys_membarrier(tf.arg0() as _, tf.arg1() as _)),
+    (SYS_STATX, |tf| sys_statx(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _, tf.arg3() as _, tf.arg4() as _)),
+    (SYS_DUMP_MAPS, |tf| sys_dump_maps(tf.arg0() as _, tf.arg1() as _)),
+    (SYS_PAGE_FAULTS, |_| current().task_ext().page_faults() as isize),
+    (SYS_UNIMPLEMENTED_HITS, |tf| unimplemented_hits(tf.arg0()) as isize),
+];
+
+// `dispatch_syscall` binary searches the table, which needs the numbers to
+// be strictly ascending.
+const _: () = {
+    let mut i = 1;
+    while i < SYSCALL_TABLE.len() {
+        assert!(
+            SYSCALL_TABLE[i - 1].0 < SYSCALL_TABLE[i].0,
+            "SYSCALL_TABLE is not sorted by syscall number"
+        );
+        i += 1;
+    }
+};
+
+/// Number of hits of each unimplemented syscall, keyed by syscall number.
+static UNIMPLEMENTED_SYSCALLS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+#[register_trap_handler(SYSCALL)]
+fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
+    ax_println!("handle_syscall [{}] ...", syscall_num);
+    exit_if_group_exiting();
+    let start = current().cpu_time_nanos();
+    let ret = dispatch_syscall(tf, syscall_num);
+    let curr = current();
+    curr.task_ext()
+        .add_sys_time(curr.cpu_time_nanos().saturating_sub(start));
+    exit_if_group_exiting();
+    ret
+}
+
+/// Exits the current thread if another thread has called `exit_group`.
+fn exit_if_group_exiting() {
+    if let Some(exit_code) = current().task_ext().group.exit_code() {
+        sys_exit(exit_code);
+    }
+}
+
+/// Looks up the handler of `syscall_num` and calls it, returns `-ENOSYS` if
+/// there is none.
+fn dispatch_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
+    match SYSCALL_TABLE.binary_search_by_key(&syscall_num, |&(num, _)| num) {
+        Ok(index) => (SYSCALL_TABLE[index].1)(tf),
+        Err(_) => {
             ax_println!("Unimplemented syscall: {}", syscall_num);
+            *UNIMPLEMENTED_SYSCALLS.lock().entry(syscall_num).or_insert(0) += 1;
             -LinuxError::ENOSYS.code() as _
         }
-    };
-    ret
+    }
+}
+
+/// Copies `length` bytes of `fd` at `offset` to the area `[target, target +
+/// len_aligned)` of `aspace`, the rest of the area is zeroed. The file
+/// position is kept.
+fn read_file_area(
+    aspace: &mut AddrSpace,
+    target: VirtAddr,
+    len_aligned: usize,
+    fd: i32,
+    offset: usize,
+    length: usize,
+) -> Result<(), LinuxError> {
+    let saved = syscall_ret_to_isize(api::sys_lseek(fd, 0, SEEK_CUR) as isize)?;
+    syscall_ret_to_isize(api::sys_lseek(fd, offset as _, SEEK_SET) as isize)?;
+
+    let mut buf: Vec<u8> = Vec::with_capacity(length);
+    buf.resize(length, 0);
+    let read_len = syscall_ret_to_isize(
+        api::sys_read(fd, buf.as_mut_ptr() as *mut c_void, length) as isize,
+    )? as usize;
+
+    if read_len > 0 {
+        aspace
+            .write(target, &buf[..read_len])
+            .map_err(ax_err_to_linux)?;
+    }
+    // Bytes past the end of file must read as zero, don't rely on the
+    // frames being zeroed by the backend.
+    if read_len < len_aligned {
+        let zeros = vec![0u8; len_aligned - read_len];
+        aspace
+            .write(target + read_len, &zeros)
+            .map_err(ax_err_to_linux)?;
+    }
+
+    syscall_ret_to_isize(api::sys_lseek(fd, saved as _, SEEK_SET) as isize)?;
+    Ok(())
 }
 
 fn sys_mmap(
@@ -156,113 +429,920 @@ fn sys_mmap(
         // Parse w/r bit in MmapProt and map it into MappingFlags
         let prot_bits = MmapProt::from_bits(prot).ok_or(LinuxError::EINVAL)?;
         let flag_bits = MmapFlags::from_bits(flags).ok_or(LinuxError::EINVAL)?;
-        if !flag_bits.contains(MmapFlags::MAP_ANONYMOUS) && fd < 0 { // In anomynous map, fd must le 0
-            return Err(LinuxError::EBADF);
+        // Exactly one of MAP_PRIVATE and MAP_SHARED must be given.
+        if flag_bits.contains(MmapFlags::MAP_PRIVATE) == flag_bits.contains(MmapFlags::MAP_SHARED) {
+            return Err(LinuxError::EINVAL);
+        }
+        if !flag_bits.contains(MmapFlags::MAP_ANONYMOUS) {
+            if fd < 0 { // In anomynous map, fd must le 0
+                return Err(LinuxError::EBADF);
+            }
+            // Fail early rather than in lseek/read below.
+            api::get_file_like(fd).map_err(|_| LinuxError::EBADF)?;
+        }
+
+        // Shared writes go back to the file, so it must be opened for writing.
+        if flag_bits.contains(MmapFlags::MAP_SHARED)
+            && prot_bits.contains(MmapProt::PROT_WRITE)
+            && !flag_bits.contains(MmapFlags::MAP_ANONYMOUS)
+        {
+            let status = current().task_ext().fd_flags.lock().get(&fd).map(|f| f.status);
+            if status.is_some_and(|status| status & 0b11 == api::ctypes::O_RDONLY) {
+                return Err(LinuxError::EACCES);
+            }
+        }
+
+        // W^X: code written through a shared file mapping could be run from
+        // it, or by another mapping of the same file.
+        if crate::DENY_SHARED_WX
+            && flag_bits.contains(MmapFlags::MAP_SHARED)
+            && prot_bits.contains(MmapProt::PROT_WRITE | MmapProt::PROT_EXEC)
+            && !flag_bits.contains(MmapFlags::MAP_ANONYMOUS)
+        {
+            return Err(LinuxError::EACCES);
         }
 
+        // A length too large to be rounded up is checked against the address
+        // space below, with the other lengths.
         let len_aligned = length
             .checked_add(PAGE_SIZE_4K - 1)
-            .ok_or(LinuxError::ENOMEM)?
-            & !(PAGE_SIZE_4K - 1);
-        if len_aligned == 0 {
-            return Err(LinuxError::EINVAL);
-        }
+            .map_or(usize::MAX, |len| len & !(PAGE_SIZE_4K - 1));
 
         let offset = _offset as usize;
         if offset % PAGE_SIZE_4K != 0 {
             return Err(LinuxError::EINVAL);
         }
 
+        // Huge pages are only for anonymous mappings, and the length must be
+        // a multiple of the huge page size.
+        let huge = flag_bits.contains(MmapFlags::MAP_HUGETLB);
+        let page_size = if huge { PAGE_SIZE_2M } else { PAGE_SIZE_4K };
+        if huge && (!flag_bits.contains(MmapFlags::MAP_ANONYMOUS) || length % PAGE_SIZE_2M != 0) {
+            return Err(LinuxError::EINVAL);
+        }
+
         let mut map_flags: MappingFlags = prot_bits.into();
         if !map_flags.contains(MappingFlags::USER) {
             map_flags |= MappingFlags::USER;
         }
-        let populate = !flag_bits.contains(MmapFlags::MAP_NORESERVE);
+        // Anonymous memory is allocated on demand unless MAP_POPULATE asks
+        // otherwise. File contents are copied in right away, that needs the
+        // frames to be there.
+        //
+        // Shared anonymous memory is populated too, so that its frames are
+        // fixed from the start. All threads live in the same address space,
+        // hence they see the same frames through it. There is no fork, so
+        // sharing with another address space is not supported.
+        let shared_anonymous = flag_bits.contains(MmapFlags::MAP_SHARED | MmapFlags::MAP_ANONYMOUS);
+        let populate = !flag_bits.contains(MmapFlags::MAP_ANONYMOUS)
+            || shared_anonymous
+            || (flag_bits.contains(MmapFlags::MAP_POPULATE)
+                && !flag_bits.contains(MmapFlags::MAP_NORESERVE));
 
         let curr = current();
         let task_ext = curr.task_ext();
         let mut aspace = task_ext.aspace.lock();
+        let mut mmap_regions = task_ext.mmap_regions();
+        if mmap_regions.regions.len() >= crate::MAX_MAP_COUNT {
+            return Err(LinuxError::ENOMEM);
+        }
 
+        // MAP_32BIT keeps the search below 4 GiB, a fixed address is taken
+        // as is.
         let base = aspace.base();
-        let limit = VirtAddrRange::from_start_size(base, aspace.size());
+        let mut end = aspace.end();
+        if flag_bits.contains(MmapFlags::MAP_32BIT) && !flag_bits.contains(MmapFlags::MAP_FIXED) {
+            end = end.min(VirtAddr::from(MAP_32BIT_END));
+            if end <= base {
+                return Err(LinuxError::ENOMEM);
+            }
+        }
+        let limit = VirtAddrRange::new(base, end);
+        // The area found in a gap between two others isn't checked against
+        // the limit, only the one after the last area is.
+        let find_free_area = |hint: VirtAddr, size: usize| {
+            aspace
+                .find_free_area(hint, size, limit)
+                .filter(|&start| {
+                    start
+                        .as_usize()
+                        .checked_add(size)
+                        .is_some_and(|area_end| area_end <= limit.end.as_usize())
+                })
+        };
         let req_addr = addr as usize;
+        if len_aligned > aspace.size() {
+            return Err(LinuxError::ENOMEM);
+        }
 
         let target = if flag_bits.contains(MmapFlags::MAP_FIXED) {
             if addr.is_null() {
                 return Err(LinuxError::EINVAL);
             }
-            if req_addr % PAGE_SIZE_4K != 0 {
+            if req_addr % page_size != 0 {
                 return Err(LinuxError::EINVAL);
             }
+            // The range must not wrap around, nor run past the address space.
             let vaddr = VirtAddr::from(req_addr);
-            if !aspace.contains_range(vaddr, len_aligned) {
-                return Err(LinuxError::EINVAL);
+            if req_addr.checked_add(len_aligned).is_none()
+                || !aspace.contains_range(vaddr, len_aligned)
+            {
+                return Err(LinuxError::ENOMEM);
             }
             vaddr
+        } else if let Some(start) = addr
+            .is_null()
+            .then(|| mmap_regions.best_fit(len_aligned, page_size))
+            .flatten()
+            // Nothing else may have been mapped in the hole since, and it
+            // must be within the limit of MAP_32BIT.
+            .filter(|&start| find_free_area(start, len_aligned) == Some(start))
+        {
+            // Without a hint, reuse a hole left by `munmap` before growing
+            // into fresh space, to keep the address space from fragmenting.
+            start
         } else {
+            // The slack needed to align the area to `page_size` must fit in
+            // the address space as well.
+            let search_len = len_aligned
+                .checked_add(page_size - PAGE_SIZE_4K)
+                .filter(|&len| len <= limit.size())
+                .ok_or(LinuxError::ENOMEM)?;
             let hint = if addr.is_null() {
                 base
             } else {
                 VirtAddr::from(req_addr & !(PAGE_SIZE_4K - 1))
             };
-            aspace
-                .find_free_area(hint, len_aligned, limit)
+            find_free_area(hint, search_len)
                 .or_else(|| {
                     if hint != base {
-                        aspace.find_free_area(base, len_aligned, limit)
+                        find_free_area(base, search_len)
                     } else {
                         None
                     }
                 })
                 .ok_or(LinuxError::ENOMEM)?
+                .align_up(page_size)
         };
 
+        if huge {
+            aspace
+                .map_alloc_huge(target, len_aligned, map_flags)
+                .map_err(ax_err_to_linux)?;
+        } else {
+            aspace
+                .map_alloc(target, len_aligned, map_flags, populate)
+                .map_err(ax_err_to_linux)?;
+        }
+
+        if !flag_bits.contains(MmapFlags::MAP_ANONYMOUS) {
+            // The area isn't recorded yet, don't leave it mapped on failure.
+            if let Err(err) = read_file_area(&mut aspace, target, len_aligned, fd, offset, length) {
+                let _ = aspace.unmap(target, len_aligned);
+                return Err(err);
+            }
+        }
+
+        let anonymous = flag_bits.contains(MmapFlags::MAP_ANONYMOUS);
+        mmap_regions.insert(MmapRegion {
+            range: VirtAddrRange::from_start_size(target, len_aligned),
+            flags: map_flags,
+            fd: if anonymous { None } else { Some(fd) },
+            offset: if anonymous { 0 } else { offset },
+            shared: flag_bits.contains(MmapFlags::MAP_SHARED),
+            huge,
+        });
+        Ok(target.as_usize())
+    })
+}
+
+fn sys_getcwd(buf: *mut c_char, size: usize) -> isize {
+    syscall_body!(sys_getcwd, {
+        if buf.is_null() {
+            return Err(LinuxError::EFAULT);
+        }
+        // The posix api reports `ERANGE` itself if `size` can't hold the path and its NUL.
+        let mut cwd = vec![0u8; size.min(PATH_MAX)];
+        syscall_ret_to_isize(api::sys_getcwd(cwd.as_mut_ptr() as _, cwd.len()) as isize)?;
+        let len = cwd.iter().position(|&c| c == 0).ok_or(LinuxError::ERANGE)?;
+        copy_bytes_to_user(buf as *mut u8, &cwd[..=len])?;
+        Ok(buf as isize)
+    })
+}
+
+fn sys_faccessat(dfd: c_int, fname: *const c_char, mode: c_int, _flags: c_int) -> isize {
+    syscall_body!(sys_faccessat, {
+        let fname = copy_path_from_user(fname)?;
+        if dfd != AT_FDCWD {
+            return Err(LinuxError::EINVAL);
+        }
+        if mode & !(R_OK | W_OK | X_OK) != 0 {
+            return Err(LinuxError::EINVAL);
+        }
+
+        let mut st = api::ctypes::stat::default();
+        syscall_ret_to_isize(unsafe { api::sys_stat(fname.as_ptr() as _, &mut st) } as isize)?;
+        if mode == F_OK {
+            return Ok(0);
+        }
+
+        // Every file is owned by the only user, so check the owner bits.
+        let perm = st.st_mode;
+        if (mode & R_OK != 0 && perm & 0o400 == 0)
+            || (mode & W_OK != 0 && perm & 0o200 == 0)
+            || (mode & X_OK != 0 && perm & 0o100 == 0)
+        {
+            return Err(LinuxError::EACCES);
+        }
+        Ok(0)
+    })
+}
+
+/// Prints how many times each unimplemented syscall has been called.
+pub fn dump_unimplemented_syscalls() {
+    let hits = UNIMPLEMENTED_SYSCALLS.lock();
+    if hits.is_empty() {
+        return;
+    }
+    ax_println!("Unimplemented syscalls:");
+    for (num, count) in hits.iter() {
+        ax_println!("  [{}] x {}", num, count);
+    }
+}
+
+/// Returns how many times the unimplemented syscall `syscall_num` has been
+/// called.
+fn unimplemented_hits(syscall_num: usize) -> usize {
+    UNIMPLEMENTED_SYSCALLS.lock().get(&syscall_num).copied().unwrap_or(0)
+}
+
+/// Prints the mmap regions of the current task, like `/proc/self/maps`.
+pub fn dump_maps() {
+    let curr = current();
+    for region in curr.task_ext().mmap_regions().regions.iter() {
+        ax_println!("{}", region);
+    }
+}
+
+/// Prints the mmap regions with [`dump_maps`] if `buf` is NULL, otherwise
+/// copies them to `buf`, one line each, truncated to `size` bytes.
+///
+/// Returns the number of bytes copied.
+fn sys_dump_maps(buf: *mut u8, size: usize) -> isize {
+    syscall_body!(sys_dump_maps, {
+        if buf.is_null() {
+            dump_maps();
+            return Ok(0);
+        }
+        let mut maps = String::new();
+        for region in current().task_ext().mmap_regions().regions.iter() {
+            writeln!(maps, "{}", region).map_err(|_| LinuxError::ENOMEM)?;
+        }
+        let len = maps.len().min(size);
+        copy_bytes_to_user(buf, &maps.as_bytes()[..len])?;
+        Ok(len as isize)
+    })
+}
+
+fn sys_munmap(addr: *mut usize, length: usize) -> isize {
+    syscall_body!(sys_munmap, {
+        let start = addr as usize;
+        if start % PAGE_SIZE_4K != 0 || length == 0 {
+            return Err(LinuxError::EINVAL);
+        }
+        let len_aligned = length
+            .checked_add(PAGE_SIZE_4K - 1)
+            .ok_or(LinuxError::EINVAL)?
+            & !(PAGE_SIZE_4K - 1);
+        start.checked_add(len_aligned).ok_or(LinuxError::EINVAL)?;
+
+        let curr = current();
+        let task_ext = curr.task_ext();
+        let mut aspace = task_ext.aspace.lock();
+        let range = VirtAddrRange::from_start_size(VirtAddr::from(start), len_aligned);
+        let mut mmap_regions = task_ext.mmap_regions();
+        if mmap_regions.splits_huge_page(range) {
+            return Err(LinuxError::EINVAL);
+        }
         aspace
-            .map_alloc(target, len_aligned, map_flags, populate)
+            .unmap(range.start, len_aligned)
             .map_err(ax_err_to_linux)?;
 
-        if !flag_bits.contains(MmapFlags::MAP_ANONYMOUS) {
-            let saved = syscall_ret_to_isize(api::sys_lseek(fd, 0, SEEK_CUR) as isize)?;
-            syscall_ret_to_isize(api::sys_lseek(fd, offset as _, SEEK_SET) as isize)?;
-
-            let mut buf: Vec<u8> = Vec::with_capacity(length);
-            buf.resize(length, 0);
-            let read_len = syscall_ret_to_isize(
-                api::sys_read(fd, buf.as_mut_ptr() as *mut c_void, length) as isize,
-            )? as usize;
-
-            if read_len > 0 {
-                aspace
-                    .write(target, &buf[..read_len])
-                    .map_err(ax_err_to_linux)?;
+        mmap_regions.remove(range);
+        Ok(0)
+    })
+}
+
+fn sys_mprotect(addr: *mut usize, length: usize, prot: i32) -> isize {
+    syscall_body!(sys_mprotect, {
+        let start = addr as usize;
+        if start % PAGE_SIZE_4K != 0 {
+            return Err(LinuxError::EINVAL);
+        }
+        let prot_bits = MmapProt::from_bits(prot).ok_or(LinuxError::EINVAL)?;
+        if length == 0 {
+            return Ok(0);
+        }
+        let len_aligned = length
+            .checked_add(PAGE_SIZE_4K - 1)
+            .ok_or(LinuxError::ENOMEM)?
+            & !(PAGE_SIZE_4K - 1);
+        start.checked_add(len_aligned).ok_or(LinuxError::ENOMEM)?;
+
+        let curr = current();
+        let task_ext = curr.task_ext();
+        let mut aspace = task_ext.aspace.lock();
+        let range = VirtAddrRange::from_start_size(VirtAddr::from(start), len_aligned);
+        if !aspace.contains_range(range.start, len_aligned)
+            || !aspace.is_range_mapped(range.start, len_aligned)
+        {
+            return Err(LinuxError::ENOMEM);
+        }
+
+        let mut mmap_regions = task_ext.mmap_regions();
+        if mmap_regions.splits_huge_page(range) {
+            return Err(LinuxError::EINVAL);
+        }
+        // Huge pages keep some access, the axmm backend refuses to take it all.
+        if prot_bits.is_empty()
+            && mmap_regions
+                .regions
+                .iter()
+                .any(|region| region.huge && region.range.overlaps(range))
+        {
+            return Err(LinuxError::EINVAL);
+        }
+        // Same as in `mmap`, shared writes need a file opened for writing.
+        if prot_bits.contains(MmapProt::PROT_WRITE) {
+            let fd_flags = task_ext.fd_flags.lock();
+            let read_only = mmap_regions.regions.iter().any(|region| {
+                region.shared
+                    && region.range.overlaps(range)
+                    && region.fd.and_then(|fd| fd_flags.get(&fd)).is_some_and(|f| {
+                        f.status & 0b11 == api::ctypes::O_RDONLY
+                    })
+            });
+            if read_only {
+                return Err(LinuxError::EACCES);
             }
+        }
+
+        let map_flags: MappingFlags = prot_bits.into();
+        aspace
+            .protect(range.start, len_aligned, map_flags)
+            .map_err(ax_err_to_linux)?;
+        mmap_regions.protect(range, map_flags);
+        Ok(0)
+    })
+}
 
-            syscall_ret_to_isize(api::sys_lseek(fd, saved as _, SEEK_SET) as isize)?;
+/// Resizes a mapping in place.
+///
+/// Shrinking unmaps the tail pages and always keeps the base address.
+/// Growing only works if the pages right after the mapping are free, and for
+/// private anonymous mappings. Moving the mapping (`MREMAP_MAYMOVE`) is not
+/// supported, so the flag is accepted but the mapping is never moved.
+fn sys_mremap(old_addr: *mut usize, old_size: usize, new_size: usize, flags: c_int) -> isize {
+    syscall_body!(sys_mremap, {
+        let start = old_addr as usize;
+        if start % PAGE_SIZE_4K != 0 || flags & !(MREMAP_MAYMOVE | MREMAP_FIXED) != 0 {
+            return Err(LinuxError::EINVAL);
         }
+        if flags & MREMAP_FIXED != 0 || new_size == 0 {
+            return Err(LinuxError::EINVAL);
+        }
+        let align = |size: usize| {
+            size.checked_add(PAGE_SIZE_4K - 1)
+                .map(|size| size & !(PAGE_SIZE_4K - 1))
+                .ok_or(LinuxError::EINVAL)
+        };
+        let old_size = align(old_size)?;
+        let new_size = align(new_size)?;
+        let old_end = start.checked_add(old_size).ok_or(LinuxError::EINVAL)?;
+        let new_end = start.checked_add(new_size).ok_or(LinuxError::ENOMEM)?;
 
-        Ok(target.as_usize())
+        let curr = current();
+        let task_ext = curr.task_ext();
+        let mut aspace = task_ext.aspace.lock();
+        let mut mmap_regions = task_ext.mmap_regions();
+        let old_range = VirtAddrRange::new(VirtAddr::from(start), VirtAddr::from(old_end));
+        let region = mmap_regions.find(old_range).ok_or(LinuxError::EFAULT)?;
+        // Huge page regions can't be split, nor grown with small pages.
+        if region.huge && (new_size > old_size || (start | new_size) % PAGE_SIZE_2M != 0) {
+            return Err(LinuxError::EINVAL);
+        }
+
+        if new_size < old_size {
+            let tail = VirtAddrRange::new(VirtAddr::from(new_end), VirtAddr::from(old_end));
+            aspace
+                .unmap(tail.start, tail.size())
+                .map_err(ax_err_to_linux)?;
+            mmap_regions.remove(tail);
+        } else if new_size > old_size {
+            let grow = new_size - old_size;
+            let old_end = VirtAddr::from(old_end);
+            let limit = VirtAddrRange::from_start_size(aspace.base(), aspace.size());
+            if old_end != region.range.end
+                || region.fd.is_some()
+                || region.shared
+                || aspace.find_free_area(old_end, grow, limit) != Some(old_end)
+            {
+                return Err(LinuxError::ENOMEM);
+            }
+            let region_start = region.range.start;
+            aspace
+                .map_alloc(old_end, grow, region.flags, false)
+                .map_err(ax_err_to_linux)?;
+            mmap_regions.grow(region_start, VirtAddr::from(new_end));
+        }
+        Ok(start)
+    })
+}
+
+/// Creates a thread sharing the address space of the caller.
+///
+/// Only thread creation (`CLONE_VM`) is supported, the argument order is the
+/// one of the generic syscall ABI: `flags, child_stack, ptid, tls, ctid`.
+/// Other architectures get `ENOSYS`, from the dispatch table.
+#[cfg(target_arch = "riscv64")]
+fn sys_clone(
+    tf: &TrapFrame,
+    flags: usize,
+    child_stack: usize,
+    ptid: *mut i32,
+    tls: usize,
+    ctid: *mut i32,
+) -> isize {
+    syscall_body!(sys_clone, {
+        // The low byte is the signal sent to the parent on exit, which we ignore.
+        let flags = CloneFlags::from_bits(flags & !0xff).ok_or(LinuxError::EINVAL)?;
+        if !flags.contains(CloneFlags::CLONE_VM) {
+            return Err(LinuxError::EINVAL);
+        }
+        if child_stack == 0 {
+            return Err(LinuxError::EINVAL);
+        }
+
+        // The thread joins the group once created, so nothing may fail after.
+        let tid_size = core::mem::size_of::<i32>();
+        if flags.contains(CloneFlags::CLONE_PARENT_SETTID) {
+            check_user_range(ptid as usize, tid_size, MappingFlags::WRITE)?;
+        }
+        if flags.contains(CloneFlags::CLONE_CHILD_SETTID) {
+            check_user_range(ctid as usize, tid_size, MappingFlags::WRITE)?;
+        }
+
+        let tls = flags.contains(CloneFlags::CLONE_SETTLS).then_some(tls);
+        let uctx = clone_child_context(tf, child_stack, tls);
+
+        let curr = current();
+        let task_ext = crate::task::TaskExt::new_thread(uctx, curr.task_ext());
+        if flags.contains(CloneFlags::CLONE_CHILD_CLEARTID) {
+            task_ext.set_clear_child_tid(ctid as _);
+        }
+        let task = crate::task::new_user_task("userthread", task_ext);
+        let tid = task.id().as_u64() as i32;
+
+        // Both pointers were checked above.
+        if flags.contains(CloneFlags::CLONE_PARENT_SETTID) {
+            let _ = copy_to_user(ptid, &tid);
+        }
+        if flags.contains(CloneFlags::CLONE_CHILD_SETTID) {
+            let _ = copy_to_user(ctid, &tid);
+        }
+        axtask::spawn_task(task);
+        Ok(tid as isize)
+    })
+}
+
+/// Builds the user context of a thread created by `clone`, which returns 0
+/// from the syscall on `child_stack`, with the thread pointer `tls` if set.
+#[cfg(target_arch = "riscv64")]
+fn clone_child_context(tf: &TrapFrame, child_stack: usize, tls: Option<usize>) -> UspaceContext {
+    let mut child_tf = *tf;
+    if let Some(tls) = tls {
+        child_tf.regs.tp = tls;
+    }
+    let mut uctx = UspaceContext::from(&child_tf);
+    // `sepc` is only moved past the `ecall` once the syscall returns.
+    uctx.set_ip(tf.sepc + 4);
+    uctx.set_sp(child_stack);
+    uctx.set_retval(0);
+    uctx
+}
+
+/// Only `FUTEX_WAIT` (without timeout) and `FUTEX_WAKE` are supported.
+fn sys_futex(uaddr: *const u32, op: c_int, val: u32) -> isize {
+    syscall_body!(sys_futex, {
+        if uaddr as usize % core::mem::align_of::<u32>() != 0 {
+            return Err(LinuxError::EINVAL);
+        }
+        check_user_range(uaddr as usize, core::mem::size_of::<u32>(), MappingFlags::READ)?;
+        match op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME) {
+            FUTEX_WAIT => {
+                let curr = current();
+                let group = &curr.task_ext().group;
+                futex::futex_wait(uaddr, val, || group.is_exiting())
+            }
+            FUTEX_WAKE => Ok(futex::futex_wake(uaddr, val as usize)),
+            _ => Err(LinuxError::ENOSYS),
+        }
+    })
+}
+
+/// Size of `struct robust_list_head`.
+const ROBUST_LIST_HEAD_SIZE: usize = 3 * core::mem::size_of::<usize>();
+
+/// Only records the list, robust futexes are not released when a thread
+/// dies.
+fn sys_set_robust_list(head: usize, len: usize) -> isize {
+    syscall_body!(sys_set_robust_list, {
+        if len != ROBUST_LIST_HEAD_SIZE {
+            return Err(LinuxError::EINVAL);
+        }
+        current().task_ext().set_robust_list(head, len);
+        Ok(0)
+    })
+}
+
+/// Only the list of the calling thread can be read, `pid` must be 0 or its
+/// own ID.
+fn sys_get_robust_list(pid: c_int, head_ptr: *mut usize, len_ptr: *mut usize) -> isize {
+    syscall_body!(sys_get_robust_list, {
+        let curr = current();
+        // Only the calling process is known, by the ID `getpid` returns.
+        if pid != 0 && pid as usize != curr.task_ext().proc_id {
+            return Err(LinuxError::ESRCH);
+        }
+        let (head, len) = curr.task_ext().robust_list();
+        copy_to_user(head_ptr, &head)?;
+        copy_to_user(len_ptr, &len)?;
+        Ok(0)
+    })
+}
+
+/// Memory is never swapped out, so locking only checks that the range is mapped.
+fn check_lock_range(addr: usize, length: usize) -> Result<(), LinuxError> {
+    let start = addr & !(PAGE_SIZE_4K - 1);
+    let end = addr
+        .checked_add(length)
+        .and_then(|end| end.checked_add(PAGE_SIZE_4K - 1))
+        .ok_or(LinuxError::ENOMEM)?
+        & !(PAGE_SIZE_4K - 1);
+    let curr = current();
+    let aspace = curr.task_ext().aspace.lock();
+    if !aspace.is_range_mapped(VirtAddr::from(start), end - start) {
+        return Err(LinuxError::ENOMEM);
+    }
+    Ok(())
+}
+
+fn sys_mlock(addr: usize, length: usize) -> isize {
+    syscall_body!(sys_mlock, {
+        check_lock_range(addr, length)?;
+        Ok(0)
+    })
+}
+
+fn sys_munlock(addr: usize, length: usize) -> isize {
+    syscall_body!(sys_munlock, {
+        check_lock_range(addr, length)?;
+        Ok(0)
+    })
+}
+
+fn sys_mlockall(flags: c_int) -> isize {
+    syscall_body!(sys_mlockall, {
+        let valid = MCL_CURRENT | MCL_FUTURE | MCL_ONFAULT;
+        if flags == 0 || flags & !valid != 0 || flags == MCL_ONFAULT {
+            return Err(LinuxError::EINVAL);
+        }
+        Ok(0)
+    })
+}
+
+/// The `membarrier` commands supported, as returned by
+/// `MEMBARRIER_CMD_QUERY`.
+const MEMBARRIER_SUPPORTED: c_int = MEMBARRIER_CMD_GLOBAL
+    | MEMBARRIER_CMD_GLOBAL_EXPEDITED
+    | MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED
+    | MEMBARRIER_CMD_PRIVATE_EXPEDITED
+    | MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED;
+
+/// The barriers are a full memory fence on the calling CPU. The private
+/// expedited one needs the process to have registered for it first.
+fn sys_membarrier(cmd: c_int, flags: u32) -> isize {
+    syscall_body!(sys_membarrier, {
+        if flags != 0 {
+            return Err(LinuxError::EINVAL);
+        }
+        let curr = current();
+        let group = &curr.task_ext().group;
+        match cmd {
+            MEMBARRIER_CMD_QUERY => return Ok(MEMBARRIER_SUPPORTED as isize),
+            MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED | MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED => {
+                group.register_membarrier(cmd as u32);
+                return Ok(0);
+            }
+            MEMBARRIER_CMD_PRIVATE_EXPEDITED => {
+                if !group.membarrier_registered(MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED as u32) {
+                    return Err(LinuxError::EPERM);
+                }
+            }
+            // The global expedited barrier runs on the registered processes,
+            // the caller needn't be one of them.
+            MEMBARRIER_CMD_GLOBAL | MEMBARRIER_CMD_GLOBAL_EXPEDITED => {}
+            _ => return Err(LinuxError::EINVAL),
+        }
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        Ok(0)
+    })
+}
+
+/// Timestamp in `struct statx`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct StatxTimestamp {
+    tv_sec: i64,
+    tv_nsec: u32,
+    __reserved: i32,
+}
+
+/// File status returned by `statx`, as `struct statx`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Statx {
+    stx_mask: u32,
+    stx_blksize: u32,
+    stx_attributes: u64,
+    stx_nlink: u32,
+    stx_uid: u32,
+    stx_gid: u32,
+    stx_mode: u16,
+    __spare0: u16,
+    stx_ino: u64,
+    stx_size: u64,
+    stx_blocks: u64,
+    stx_attributes_mask: u64,
+    stx_atime: StatxTimestamp,
+    stx_btime: StatxTimestamp,
+    stx_ctime: StatxTimestamp,
+    stx_mtime: StatxTimestamp,
+    stx_rdev_major: u32,
+    stx_rdev_minor: u32,
+    stx_dev_major: u32,
+    stx_dev_minor: u32,
+    __spare2: [u64; 14],
+}
+
+fn sys_statx(dfd: c_int, fname: *const c_char, flags: c_int, mask: u32, statxbuf: *mut Statx) -> isize {
+    syscall_body!(sys_statx, {
+        if mask & STATX_RESERVED != 0 {
+            return Err(LinuxError::EINVAL);
+        }
+
+        let mut st = api::ctypes::stat::default();
+        let path = if fname.is_null() { vec![0] } else { copy_path_from_user(fname)? };
+        if path[0] == 0 && flags & AT_EMPTY_PATH != 0 {
+            syscall_ret_to_isize(unsafe { api::sys_fstat(dfd, &mut st) } as isize)?;
+        } else {
+            if fname.is_null() {
+                return Err(LinuxError::EFAULT);
+            }
+            // Relative paths are only supported against the current directory.
+            if dfd != AT_FDCWD && path[0] != b'/' {
+                return Err(LinuxError::EINVAL);
+            }
+            syscall_ret_to_isize(unsafe { api::sys_stat(path.as_ptr() as _, &mut st) } as isize)?;
+        }
+
+        // Basic stats are always available, whatever is asked in `mask`.
+        let stx = Statx {
+            stx_mask: STATX_TYPE | STATX_MODE | STATX_NLINK | STATX_UID | STATX_GID
+                | STATX_INO | STATX_SIZE | STATX_BLOCKS,
+            stx_blksize: st.st_blksize as _,
+            stx_nlink: st.st_nlink as _,
+            stx_uid: st.st_uid as _,
+            stx_gid: st.st_gid as _,
+            stx_mode: st.st_mode as _,
+            stx_ino: st.st_ino as _,
+            stx_size: st.st_size as _,
+            stx_blocks: st.st_blocks as _,
+            ..Default::default()
+        };
+        copy_to_user(statxbuf, &stx)?;
+        Ok(0)
     })
 }
 
 fn sys_openat(dfd: c_int, fname: *const c_char, flags: c_int, mode: api::ctypes::mode_t) -> isize {
-    assert_eq!(dfd, AT_FDCWD);
-    api::sys_open(fname, flags, mode) as isize
+    syscall_body!(sys_openat, {
+        let fname = copy_path_from_user(fname)?;
+        // Relative paths are only supported against the current directory.
+        if dfd != AT_FDCWD && fname[0] != b'/' {
+            api::get_file_like(dfd).map_err(|_| LinuxError::EBADF)?;
+            return Err(LinuxError::EOPNOTSUPP);
+        }
+
+        // The file gets the lowest free fd, which must be below the limit.
+        lowest_free_fd(0)?;
+        // O_APPEND is handled by axfs, which seeks to EOF before each write.
+        // `mode` is passed on for O_CREAT, but the file systems here keep no
+        // per-file permissions, so it has no effect on the new file.
+        let fd = syscall_ret_to_isize(api::sys_open(fname.as_ptr() as _, flags, mode) as isize)? as c_int;
+
+        // Creation flags are not part of the file status flags.
+        let creation = api::ctypes::O_CREAT
+            | api::ctypes::O_EXCL
+            | api::ctypes::O_NOCTTY
+            | api::ctypes::O_TRUNC
+            | api::ctypes::O_CLOEXEC;
+        let status = flags as u32 & !creation;
+        let cloexec = flags as u32 & api::ctypes::O_CLOEXEC != 0;
+        current()
+            .task_ext()
+            .fd_flags
+            .lock()
+            .insert(fd, FdFlags { status, cloexec });
+        Ok(fd as isize)
+    })
+}
+
+/// Only `/proc/self/exe` is a symbolic link, the file systems have none.
+///
+/// The target is truncated to `bufsiz` bytes if it is longer, and it is not
+/// NUL-terminated.
+fn sys_readlinkat(dfd: c_int, path: *const c_char, buf: *mut u8, bufsiz: isize) -> isize {
+    syscall_body!(sys_readlinkat, {
+        if path.is_null() {
+            return Err(LinuxError::EFAULT);
+        }
+        if bufsiz <= 0 {
+            return Err(LinuxError::EINVAL);
+        }
+        let name = copy_path_from_user(path)?;
+        if name[..name.len() - 1] == *PROC_SELF_EXE {
+            let curr = current();
+            let exe = curr.task_ext().exe_path.as_bytes();
+            let len = exe.len().min(bufsiz as usize);
+            copy_bytes_to_user(buf, &exe[..len])?;
+            return Ok(len as isize);
+        }
+
+        // Relative paths are only supported against the current directory.
+        if dfd != AT_FDCWD && name.first() != Some(&b'/') {
+            return Err(LinuxError::EINVAL);
+        }
+        // Any file which exists is not a link.
+        let mut st = api::ctypes::stat::default();
+        syscall_ret_to_isize(unsafe { api::sys_stat(name.as_ptr() as _, &mut st) } as isize)?;
+        Err(LinuxError::EINVAL)
+    })
+}
+
+/// Returns the number of fds the current process may have open, the
+/// `RLIMIT_NOFILE` soft limit capped by the fd table.
+fn nofile_limit() -> c_int {
+    let nofile = current().task_ext().rlimits.lock()[RLIMIT_NOFILE].cur;
+    nofile.min(FD_LIMIT as u64) as c_int
+}
+
+/// Returns the lowest free fd from `min_fd`, `EMFILE` if there is none below
+/// [`nofile_limit`].
+fn lowest_free_fd(min_fd: c_int) -> Result<c_int, LinuxError> {
+    (min_fd..nofile_limit())
+        .find(|&n| api::get_file_like(n).is_err())
+        .ok_or(LinuxError::EMFILE)
 }
 
 fn sys_close(fd: i32) -> isize {
-    api::sys_close(fd) as isize
+    let ret = api::sys_close(fd);
+    if ret == 0 {
+        current().task_ext().fd_flags.lock().remove(&fd);
+    }
+    ret as isize
+}
+
+fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> isize {
+    syscall_body!(sys_fcntl, {
+        api::get_file_like(fd)?;
+        let curr = current();
+        let mut fd_flags = curr.task_ext().fd_flags.lock();
+        let flags = fd_flags.get(&fd).copied().unwrap_or(FdFlags {
+            status: api::ctypes::O_RDWR,
+            cloexec: false,
+        });
+
+        match cmd as u32 {
+            api::ctypes::F_DUPFD | api::ctypes::F_DUPFD_CLOEXEC => {
+                let min_fd = arg as c_int;
+                if !(0..nofile_limit()).contains(&min_fd) {
+                    return Err(LinuxError::EINVAL);
+                }
+                let new_fd = lowest_free_fd(min_fd)?;
+                syscall_ret_to_isize(api::sys_dup2(fd, new_fd) as isize)?;
+                let cloexec = cmd as u32 == api::ctypes::F_DUPFD_CLOEXEC;
+                fd_flags.insert(new_fd, FdFlags { cloexec, ..flags });
+                Ok(new_fd as isize)
+            }
+            api::ctypes::F_GETFD => Ok(if flags.cloexec { api::ctypes::FD_CLOEXEC as isize } else { 0 }),
+            api::ctypes::F_SETFD => {
+                let cloexec = arg & api::ctypes::FD_CLOEXEC as usize != 0;
+                fd_flags.insert(fd, FdFlags { cloexec, ..flags });
+                Ok(0)
+            }
+            api::ctypes::F_GETFL => Ok(flags.status as isize),
+            api::ctypes::F_SETFL => {
+                // Only O_APPEND and O_NONBLOCK can be changed, the access mode is kept.
+                let settable = api::ctypes::O_APPEND | api::ctypes::O_NONBLOCK;
+                syscall_ret_to_isize(
+                    api::sys_fcntl(fd, api::ctypes::F_SETFL as _, arg) as isize,
+                )?;
+                let status = (flags.status & !settable) | (arg as u32 & settable);
+                fd_flags.insert(fd, FdFlags { status, ..flags });
+                Ok(0)
+            }
+            _ => syscall_ret_to_isize(api::sys_fcntl(fd, cmd, arg) as isize),
+        }
+    })
 }
 
 fn sys_read(fd: i32, buf: *mut c_void, count: usize) -> isize {
-    api::sys_read(fd, buf, count)
+    syscall_body!(sys_read, {
+        // The posix api fills the buffer in place, it only has to be there.
+        check_user_range(buf as usize, count, MappingFlags::WRITE)?;
+        syscall_ret_to_isize(api::sys_read(fd, buf, count))
+    })
 }
 
 fn sys_write(fd: i32, buf: *const c_void, count: usize) -> isize {
-    api::sys_write(fd, buf, count)
+    syscall_body!(sys_write, {
+        check_user_range(buf as usize, count, MappingFlags::READ)?;
+        syscall_ret_to_isize(api::sys_write(fd, buf, count))
+    })
 }
 
+/// Writes the buffers in order, stops at the first failed or short write.
+///
+/// Returns the number of bytes written so far, or the error if nothing has
+/// been written.
 fn sys_writev(fd: i32, iov: *const api::ctypes::iovec, iocnt: i32) -> isize {
-    unsafe { api::sys_writev(fd, iov, iocnt) }
+    syscall_body!(sys_writev, {
+        if !(0..=IOV_MAX).contains(&iocnt) {
+            return Err(LinuxError::EINVAL);
+        }
+
+        let mut written = 0;
+        for i in 0..iocnt as usize {
+            let res = copy_from_user(iov.wrapping_add(i)).and_then(|v| {
+                if v.iov_len == 0 {
+                    return Ok((0, 0));
+                }
+                check_user_range(v.iov_base as usize, v.iov_len, MappingFlags::READ)?;
+                let n = syscall_ret_to_isize(api::sys_write(fd, v.iov_base, v.iov_len))?;
+                Ok((n as usize, v.iov_len))
+            });
+            match res {
+                Ok((n, len)) => {
+                    written += n;
+                    if n < len {
+                        break;
+                    }
+                }
+                Err(e) if written == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(written)
+    })
+}
+
+pub(crate) fn sys_exit(exit_code: i32) -> ! {
+    // Release all user mappings (and their frames) before the task goes away.
+    // We are running on the kernel stack, so the kernel part of the page
+    // table is all we need from here on.
+    let curr = current();
+    let task_ext = curr.task_ext();
+    let clear_child_tid = task_ext.clear_child_tid() as *mut i32;
+    if !clear_child_tid.is_null() {
+        // The thread may have unmapped it, nothing to clear then.
+        let _ = copy_to_user(clear_child_tid, &0);
+        futex::futex_wake(clear_child_tid as _, 1);
+    }
+    // Other threads may still run in the address space.
+    if task_ext.group.thread_exit() {
+        dump_unimplemented_syscalls();
+        task_ext.aspace.lock().clear();
+        task_ext.mmap_regions().clear();
+    }
+    axtask::exit(exit_code)
+}
+
+pub(crate) fn sys_exit_group(exit_code: i32) -> ! {
+    current().task_ext().group.set_exiting(exit_code);
+    // Threads blocked in futex wait have to come back to notice it.
+    futex::futex_wake_all();
+    sys_exit(exit_code)
 }
 
 fn sys_set_tid_address(tid_ptd: *const i32) -> isize {
@@ -271,9 +1351,249 @@ fn sys_set_tid_address(tid_ptd: *const i32) -> isize {
     curr.id().as_u64() as isize
 }
 
-fn sys_ioctl(_fd: i32, _op: usize, _argp: *mut c_void) -> i32 {
-    ax_println!("Ignore SYS_IOCTL");
-    0
+fn sys_rt_sigaction(
+    signum: usize,
+    act: *const SigAction,
+    oldact: *mut SigAction,
+    sigsetsize: usize,
+) -> isize {
+    syscall_body!(sys_rt_sigaction, {
+        if sigsetsize != core::mem::size_of::<u64>() {
+            return Err(LinuxError::EINVAL);
+        }
+        if !(1..=NSIG).contains(&signum) {
+            return Err(LinuxError::EINVAL);
+        }
+        if !act.is_null() && (signum == SIGKILL || signum == SIGSTOP) {
+            return Err(LinuxError::EINVAL);
+        }
+
+        // Read the new action first, `act` and `oldact` may be the same.
+        let new_act = if act.is_null() { None } else { Some(copy_from_user(act)?) };
+
+        // Signals are not delivered yet, we only keep the actions for later use.
+        let curr = current();
+        let mut sigactions = curr.task_ext().sigactions.lock();
+        let slot = &mut sigactions[signum - 1];
+        if !oldact.is_null() {
+            copy_to_user(oldact, slot)?;
+        }
+        if let Some(new_act) = new_act {
+            *slot = new_act;
+        }
+        Ok(0)
+    })
+}
+
+fn sys_rt_sigprocmask(how: c_int, set: *const u64, oldset: *mut u64, sigsetsize: usize) -> isize {
+    syscall_body!(sys_rt_sigprocmask, {
+        if sigsetsize != core::mem::size_of::<u64>() {
+            return Err(LinuxError::EINVAL);
+        }
+
+        let curr = current();
+        let task_ext = curr.task_ext();
+        let old = task_ext.sigmask();
+        if !set.is_null() {
+            let set = copy_from_user(set)?;
+            let new = match how {
+                SIG_BLOCK => old | set,
+                SIG_UNBLOCK => old & !set,
+                SIG_SETMASK => set,
+                _ => return Err(LinuxError::EINVAL),
+            };
+            // SIGKILL and SIGSTOP can't be blocked, silently drop them.
+            let unblockable = (1 << (SIGKILL - 1)) | (1 << (SIGSTOP - 1));
+            task_ext.set_sigmask(new & !unblockable);
+        }
+        if !oldset.is_null() {
+            copy_to_user(oldset, &old)?;
+        }
+        Ok(0)
+    })
+}
+
+/// System statistics, as `struct sysinfo` on 64-bit targets.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct SysInfo {
+    uptime: i64,
+    loads: [u64; 3],
+    totalram: u64,
+    freeram: u64,
+    sharedram: u64,
+    bufferram: u64,
+    totalswap: u64,
+    freeswap: u64,
+    procs: u16,
+    pad: u16,
+    totalhigh: u64,
+    freehigh: u64,
+    mem_unit: u32,
+}
+
+/// Reports the memory of the global allocator, in bytes. Free memory is
+/// the free pages plus the free space of the byte allocator, which lives in
+/// pages already counted as used.
+fn sys_sysinfo(info: *mut SysInfo) -> isize {
+    syscall_body!(sys_sysinfo, {
+        let allocator = axalloc::global_allocator();
+        let total_pages = allocator.used_pages() + allocator.available_pages();
+        let totalram = (total_pages * PAGE_SIZE_4K) as u64;
+        let freeram = (allocator.available_pages() * PAGE_SIZE_4K + allocator.available_bytes()) as u64;
+        let si = SysInfo {
+            uptime: axhal::time::monotonic_time().as_secs() as i64,
+            totalram,
+            freeram: freeram.min(totalram),
+            procs: 1,
+            mem_unit: 1,
+            ..Default::default()
+        };
+        copy_to_user(info, &si)?;
+        Ok(0)
+    })
+}
+
+/// Only the thread name can be set and read, with `PR_SET_NAME` and
+/// `PR_GET_NAME`. Longer names are truncated to 15 bytes.
+fn sys_prctl(option: c_int, arg2: usize) -> isize {
+    syscall_body!(sys_prctl, {
+        let curr = current();
+        match option {
+            PR_SET_NAME => {
+                let mut comm = [0; TASK_COMM_LEN];
+                for (i, c) in comm[..TASK_COMM_LEN - 1].iter_mut().enumerate() {
+                    *c = copy_from_user((arg2 as *const u8).wrapping_add(i))?;
+                    if *c == 0 {
+                        break;
+                    }
+                }
+                curr.task_ext().set_comm(comm);
+                Ok(0)
+            }
+            PR_GET_NAME => {
+                copy_to_user(arg2 as *mut [u8; TASK_COMM_LEN], &curr.task_ext().comm())?;
+                Ok(0)
+            }
+            _ => Err(LinuxError::EINVAL),
+        }
+    })
+}
+
+/// Gets and sets the resource limits of the calling process, `getrlimit`
+/// and `setrlimit` are the same with a `pid` of 0.
+///
+/// The limits are kept for all resources, only `RLIMIT_NOFILE` is enforced,
+/// at `openat` and `F_DUPFD`. There is nobody else to protect against, so
+/// raising a hard limit is allowed, but `RLIMIT_NOFILE` can't go past the fd
+/// table.
+fn sys_prlimit64(pid: c_int, resource: u32, new_limit: *const RLimit, old_limit: *mut RLimit) -> isize {
+    syscall_body!(sys_prlimit64, {
+        let curr = current();
+        let task_ext = curr.task_ext();
+        if pid != 0 && pid as usize != task_ext.proc_id {
+            return Err(LinuxError::ESRCH);
+        }
+        let resource = resource as usize;
+        if resource >= RLIM_NLIMITS {
+            return Err(LinuxError::EINVAL);
+        }
+        let new = if new_limit.is_null() { None } else { Some(copy_from_user(new_limit)?) };
+        if let Some(new) = new {
+            if new.cur > new.max {
+                return Err(LinuxError::EINVAL);
+            }
+            if resource == RLIMIT_NOFILE && new.max > FD_LIMIT as u64 {
+                return Err(LinuxError::EPERM);
+            }
+        }
+
+        let mut rlimits = task_ext.rlimits.lock();
+        if !old_limit.is_null() {
+            copy_to_user(old_limit, &rlimits[resource])?;
+        }
+        if let Some(new) = new {
+            rlimits[resource] = new;
+        }
+        Ok(0)
+    })
+}
+
+/// Process times, as `struct tms`, in clock ticks.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Tms {
+    tms_utime: i64,
+    tms_stime: i64,
+    tms_cutime: i64,
+    tms_cstime: i64,
+}
+
+/// Reports the times of the calling thread, see [`TaskExt::cpu_times`](crate::task::TaskExt::cpu_times).
+/// Exited children are not accounted. Returns the clock ticks since boot.
+fn sys_times(buf: *mut Tms) -> isize {
+    syscall_body!(sys_times, {
+        let to_ticks = |nanos: u64| (nanos / (axhal::time::NANOS_PER_SEC / CLOCK_TICKS_PER_SEC)) as i64;
+        if !buf.is_null() {
+            let curr = current();
+            let (user, sys) = curr.task_ext().cpu_times(&curr);
+            let tms = Tms {
+                tms_utime: to_ticks(user),
+                tms_stime: to_ticks(sys),
+                ..Default::default()
+            };
+            copy_to_user(buf, &tms)?;
+        }
+        Ok(to_ticks(axhal::time::monotonic_time_nanos()) as isize)
+    })
+}
+
+/// Terminal window size, as `struct winsize`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct WinSize {
+    ws_row: u16,
+    ws_col: u16,
+    ws_xpixel: u16,
+    ws_ypixel: u16,
+}
+
+fn sys_ioctl(fd: i32, op: usize, argp: *mut c_void) -> isize {
+    syscall_body!(sys_ioctl, {
+        api::get_file_like(fd)?;
+        // Only stdin/stdout/stderr are backed by the console.
+        let is_tty = (0..=2).contains(&fd);
+        match op {
+            TIOCGWINSZ if is_tty => {
+                let ws = WinSize {
+                    ws_row: 24,
+                    ws_col: 80,
+                    ws_xpixel: 0,
+                    ws_ypixel: 0,
+                };
+                copy_to_user(argp as *mut WinSize, &ws)?;
+                Ok(0)
+            }
+            FIONBIO => {
+                let nonblocking = copy_from_user(argp as *const c_int)? != 0;
+                let old = syscall_ret_to_isize(
+                    sys_fcntl(fd, api::ctypes::F_GETFL as _, 0),
+                )? as usize;
+                let new = if nonblocking {
+                    old | api::ctypes::O_NONBLOCK as usize
+                } else {
+                    old & !(api::ctypes::O_NONBLOCK as usize)
+                };
+                syscall_ret_to_isize(sys_fcntl(fd, api::ctypes::F_SETFL as _, new))?;
+                Ok(0)
+            }
+            _ if is_tty => {
+                ax_println!("Ignore SYS_IOCTL");
+                Ok(0)
+            }
+            _ => Err(LinuxError::ENOTTY),
+        }
+    })
 }
 
 fn ax_err_to_linux(err: AxError) -> LinuxError {
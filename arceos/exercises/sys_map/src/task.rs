@@ -1,37 +1,461 @@
 #![allow(dead_code)]
 
-use core::sync::atomic::AtomicU64;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
+use alloc::collections::BTreeMap;
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use axhal::arch::UspaceContext;
+use axhal::paging::MappingFlags;
 use axmm::AddrSpace;
-use axsync::Mutex;
+use memory_addr::{MemoryAddr, VirtAddr, VirtAddrRange};
+use axsync::{Mutex, MutexGuard};
 use axtask::{AxTaskRef, TaskExtRef, TaskInner};
 
 /// Task extended data for the monolithic kernel.
 pub struct TaskExt {
     /// The process ID.
     pub proc_id: usize,
+    /// The ID of the parent process, 0 for a process started by the kernel.
+    pub parent_id: usize,
+    /// The path of the executable, shown as `/proc/self/exe`.
+    pub exe_path: Arc<String>,
     /// The clear thread tid field
     ///
     /// See <https://manpages.debian.org/unstable/manpages-dev/set_tid_address.2.en.html#clear_child_tid>
     ///
     /// When the thread exits, the kernel clears the word at this address if it is not NULL.
     clear_child_tid: AtomicU64,
+    /// The head and the size of the robust futex list given to
+    /// `set_robust_list`. The list is not walked on exit.
+    robust_list: Mutex<(usize, usize)>,
     /// The user space context.
     pub uctx: UspaceContext,
     /// The virtual memory address space.
     pub aspace: Arc<Mutex<AddrSpace>>,
+    /// The thread group (process) this task belongs to.
+    pub group: Arc<ThreadGroup>,
+    /// The regions mapped by `mmap`, shared by the threads of the address space.
+    mmap_regions: Arc<Mutex<MmapRegistry>>,
+    /// Per-fd flags which are not kept by the fd table of `arceos_posix_api`.
+    pub fd_flags: Arc<Mutex<BTreeMap<i32, FdFlags>>>,
+    /// Signal actions installed by `rt_sigaction`, indexed by `signum - 1`.
+    pub sigactions: Arc<Mutex<[SigAction; NSIG]>>,
+    /// Resource limits, shared by the threads of the process.
+    pub rlimits: Arc<Mutex<[RLimit; RLIM_NLIMITS]>>,
+    /// The blocked signal mask, bit `signum - 1` stands for `signum`.
+    sigmask: AtomicU64,
+    /// Number of page faults serviced for this task.
+    page_faults: AtomicU64,
+    /// The thread name set by `prctl(PR_SET_NAME)`, NUL-padded.
+    comm: Mutex<[u8; TASK_COMM_LEN]>,
+    /// On-CPU time spent in syscalls, in nanoseconds.
+    sys_nanos: AtomicU64,
+}
+
+/// State shared by the threads of a process.
+///
+/// There is no list of the member tasks, as `axtask` can't stop another task.
+/// Instead, `exit_group` marks the group as exiting and every thread exits the
+/// next time it enters the kernel. A thread spinning in user space without
+/// syscalls keeps running until it does.
+pub struct ThreadGroup {
+    /// Number of threads which haven't exited.
+    live: AtomicUsize,
+    /// The exit code given to `exit_group`, `None` if the group isn't exiting.
+    exit_code: Mutex<Option<i32>>,
+    /// Whether `exit_code` is set, readable without locking.
+    exiting: AtomicBool,
+    /// The `membarrier` registration commands issued by the process.
+    membarrier: AtomicU32,
+}
+
+impl ThreadGroup {
+    fn new() -> Self {
+        Self {
+            live: AtomicUsize::new(1),
+            exit_code: Mutex::new(None),
+            exiting: AtomicBool::new(false),
+            membarrier: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns the exit code if `exit_group` has been called.
+    pub fn exit_code(&self) -> Option<i32> {
+        *self.exit_code.lock()
+    }
+
+    /// Marks the whole group as exiting, the first exit code wins.
+    pub fn set_exiting(&self, exit_code: i32) {
+        self.exit_code.lock().get_or_insert(exit_code);
+        self.exiting.store(true, Ordering::Release);
+    }
+
+    /// Returns whether `exit_group` has been called, without blocking.
+    pub fn is_exiting(&self) -> bool {
+        self.exiting.load(Ordering::Acquire)
+    }
+
+    /// Records the `membarrier` registration command `cmd`.
+    pub fn register_membarrier(&self, cmd: u32) {
+        self.membarrier.fetch_or(cmd, Ordering::AcqRel);
+    }
+
+    /// Returns whether the `membarrier` registration command `cmd` has been
+    /// issued.
+    pub fn membarrier_registered(&self, cmd: u32) -> bool {
+        self.membarrier.load(Ordering::Acquire) & cmd != 0
+    }
+
+    /// Records that a thread has exited, returns `true` if it was the last one.
+    pub fn thread_exit(&self) -> bool {
+        self.live.fetch_sub(1, Ordering::AcqRel) == 1
+    }
+}
+
+/// Number of resource limits.
+pub const RLIM_NLIMITS: usize = 16;
+/// No limit.
+pub const RLIM_INFINITY: u64 = u64::MAX;
+/// Maximum size of the stack, in bytes.
+pub const RLIMIT_STACK: usize = 3;
+/// One more than the highest file descriptor which can be opened.
+pub const RLIMIT_NOFILE: usize = 7;
+
+/// A resource limit, as `struct rlimit`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RLimit {
+    /// The soft limit, which is enforced.
+    pub cur: u64,
+    /// The hard limit, the ceiling of the soft one.
+    pub max: u64,
+}
+
+impl RLimit {
+    const INFINITY: Self = Self {
+        cur: RLIM_INFINITY,
+        max: RLIM_INFINITY,
+    };
+}
+
+/// Returns the limits of a new process: the size of its stack and of the
+/// fd table, the others are unlimited.
+fn default_rlimits() -> [RLimit; RLIM_NLIMITS] {
+    let mut rlimits = [RLimit::INFINITY; RLIM_NLIMITS];
+    rlimits[RLIMIT_STACK].cur = crate::USER_STACK_SIZE as u64;
+    rlimits[RLIMIT_NOFILE] = RLimit {
+        cur: crate::FD_LIMIT as u64,
+        max: crate::FD_LIMIT as u64,
+    };
+    rlimits
+}
+
+/// Size of a thread name, including its NUL.
+pub const TASK_COMM_LEN: usize = 16;
+
+/// Returns the thread name of a task running `exe_path`, its file name
+/// truncated to fit.
+fn comm_of(exe_path: &str) -> [u8; TASK_COMM_LEN] {
+    let name = exe_path.rsplit('/').next().unwrap_or(exe_path).as_bytes();
+    let mut comm = [0; TASK_COMM_LEN];
+    let len = name.len().min(TASK_COMM_LEN - 1);
+    comm[..len].copy_from_slice(&name[..len]);
+    comm
+}
+
+/// Number of signals, including the real-time ones.
+pub const NSIG: usize = 64;
+
+/// Signal action, laid out as the kernel `struct sigaction`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SigAction {
+    /// `SIG_DFL`, `SIG_IGN` or the address of the handler.
+    pub handler: usize,
+    /// `SA_*` flags.
+    pub flags: usize,
+    /// The signal return trampoline, riscv64 has no `SA_RESTORER`.
+    #[cfg(not(target_arch = "riscv64"))]
+    pub restorer: usize,
+    /// Signals blocked while the handler runs.
+    pub mask: u64,
+}
+
+impl SigAction {
+    /// The default action (`SIG_DFL`).
+    pub const DEFAULT: Self = Self {
+        handler: 0,
+        flags: 0,
+        #[cfg(not(target_arch = "riscv64"))]
+        restorer: 0,
+        mask: 0,
+    };
+}
+
+/// The size of the huge pages of `MAP_HUGETLB`.
+pub const PAGE_SIZE_2M: usize = 0x20_0000;
+
+/// A region mapped by `mmap`.
+#[derive(Clone, Debug)]
+pub struct MmapRegion {
+    /// The page aligned virtual address range.
+    pub range: VirtAddrRange,
+    /// The mapping permissions.
+    pub flags: MappingFlags,
+    /// The backing file, `None` for anonymous mappings.
+    pub fd: Option<i32>,
+    /// The offset into the backing file.
+    pub offset: usize,
+    /// Whether the mapping is `MAP_SHARED`.
+    pub shared: bool,
+    /// Whether the region is backed by 2 MiB pages, for `MAP_HUGETLB`.
+    pub huge: bool,
+}
+
+impl MmapRegion {
+    /// Returns what is left of the region after removing `range` from it,
+    /// i.e. the parts before and after `range`.
+    pub fn subtract(&self, range: VirtAddrRange) -> (Option<Self>, Option<Self>) {
+        if !self.range.overlaps(range) {
+            return (Some(self.clone()), None);
+        }
+        let head = (self.range.start < range.start).then(|| Self {
+            range: VirtAddrRange::new(self.range.start, range.start),
+            ..self.clone()
+        });
+        let tail = (range.end < self.range.end).then(|| Self {
+            range: VirtAddrRange::new(range.end, self.range.end),
+            offset: if self.fd.is_some() {
+                self.offset + (range.end - self.range.start)
+            } else {
+                0
+            },
+            ..self.clone()
+        });
+        (head, tail)
+    }
+}
+
+impl fmt::Display for MmapRegion {
+    /// Formats the region as a line of `/proc/self/maps`, without the device,
+    /// inode and path columns.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let perm = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "{:08x}-{:08x} {}{}{}{} {:08x}",
+            self.range.start,
+            self.range.end,
+            perm(MappingFlags::READ, 'r'),
+            perm(MappingFlags::WRITE, 'w'),
+            perm(MappingFlags::EXECUTE, 'x'),
+            if self.shared { 's' } else { 'p' },
+            self.offset,
+        )
+    }
+}
+
+/// Bookkeeping of `mmap`: the mapped regions and the holes left by `munmap`.
+#[derive(Default)]
+pub struct MmapRegistry {
+    /// The mapped regions, in no particular order.
+    pub regions: Vec<MmapRegion>,
+    /// Unmapped ranges which used to belong to a region, sorted by address
+    /// and coalesced.
+    holes: Vec<VirtAddrRange>,
+}
+
+impl MmapRegistry {
+    /// Records a new region, which may fill some of the holes.
+    pub fn insert(&mut self, region: MmapRegion) {
+        self.holes = self
+            .holes
+            .iter()
+            .flat_map(|hole| subtract_range(*hole, region.range))
+            .collect();
+        self.regions.push(region);
+    }
+
+    /// Removes `range` from the regions, splitting the ones which are only
+    /// partially covered. The freed parts are recorded as holes.
+    pub fn remove(&mut self, range: VirtAddrRange) {
+        let regions = core::mem::take(&mut self.regions);
+        for region in regions {
+            if region.range.overlaps(range) {
+                let start = region.range.start.max(range.start);
+                let end = region.range.end.min(range.end);
+                self.add_hole(VirtAddrRange::new(start, end));
+            }
+            let (head, tail) = region.subtract(range);
+            self.regions.extend(head);
+            self.regions.extend(tail);
+        }
+    }
+
+    /// Returns the region which contains the whole `range`.
+    pub fn find(&self, range: VirtAddrRange) -> Option<&MmapRegion> {
+        self.regions.iter().find(|region| region.range.contains_range(range))
+    }
+
+    /// Grows the region starting at `start` up to `end`, filling the holes
+    /// it grows over. Shrinking is done by [`remove`](Self::remove).
+    pub fn grow(&mut self, start: VirtAddr, end: VirtAddr) {
+        let Some(region) = self.regions.iter_mut().find(|r| r.range.start == start) else {
+            return;
+        };
+        let grown = VirtAddrRange::new(region.range.end, end);
+        region.range.end = end;
+        self.holes = self
+            .holes
+            .iter()
+            .flat_map(|hole| subtract_range(*hole, grown))
+            .collect();
+    }
+
+    /// Changes the flags of `range`, splitting the regions which are only
+    /// partially covered so that each part keeps its own flags.
+    pub fn protect(&mut self, range: VirtAddrRange, flags: MappingFlags) {
+        let regions = core::mem::take(&mut self.regions);
+        for region in regions {
+            if !region.range.overlaps(range) {
+                self.regions.push(region);
+                continue;
+            }
+            let start = region.range.start.max(range.start);
+            let end = region.range.end.min(range.end);
+            let middle = MmapRegion {
+                range: VirtAddrRange::new(start, end),
+                flags,
+                offset: if region.fd.is_some() {
+                    region.offset + (start - region.range.start)
+                } else {
+                    0
+                },
+                ..region.clone()
+            };
+            let (head, tail) = region.subtract(range);
+            self.regions.extend(head);
+            self.regions.push(middle);
+            self.regions.extend(tail);
+        }
+    }
+
+    /// Returns whether an end of `range` falls inside a 2 MiB page of a huge
+    /// page region. Huge pages can't be split.
+    pub fn splits_huge_page(&self, range: VirtAddrRange) -> bool {
+        self.regions.iter().any(|region| {
+            region.huge
+                && [range.start, range.end]
+                    .iter()
+                    .any(|&addr| region.range.contains(addr) && !addr.is_aligned(PAGE_SIZE_2M))
+        })
+    }
+
+    /// Returns the start of the smallest hole which can hold `size` bytes
+    /// aligned to `align`.
+    pub fn best_fit(&self, size: usize, align: usize) -> Option<VirtAddr> {
+        self.holes
+            .iter()
+            .filter_map(|hole| {
+                let start = hole.start.align_up(align);
+                let end = start.as_usize().checked_add(size)?;
+                (end <= hole.end.as_usize()).then_some((hole.size(), start))
+            })
+            .min_by_key(|(hole_size, _)| *hole_size)
+            .map(|(_, start)| start)
+    }
+
+    /// Forgets all the regions and holes.
+    pub fn clear(&mut self) {
+        self.regions.clear();
+        self.holes.clear();
+    }
+
+    fn add_hole(&mut self, hole: VirtAddrRange) {
+        let idx = self.holes.partition_point(|h| h.start < hole.start);
+        self.holes.insert(idx, hole);
+        // Merge the holes which touch each other.
+        let mut merged: Vec<VirtAddrRange> = Vec::with_capacity(self.holes.len());
+        for hole in self.holes.drain(..) {
+            match merged.last_mut() {
+                Some(last) if last.end >= hole.start => last.end = last.end.max(hole.end),
+                _ => merged.push(hole),
+            }
+        }
+        self.holes = merged;
+    }
+}
+
+/// Returns the parts of `range` which are not covered by `other`.
+fn subtract_range(range: VirtAddrRange, other: VirtAddrRange) -> impl Iterator<Item = VirtAddrRange> {
+    if !range.overlaps(other) {
+        return [Some(range), None].into_iter().flatten();
+    }
+    let head = (range.start < other.start).then(|| VirtAddrRange::new(range.start, other.start));
+    let tail = (other.end < range.end).then(|| VirtAddrRange::new(other.end, range.end));
+    [head, tail].into_iter().flatten()
+}
+
+/// Flags attached to an open file descriptor.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FdFlags {
+    /// File status flags (`O_*`) given at open time or set by `F_SETFL`.
+    pub status: u32,
+    /// Whether `FD_CLOEXEC` is set.
+    pub cloexec: bool,
 }
 
 impl TaskExt {
-    pub const fn new(uctx: UspaceContext, aspace: Arc<Mutex<AddrSpace>>) -> Self {
+    pub fn new(exe_path: &str, uctx: UspaceContext, aspace: Arc<Mutex<AddrSpace>>) -> Self {
         Self {
             proc_id: 233,
+            parent_id: 0,
+            exe_path: Arc::new(String::from(exe_path)),
             uctx,
             clear_child_tid: AtomicU64::new(0),
+            robust_list: Mutex::new((0, 0)),
             aspace,
+            group: Arc::new(ThreadGroup::new()),
+            mmap_regions: Arc::new(Mutex::new(MmapRegistry::default())),
+            fd_flags: Arc::new(Mutex::new(BTreeMap::new())),
+            sigactions: Arc::new(Mutex::new([SigAction::DEFAULT; NSIG])),
+            rlimits: Arc::new(Mutex::new(default_rlimits())),
+            sigmask: AtomicU64::new(0),
+            page_faults: AtomicU64::new(0),
+            comm: Mutex::new(comm_of(exe_path)),
+            sys_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// Creates the extended data of a thread of `parent`'s process.
+    ///
+    /// The new thread shares the address space, the mmap regions, the fd
+    /// flags, the signal actions and the resource limits with `parent`, and
+    /// inherits its signal mask and name.
+    pub fn new_thread(uctx: UspaceContext, parent: &TaskExt) -> Self {
+        parent.group.live.fetch_add(1, Ordering::AcqRel);
+        Self {
+            proc_id: parent.proc_id,
+            // Threads share the parent of their process.
+            parent_id: parent.parent_id,
+            exe_path: parent.exe_path.clone(),
+            uctx,
+            clear_child_tid: AtomicU64::new(0),
+            robust_list: Mutex::new((0, 0)),
+            aspace: parent.aspace.clone(),
+            group: parent.group.clone(),
+            mmap_regions: parent.mmap_regions.clone(),
+            fd_flags: parent.fd_flags.clone(),
+            sigactions: parent.sigactions.clone(),
+            rlimits: parent.rlimits.clone(),
+            sigmask: AtomicU64::new(parent.sigmask()),
+            page_faults: AtomicU64::new(0),
+            comm: Mutex::new(*parent.comm.lock()),
+            sys_nanos: AtomicU64::new(0),
         }
     }
 
@@ -44,11 +468,71 @@ impl TaskExt {
         self.clear_child_tid
             .store(clear_child_tid, core::sync::atomic::Ordering::Relaxed);
     }
+
+    /// Returns the head and the size of the robust futex list.
+    pub(crate) fn robust_list(&self) -> (usize, usize) {
+        *self.robust_list.lock()
+    }
+
+    pub(crate) fn set_robust_list(&self, head: usize, len: usize) {
+        *self.robust_list.lock() = (head, len);
+    }
+
+    /// Returns the regions mapped by `mmap`, locked for iteration or update.
+    pub(crate) fn mmap_regions(&self) -> MutexGuard<'_, MmapRegistry> {
+        self.mmap_regions.lock()
+    }
+
+    pub(crate) fn page_faults(&self) -> u64 {
+        self.page_faults.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn inc_page_faults(&self) {
+        self.page_faults
+            .fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_sys_time(&self, nanos: u64) {
+        self.sys_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Returns the user and system time of `task`, in nanoseconds.
+    ///
+    /// Both only count the time `task` was on a CPU: the system time is the
+    /// part of it spent in syscalls and the user time is the rest.
+    pub(crate) fn cpu_times(&self, task: &TaskInner) -> (u64, u64) {
+        let sys = self.sys_nanos.load(Ordering::Relaxed);
+        (task.cpu_time_nanos().saturating_sub(sys), sys)
+    }
+
+    pub(crate) fn comm(&self) -> [u8; TASK_COMM_LEN] {
+        *self.comm.lock()
+    }
+
+    pub(crate) fn set_comm(&self, comm: [u8; TASK_COMM_LEN]) {
+        *self.comm.lock() = comm;
+    }
+
+    pub(crate) fn sigmask(&self) -> u64 {
+        self.sigmask.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_sigmask(&self, sigmask: u64) {
+        self.sigmask
+            .store(sigmask, core::sync::atomic::Ordering::Relaxed);
+    }
 }
 
 axtask::def_task_ext!(TaskExt);
 
-pub fn spawn_user_task(aspace: Arc<Mutex<AddrSpace>>, uctx: UspaceContext) -> AxTaskRef {
+pub fn spawn_user_task(exe_path: &str, aspace: Arc<Mutex<AddrSpace>>, uctx: UspaceContext) -> AxTaskRef {
+    axtask::spawn_task(new_user_task("userboot", TaskExt::new(exe_path, uctx, aspace)))
+}
+
+/// Creates a task which enters user space with the context in `task_ext`.
+///
+/// The task is not spawned, so that the caller can use its ID before it runs.
+pub fn new_user_task(name: &str, task_ext: TaskExt) -> TaskInner {
     let mut task = TaskInner::new(
         || {
             let curr = axtask::current();
@@ -61,11 +545,11 @@ pub fn spawn_user_task(aspace: Arc<Mutex<AddrSpace>>, uctx: UspaceContext) -> Ax
             );
             unsafe { curr.task_ext().uctx.enter_uspace(kstack_top) };
         },
-        "userboot".into(),
+        name.into(),
         crate::KERNEL_STACK_SIZE,
     );
     task.ctx_mut()
-        .set_page_table_root(aspace.lock().page_table_root());
-    task.init_task_ext(TaskExt::new(uctx, aspace));
-    axtask::spawn_task(task)
+        .set_page_table_root(task_ext.aspace.lock().page_table_root());
+    task.init_task_ext(task_ext);
+    task
 }
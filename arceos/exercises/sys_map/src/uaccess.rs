@@ -0,0 +1,142 @@
+//! Checked access to user memory.
+//!
+//! The helpers go through the page table of the current task rather than
+//! dereferencing user pointers, so a bad address turns into `EFAULT` instead
+//! of a kernel page fault.
+
+use core::ffi::c_char;
+use core::mem::{size_of, MaybeUninit};
+
+use alloc::vec::Vec;
+
+use axerrno::{LinuxError, LinuxResult};
+use axhal::mem::VirtAddr;
+use axhal::paging::MappingFlags;
+use axmm::AddrSpace;
+use axtask::{current, TaskExtRef};
+use memory_addr::{MemoryAddr, PageIter4K, PAGE_SIZE_4K};
+
+/// The longest path accepted.
+pub const PATH_MAX: usize = 4096;
+
+/// Checks that `[start, start + len)` is mapped with `access` permission,
+/// and faults in the pages of lazy mappings.
+fn prepare_user_range(
+    aspace: &mut AddrSpace,
+    start: usize,
+    len: usize,
+    access: MappingFlags,
+) -> LinuxResult {
+    if start == 0 {
+        return Err(LinuxError::EFAULT);
+    }
+    let end = start.checked_add(len).ok_or(LinuxError::EFAULT)?;
+    let start = VirtAddr::from(start);
+    let end = VirtAddr::from(end);
+    if !aspace.contains_range(start, len) || !aspace.is_range_mapped(start, len) {
+        return Err(LinuxError::EFAULT);
+    }
+    for page in PageIter4K::new(start.align_down_4k(), end.align_up_4k()).unwrap() {
+        let present = aspace
+            .page_table()
+            .query(page)
+            .is_ok_and(|(_, flags, _)| flags.contains(access));
+        if !present && !aspace.handle_page_fault(page, access) {
+            return Err(LinuxError::EFAULT);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the user range can be accessed, without copying anything.
+pub fn check_user_range(start: usize, len: usize, access: MappingFlags) -> LinuxResult {
+    let curr = current();
+    let mut aspace = curr.task_ext().aspace.lock();
+    prepare_user_range(&mut aspace, start, len, access | MappingFlags::USER)
+}
+
+/// Reads a `T` from user memory.
+pub fn copy_from_user<T: Copy>(src: *const T) -> LinuxResult<T> {
+    let curr = current();
+    let mut aspace = curr.task_ext().aspace.lock();
+    let access = MappingFlags::READ | MappingFlags::USER;
+    prepare_user_range(&mut aspace, src as usize, size_of::<T>(), access)?;
+
+    let mut val = MaybeUninit::<T>::uninit();
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>())
+    };
+    aspace
+        .read(VirtAddr::from(src as usize), buf)
+        .map_err(|_| LinuxError::EFAULT)?;
+    Ok(unsafe { val.assume_init() })
+}
+
+/// Writes `val` to user memory.
+pub fn copy_to_user<T: Copy>(dst: *mut T, val: &T) -> LinuxResult {
+    let curr = current();
+    let mut aspace = curr.task_ext().aspace.lock();
+    let access = MappingFlags::WRITE | MappingFlags::USER;
+    prepare_user_range(&mut aspace, dst as usize, size_of::<T>(), access)?;
+
+    let buf = unsafe {
+        core::slice::from_raw_parts(val as *const T as *const u8, size_of::<T>())
+    };
+    aspace
+        .write(VirtAddr::from(dst as usize), buf)
+        .map_err(|_| LinuxError::EFAULT)
+}
+
+/// Writes `data` to user memory at `dst`.
+pub fn copy_bytes_to_user(dst: *mut u8, data: &[u8]) -> LinuxResult {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let curr = current();
+    let mut aspace = curr.task_ext().aspace.lock();
+    let access = MappingFlags::WRITE | MappingFlags::USER;
+    prepare_user_range(&mut aspace, dst as usize, data.len(), access)?;
+    aspace
+        .write(VirtAddr::from(dst as usize), data)
+        .map_err(|_| LinuxError::EFAULT)
+}
+
+/// Reads a NUL-terminated string of at most `max` bytes from user memory,
+/// without the NUL. Longer strings fail with `ENAMETOOLONG`.
+///
+/// The string is read page by page, so that bytes past the NUL are never
+/// touched: they may well be in an unmapped page.
+pub fn copy_cstr_from_user(src: *const c_char, max: usize) -> LinuxResult<Vec<u8>> {
+    let curr = current();
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; PAGE_SIZE_4K];
+    loop {
+        let addr = (src as usize).checked_add(buf.len()).ok_or(LinuxError::EFAULT)?;
+        // One more byte than `max` is read, for the NUL.
+        let len = (PAGE_SIZE_4K - addr % PAGE_SIZE_4K).min(max + 1 - buf.len());
+        {
+            let mut aspace = curr.task_ext().aspace.lock();
+            let access = MappingFlags::READ | MappingFlags::USER;
+            prepare_user_range(&mut aspace, addr, len, access)?;
+            aspace
+                .read(VirtAddr::from(addr), &mut chunk[..len])
+                .map_err(|_| LinuxError::EFAULT)?;
+        }
+        if let Some(nul) = chunk[..len].iter().position(|&c| c == 0) {
+            buf.extend_from_slice(&chunk[..nul]);
+            return Ok(buf);
+        }
+        buf.extend_from_slice(&chunk[..len]);
+        if buf.len() > max {
+            return Err(LinuxError::ENAMETOOLONG);
+        }
+    }
+}
+
+/// Reads a path from user memory, NUL-terminated so that it can be passed to
+/// the posix api.
+pub fn copy_path_from_user(src: *const c_char) -> LinuxResult<Vec<u8>> {
+    let mut path = copy_cstr_from_user(src, PATH_MAX)?;
+    path.push(0);
+    Ok(path)
+}
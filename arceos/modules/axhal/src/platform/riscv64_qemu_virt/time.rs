@@ -1,8 +1,14 @@
+#[cfg(feature = "irq")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use riscv::register::time;
 
 const NANOS_PER_TICK: u64 = crate::time::NANOS_PER_SEC / axconfig::TIMER_FREQUENCY as u64;
 /// RTC wall time offset in nanoseconds at monotonic time base.
 static mut RTC_EPOCHOFFSET_NANOS: u64 = 0;
+/// The deadline of the one-shot timer in hardware ticks.
+#[cfg(feature = "irq")]
+static ONESHOT_DEADLINE_TICKS: AtomicU64 = AtomicU64::new(u64::MAX);
 
 /// Returns the current clock time in hardware ticks.
 #[inline]
@@ -32,7 +38,18 @@ pub fn epochoffset_nanos() -> u64 {
 /// A timer interrupt will be triggered at the specified monotonic time deadline (in nanoseconds).
 #[cfg(feature = "irq")]
 pub fn set_oneshot_timer(deadline_ns: u64) {
-    sbi_rt::set_timer(nanos_to_ticks(deadline_ns));
+    let deadline = nanos_to_ticks(deadline_ns);
+    ONESHOT_DEADLINE_TICKS.store(deadline, Ordering::Relaxed);
+    sbi_rt::set_timer(deadline);
+}
+
+/// Returns the deadline of the one-shot timer in hardware ticks, `u64::MAX`
+/// if it has not been set.
+///
+/// It lets a hypervisor share the timer with a guest.
+#[cfg(feature = "irq")]
+pub fn oneshot_deadline_ticks() -> u64 {
+    ONESHOT_DEADLINE_TICKS.load(Ordering::Relaxed)
 }
 
 pub(super) fn init_early() {
@@ -12,6 +12,8 @@ pub type TimeValue = Duration;
 pub use crate::platform::irq::TIMER_IRQ_NUM;
 #[cfg(feature = "irq")]
 pub use crate::platform::time::set_oneshot_timer;
+#[cfg(all(feature = "irq", platform_family = "riscv64-qemu-virt"))]
+pub use crate::platform::time::oneshot_deadline_ticks;
 pub use crate::platform::time::{current_ticks, epochoffset_nanos, nanos_to_ticks, ticks_to_nanos};
 
 /// Number of milliseconds in a second.
@@ -6,7 +6,8 @@ use axhal::{
     paging::{MappingFlags, PageTable},
 };
 use memory_addr::{
-    is_aligned_4k, pa, MemoryAddr, PageIter4K, PhysAddr, VirtAddr, VirtAddrRange, PAGE_SIZE_4K,
+    is_aligned, is_aligned_4k, pa, MemoryAddr, PageIter4K, PhysAddr, VirtAddr, VirtAddrRange,
+    PAGE_SIZE_4K,
 };
 use memory_set::{MemoryArea, MemorySet};
 use crate::backend::Backend;
@@ -14,6 +15,8 @@ use crate::paging_err_to_ax_err;
 use crate::mapping_err_to_ax_err;
 use alloc::vec::Vec;
 
+const PAGE_SIZE_2M: usize = 0x20_0000;
+
 /// The virtual memory address space.
 pub struct AddrSpace {
     va_range: VirtAddrRange,
@@ -53,6 +56,25 @@ impl AddrSpace {
             .contains_range(VirtAddrRange::from_start_size(start, size))
     }
 
+    /// Checks if every page of the given address range belongs to a memory
+    /// area, i.e. has been mapped by [`AddrSpace::map_alloc`] or alike.
+    pub fn is_range_mapped(&self, start: VirtAddr, size: usize) -> bool {
+        let end = start + size;
+        let mut addr = start;
+        while addr < end {
+            match self.areas.find(addr) {
+                Some(area) => addr = area.end(),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Returns the range and flags of each memory area, in address order.
+    pub fn mapped_areas(&self) -> impl Iterator<Item = (VirtAddrRange, MappingFlags)> + '_ {
+        self.areas.iter().map(|area| (area.va_range(), area.flags()))
+    }
+
     /// Creates a new empty address space.
     pub fn new_empty(base: VirtAddr, size: usize) -> AxResult<Self> {
         Ok(Self {
@@ -158,6 +180,27 @@ impl AddrSpace {
         Ok(())
     }
 
+    /// Add a new allocation mapping backed by 2 MiB pages.
+    ///
+    /// All the huge pages are allocated and zeroed when the mapping is created.
+    ///
+    /// Returns an error if the address range is out of the address space or not
+    /// aligned to 2 MiB.
+    pub fn map_alloc_huge(&mut self, start: VirtAddr, size: usize, flags: MappingFlags) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned(PAGE_SIZE_2M) || !is_aligned(size, PAGE_SIZE_2M) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let area = MemoryArea::new(start, size, flags, Backend::new_huge_alloc());
+        self.areas
+            .map(area, &mut self.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        Ok(())
+    }
+
     /// Removes mappings within the specified virtual address range.
     ///
     /// Returns an error if the address range is out of the address space or not
@@ -170,13 +213,28 @@ impl AddrSpace {
             return ax_err!(InvalidInput, "address not aligned");
         }
 
-        self.pt
-            .unmap_region(start, size, true)
-            .map_err(paging_err_to_ax_err)?
-            .ignore();
+        // Areas partially covered by the range are split, and the frames of
+        // the covered part are released by the backend.
+        self.areas
+            .unmap(start, size, &mut self.pt)
+            .map_err(mapping_err_to_ax_err)?;
         Ok(())
     }
 
+    /// Removes all mappings in the address space.
+    ///
+    /// The page table entries copied by [`AddrSpace::copy_mappings_from`] are
+    /// not memory areas and are kept. An area which fails to be unmapped is
+    /// logged and left in place, the others are still removed.
+    pub fn clear(&mut self) {
+        let ranges: Vec<_> = self.areas.iter().map(|area| area.va_range()).collect();
+        for range in ranges {
+            if let Err(err) = self.areas.unmap(range.start, range.size(), &mut self.pt) {
+                warn!("AddrSpace clear: failed to unmap {:?}: {:?}", range, err);
+            }
+        }
+    }
+
     /// To process data in this area with the given function.
     ///
     /// Now it supports reading and writing data in the given interval.
@@ -237,6 +295,9 @@ impl AddrSpace {
 
     /// Updates mapping within the specified virtual address range.
     ///
+    /// The areas which are partially covered are split, so that the pages
+    /// faulted in later get the new flags too.
+    ///
     /// Returns an error if the address range is out of the address space or not
     /// aligned.
     pub fn protect(&mut self, start: VirtAddr, size: usize, flags: MappingFlags) -> AxResult {
@@ -247,10 +308,9 @@ impl AddrSpace {
             return ax_err!(InvalidInput, "address not aligned");
         }
 
-        self.pt
-            .protect_region(start, size, flags, true)
-            .map_err(paging_err_to_ax_err)?
-            .ignore();
+        self.areas
+            .protect(start, size, |_| Some(flags), &mut self.pt)
+            .map_err(mapping_err_to_ax_err)?;
         Ok(())
     }
 
@@ -1,10 +1,17 @@
 use axalloc::global_allocator;
 use axhal::mem::{phys_to_virt, virt_to_phys};
 use axhal::paging::{MappingFlags, PageSize, PageTable};
-use memory_addr::{PageIter4K, PhysAddr, VirtAddr, PAGE_SIZE_4K};
+use memory_addr::{PageIter, PageIter4K, PhysAddr, VirtAddr, PAGE_SIZE_4K};
 
 use super::Backend;
 
+const PAGE_SIZE_2M: usize = 0x20_0000;
+const FRAMES_PER_2M: usize = PAGE_SIZE_2M / PAGE_SIZE_4K;
+
+/// Allocates a frame, cleared if `zeroed`.
+///
+/// The backend maps zeroed frames only, anonymous user mappings rely on it to
+/// read as zero.
 fn alloc_frame(zeroed: bool) -> Option<PhysAddr> {
     let vaddr = VirtAddr::from(global_allocator().alloc_pages(1, PAGE_SIZE_4K).ok()?);
     if zeroed {
@@ -19,12 +26,56 @@ fn dealloc_frame(frame: PhysAddr) {
     global_allocator().dealloc_pages(vaddr.as_usize(), 1);
 }
 
+fn alloc_huge_frame() -> Option<PhysAddr> {
+    let vaddr = VirtAddr::from(
+        global_allocator()
+            .alloc_pages(FRAMES_PER_2M, PAGE_SIZE_2M)
+            .ok()?,
+    );
+    unsafe { core::ptr::write_bytes(vaddr.as_mut_ptr(), 0, PAGE_SIZE_2M) };
+    Some(virt_to_phys(vaddr))
+}
+
+fn dealloc_huge_frame(frame: PhysAddr) {
+    let vaddr = phys_to_virt(frame);
+    global_allocator().dealloc_pages(vaddr.as_usize(), FRAMES_PER_2M);
+}
+
+/// A page table entry of an allocation mapping.
+struct AllocEntry {
+    /// The frame of the entry, `None` for a page not faulted in yet.
+    frame: Option<PhysAddr>,
+    /// Whether the entry is valid, it is not if its access was taken away
+    /// by `protect`.
+    present: bool,
+    size: PageSize,
+}
+
+/// Queries the entry of the page at `vaddr`.
+///
+/// The entries of lazy mappings map the page 0 with no flags until they are
+/// faulted in, there is no RAM at 0 so it can't be a frame.
+fn query_entry(pt: &PageTable, vaddr: VirtAddr) -> Option<AllocEntry> {
+    let (paddr, flags, size) = pt.query(vaddr).ok()?;
+    let frame = PhysAddr::from(paddr.as_usize() & !(size as usize - 1));
+    Some(AllocEntry {
+        frame: (frame.as_usize() != 0).then_some(frame),
+        present: !flags.is_empty(),
+        size,
+    })
+}
+
 impl Backend {
     /// Creates a new allocation mapping backend.
     pub const fn new_alloc(populate: bool) -> Self {
         Self::Alloc { populate }
     }
 
+    /// Creates a new huge page allocation mapping backend.
+    pub const fn new_huge_alloc() -> Self {
+        Self::HugeAlloc
+    }
+
     pub(crate) fn map_alloc(
         &self,
         start: VirtAddr,
@@ -70,16 +121,60 @@ impl Backend {
     ) -> bool {
         debug!("unmap_alloc: [{:#x}, {:#x})", start, start + size);
         for addr in PageIter4K::new(start, start + size).unwrap() {
-            if let Ok((frame, page_size, tlb)) = pt.unmap(addr) {
-                // Deallocate the physical frame if there is a mapping in the
-                // page table.
-                if page_size.is_huge() {
-                    return false;
-                }
+            let Some(entry) = query_entry(pt, addr) else {
+                continue;
+            };
+            if entry.size.is_huge() {
+                return false;
+            }
+            // `unmap` clears the entry even if it is not present, but only
+            // reports the frame of a present one.
+            if let Ok((_, _, tlb)) = pt.unmap(addr) {
                 tlb.flush();
+            }
+            if let Some(frame) = entry.frame {
                 dealloc_frame(frame);
-            } else {
-                // Deallocation is needn't if the page is not mapped.
+            }
+        }
+        true
+    }
+
+    pub(crate) fn protect_alloc(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+        pt: &mut PageTable,
+    ) -> bool {
+        debug!(
+            "protect_alloc: [{:#x}, {:#x}) {:?}",
+            start,
+            start + size,
+            flags
+        );
+        // An entry without any access bit keeps its frame but is not valid,
+        // so that the frame is still there when the access is given back.
+        // Such an entry can't be protected again, it is remapped instead.
+        let access = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE;
+        let pte_flags = if flags.intersects(access) {
+            flags
+        } else {
+            MappingFlags::empty()
+        };
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            // Pages which are not allocated yet are left alone, the page fault
+            // handler maps them with the flags of the area.
+            let Some(entry) = query_entry(pt, addr) else {
+                continue;
+            };
+            let res = match entry.frame {
+                Some(_) if entry.present => pt.protect(addr, pte_flags).map(|(_, tlb)| tlb),
+                Some(frame) => pt.remap(addr, frame, pte_flags).map(|(_, tlb)| tlb),
+                None => continue,
+            };
+            match res {
+                Ok(tlb) => tlb.flush(),
+                Err(_) => return false,
             }
         }
         true
@@ -105,4 +200,82 @@ impl Backend {
             false
         }
     }
+
+    pub(crate) fn map_huge_alloc(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+        pt: &mut PageTable,
+    ) -> bool {
+        debug!(
+            "map_huge_alloc: [{:#x}, {:#x}) {:?}",
+            start,
+            start + size,
+            flags
+        );
+        let Some(iter) = PageIter::<PAGE_SIZE_2M, VirtAddr>::new(start, start + size) else {
+            return false;
+        };
+        for addr in iter {
+            let Some(frame) = alloc_huge_frame() else {
+                return false;
+            };
+            if let Ok(tlb) = pt.map(addr, frame, PageSize::Size2M, flags) {
+                tlb.ignore(); // TLB flush on map is unnecessary, as there are no outdated mappings.
+            } else {
+                dealloc_huge_frame(frame);
+                return false;
+            }
+        }
+        true
+    }
+
+    pub(crate) fn protect_huge_alloc(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+        pt: &mut PageTable,
+    ) -> bool {
+        debug!(
+            "protect_huge_alloc: [{:#x}, {:#x}) {:?}",
+            start,
+            start + size,
+            flags
+        );
+        // Unlike a 4K entry, a 2M entry can't be left invalid with its frame
+        // for a later `protect`: without access bits it would be walked as a
+        // page table. Taking all the access away is refused.
+        let access = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE;
+        if !flags.intersects(access) {
+            return false;
+        }
+        pt.protect_region(start, size, flags, true)
+            .map(|tlb| tlb.flush_all())
+            .is_ok()
+    }
+
+    pub(crate) fn unmap_huge_alloc(&self, start: VirtAddr, size: usize, pt: &mut PageTable) -> bool {
+        debug!("unmap_huge_alloc: [{:#x}, {:#x})", start, start + size);
+        let Some(iter) = PageIter::<PAGE_SIZE_2M, VirtAddr>::new(start, start + size) else {
+            return false;
+        };
+        for addr in iter {
+            // Check the size first, a small page is not ours to free.
+            let Some(entry) = query_entry(pt, addr) else {
+                continue;
+            };
+            if entry.size != PageSize::Size2M {
+                return false;
+            }
+            if let Ok((_, _, tlb)) = pt.unmap(addr) {
+                tlb.flush();
+            }
+            if let Some(frame) = entry.frame {
+                dealloc_huge_frame(frame);
+            }
+        }
+        true
+    }
 }
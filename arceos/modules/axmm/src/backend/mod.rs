@@ -10,12 +10,13 @@ mod linear;
 
 /// A unified enum type for different memory mapping backends.
 ///
-/// Currently, two backends are implemented:
+/// Currently, three backends are implemented:
 ///
 /// - **Linear**: used for linear mappings. The target physical frames are
 ///   contiguous and their addresses should be known when creating the mapping.
 /// - **Allocation**: used in general, or for lazy mappings. The target physical
 ///   frames are obtained from the global allocator.
+/// - **Huge allocation**: like allocation, but backed by 2 MiB pages.
 #[derive(Clone)]
 pub enum Backend {
     /// Linear mapping backend.
@@ -37,6 +38,12 @@ pub enum Backend {
         /// Whether to populate the physical frames when creating the mapping.
         populate: bool,
     },
+    /// Huge page allocation mapping backend.
+    ///
+    /// Each 2 MiB page is a physically contiguous block obtained from the
+    /// global allocator. All of them are allocated when the mapping is
+    /// created.
+    HugeAlloc,
 }
 
 impl MappingBackend for Backend {
@@ -47,6 +54,7 @@ impl MappingBackend for Backend {
         match *self {
             Self::Linear { pa_va_offset } => self.map_linear(start, size, flags, pt, pa_va_offset),
             Self::Alloc { populate } => self.map_alloc(start, size, flags, pt, populate),
+            Self::HugeAlloc => self.map_huge_alloc(start, size, flags, pt),
         }
     }
 
@@ -54,6 +62,7 @@ impl MappingBackend for Backend {
         match *self {
             Self::Linear { pa_va_offset } => self.unmap_linear(start, size, pt, pa_va_offset),
             Self::Alloc { populate } => self.unmap_alloc(start, size, pt, populate),
+            Self::HugeAlloc => self.unmap_huge_alloc(start, size, pt),
         }
     }
 
@@ -64,10 +73,14 @@ impl MappingBackend for Backend {
         new_flags: Self::Flags,
         page_table: &mut Self::PageTable,
     ) -> bool {
-        page_table
-            .protect_region(start, size, new_flags, true)
-            .map(|tlb| tlb.ignore())
-            .is_ok()
+        match *self {
+            Self::Alloc { .. } => self.protect_alloc(start, size, new_flags, page_table),
+            Self::HugeAlloc => self.protect_huge_alloc(start, size, new_flags, page_table),
+            Self::Linear { .. } => page_table
+                .protect_region(start, size, new_flags, true)
+                .map(|tlb| tlb.ignore())
+                .is_ok(),
+        }
     }
 }
 
@@ -83,6 +96,7 @@ impl Backend {
             Self::Alloc { populate } => {
                 self.handle_page_fault_alloc(vaddr, orig_flags, page_table, populate)
             }
+            Self::HugeAlloc => false, // Huge mappings are always populated.
         }
     }
 }
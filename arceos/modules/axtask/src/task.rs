@@ -51,6 +51,11 @@ pub struct TaskInner {
     exit_code: AtomicI32,
     wait_for_exit: WaitQueue,
 
+    /// Time the task has spent on a CPU, not counting the current run.
+    cpu_nanos: AtomicU64,
+    /// When the task was last switched in.
+    sched_in_nanos: AtomicU64,
+
     kstack: Option<TaskStack>,
     ctx: UnsafeCell<TaskContext>,
     task_ext: AxTaskExt,
@@ -126,6 +131,19 @@ impl TaskInner {
         alloc::format!("Task({}, {:?})", self.id.as_u64(), self.name)
     }
 
+    /// Returns the time the task has spent running on a CPU, in nanoseconds.
+    ///
+    /// The time it spent ready or blocked is not counted.
+    pub fn cpu_time_nanos(&self) -> u64 {
+        let total = self.cpu_nanos.load(Ordering::Acquire);
+        if self.is_running() {
+            let since = self.sched_in_nanos.load(Ordering::Acquire);
+            total + axhal::time::monotonic_time_nanos().saturating_sub(since)
+        } else {
+            total
+        }
+    }
+
     /// Wait for the task to exit, and return the exit code.
     ///
     /// It will return immediately if the task has already exited (but not dropped).
@@ -180,6 +198,8 @@ impl TaskInner {
             preempt_disable_count: AtomicUsize::new(0),
             exit_code: AtomicI32::new(0),
             wait_for_exit: WaitQueue::new(),
+            cpu_nanos: AtomicU64::new(0),
+            sched_in_nanos: AtomicU64::new(axhal::time::monotonic_time_nanos()),
             kstack: None,
             ctx: UnsafeCell::new(TaskContext::new()),
             task_ext: AxTaskExt::empty(),
@@ -219,6 +239,20 @@ impl TaskInner {
         self.state.store(state as u8, Ordering::Release)
     }
 
+    /// Records that the task is switched in at `now`.
+    #[inline]
+    pub(crate) fn sched_in(&self, now: u64) {
+        self.sched_in_nanos.store(now, Ordering::Release);
+    }
+
+    /// Adds the time since the task was switched in to its CPU time.
+    #[inline]
+    pub(crate) fn sched_out(&self, now: u64) {
+        let since = self.sched_in_nanos.load(Ordering::Acquire);
+        self.cpu_nanos
+            .fetch_add(now.saturating_sub(since), Ordering::AcqRel);
+    }
+
     #[inline]
     pub(crate) fn is_running(&self) -> bool {
         matches!(self.state(), TaskState::Running)
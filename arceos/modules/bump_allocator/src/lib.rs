@@ -1,6 +1,6 @@
 #![no_std]
 
-use core::{mem::MaybeUninit};
+use core::{mem::MaybeUninit, ptr::NonNull};
 
 use allocator::{BaseAllocator, ByteAllocator, PageAllocator};
 
@@ -14,6 +14,15 @@ pub const fn align_up(pos: usize, align: usize) -> usize {
     (pos + align - 1) & !(align - 1)
 }
 
+/// The area of an [`EarlyAllocator`] an address belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// The bytes-used area.
+    Bytes,
+    /// The pages-used area.
+    Pages,
+}
+
 /// Early memory allocator
 /// Use it before formal bytes-allocator and pages-allocator can work!
 /// This is a double-end memory range:
@@ -28,6 +37,9 @@ pub const fn align_up(pos: usize, align: usize) -> usize {
 /// When it goes down to ZERO, free bytes-used area.
 /// For pages area, it will never be freed!
 ///
+/// 'total_allocs' counts all the allocations ever made, of bytes or pages.
+/// 'wasted' counts the bytes skipped to align byte allocations.
+///
 pub struct EarlyAllocator<const SIZE: usize> {
     arena: [MaybeUninit<u8>; SIZE],
     b_start: usize,
@@ -35,6 +47,8 @@ pub struct EarlyAllocator<const SIZE: usize> {
     p_pos: usize,
     p_end: usize,
     b_count: usize,
+    total_allocs: usize,
+    wasted: usize,
 }
 
 impl<const SIZE: usize> EarlyAllocator<SIZE> {
@@ -51,7 +65,81 @@ impl<const SIZE: usize> EarlyAllocator<SIZE> {
             p_pos: SIZE,
             p_end: 0,
             b_count: 0,
+            total_allocs: 0,
+            wasted: 0,
+        }
+    }
+
+    // Create a new, empty EarlyAllocator whose whole arena is zeroed, so
+    // that fresh allocations read as zero.
+    // This costs one write of SIZE bytes when it is created at runtime.
+    // Memory freed and allocated again is not zeroed again.
+    pub const fn new_zeroed() -> Self {
+        const ZERO: MaybeUninit<u8> = MaybeUninit::new(0);
+        Self {
+            arena: [ZERO; SIZE],
+            ..Self::new()
+        }
+    }
+
+    // Which used area `ptr` is in, None if it is in neither.
+    pub fn region_of(&self, ptr: NonNull<u8>) -> Option<Region> {
+        let offset = (ptr.as_ptr() as usize).checked_sub(self.arena.as_ptr() as usize)?;
+        if (self.b_start..self.b_pos).contains(&offset) {
+            Some(Region::Bytes)
+        } else if (self.p_pos..self.p_end).contains(&offset) {
+            Some(Region::Pages)
+        } else {
+            None
+        }
+    }
+
+    // Resize the byte allocation `ptr` to `new_size`.
+    // The most recent allocation grows or shrinks in place, any other one
+    // is moved to a new allocation.
+    pub fn realloc(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: core::alloc::Layout,
+        new_size: usize,
+    ) -> allocator::AllocResult<NonNull<u8>> {
+        let arena_base = self.arena.as_ptr() as usize;
+        let offset = (ptr.as_ptr() as usize).wrapping_sub(arena_base);
+        if self.region_of(ptr) == Some(Region::Bytes) && offset + layout.size() == self.b_pos {
+            let new_b_pos = match offset.checked_add(new_size) {
+                Some(pos) => pos,
+                None => return Err(allocator::AllocError::NoMemory),
+            };
+            // collision check
+            if new_b_pos > self.p_pos {
+                return Err(allocator::AllocError::NoMemory);
+            }
+            self.b_pos = new_b_pos;
+            return Ok(ptr);
+        }
+
+        let new_layout = core::alloc::Layout::from_size_align(new_size, layout.align())
+            .map_err(|_| allocator::AllocError::InvalidParam)?;
+        let new_ptr = self.alloc(new_layout)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.as_ptr(),
+                core::cmp::min(layout.size(), new_size),
+            );
         }
+        self.dealloc(ptr, layout);
+        Ok(new_ptr)
+    }
+
+    // Bytes skipped for alignment by all the byte allocations so far.
+    pub fn wasted_bytes(&self) -> usize {
+        self.wasted
+    }
+
+    // Number of successful allocations since creation, never decreases.
+    pub fn total_allocations(&self) -> usize {
+        self.total_allocs
     }
 }
 
@@ -79,8 +167,10 @@ impl<const SIZE: usize> ByteAllocator for EarlyAllocator<SIZE> {
         &mut self,
         layout: core::alloc::Layout,
     ) -> allocator::AllocResult<core::ptr::NonNull<u8>> {
-        // Calculate aligned start offset
-        let aligned_b_pos = align_up(self.b_pos, layout.align());
+        // Calculate aligned start offset, from the aligned address as the
+        // arena itself may not be aligned.
+        let arena_base = self.arena.as_ptr() as usize;
+        let aligned_b_pos = align_up(arena_base + self.b_pos, layout.align()) - arena_base;
         let new_b_pos = aligned_b_pos.checked_add(layout.size());
         
         let new_b_pos = match new_b_pos {
@@ -93,9 +183,18 @@ impl<const SIZE: usize> ByteAllocator for EarlyAllocator<SIZE> {
             return Err(allocator::AllocError::NoMemory);
         }
 
+        // The count must not wrap, it would reset the bytes area while
+        // allocations are still live.
+        let b_count = match self.b_count.checked_add(1) {
+            Some(count) => count,
+            None => return Err(allocator::AllocError::NoMemory),
+        };
+
         // update state
+        self.wasted = self.wasted.saturating_add(aligned_b_pos - self.b_pos);
         self.b_pos = new_b_pos;
-        self.b_count += 1;
+        self.b_count = b_count;
+        self.total_allocs = self.total_allocs.saturating_add(1);
 
         unsafe {
             let ptr = (self.arena.as_mut_ptr() as *mut u8).add(aligned_b_pos);
@@ -107,7 +206,16 @@ impl<const SIZE: usize> ByteAllocator for EarlyAllocator<SIZE> {
         if self.b_count == 0 {
             return;
         }
-        
+
+        // Ignore pointers which don't come from the bytes area.
+        let arena_base = self.arena.as_ptr() as usize;
+        let addr = pos.as_ptr() as usize;
+        if addr < arena_base + self.b_start
+            || addr.saturating_add(layout.size()) > arena_base + self.b_pos
+        {
+            return;
+        }
+
         self.b_count -= 1;
         // If b_count equals to 0, reset this area.
         if self.b_count == 0 {
@@ -154,8 +262,14 @@ impl<const SIZE: usize> PageAllocator for EarlyAllocator<SIZE> {
             None => return Err(allocator::AllocError::NoMemory), // 空间不足
         };
 
-        // Algin down p_pos
-        let  new_p_pos_aligned = align_down(new_p_pos_unaligned, align);
+        // Align down the address, not the offset: the arena itself may not
+        // be aligned.
+        let arena_base = self.arena.as_ptr() as usize;
+        let phys_addr = align_down(arena_base + new_p_pos_unaligned, align);
+        let new_p_pos_aligned = match phys_addr.checked_sub(arena_base) {
+            Some(pos) => pos,
+            None => return Err(allocator::AllocError::NoMemory),
+        };
 
         // Collision check
         if new_p_pos_aligned < self.b_pos {
@@ -163,8 +277,7 @@ impl<const SIZE: usize> PageAllocator for EarlyAllocator<SIZE> {
         }
 
         self.p_pos = new_p_pos_aligned;
-        
-        let phys_addr = self.arena.as_ptr() as usize + new_p_pos_aligned;
+        self.total_allocs = self.total_allocs.saturating_add(1);
         Ok(phys_addr)
     }
 
@@ -204,4 +317,147 @@ impl<const SIZE: usize> PageAllocator for EarlyAllocator<SIZE> {
     fn available_pages(&self) -> usize {
         self.p_pos.saturating_sub(self.b_pos) / Self::PAGE_SIZE
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::alloc::Layout;
+    use std::boxed::Box;
+
+    use super::*;
+
+    const PAGE: usize = 4096;
+    const SIZE: usize = 4 * PAGE;
+
+    fn new_allocator() -> EarlyAllocator<SIZE> {
+        let mut a = EarlyAllocator::new();
+        a.init(0, SIZE);
+        a
+    }
+
+    /// Creates an allocator whose arena doesn't fit on the test stack.
+    fn boxed_allocator<const N: usize>() -> Box<EarlyAllocator<N>> {
+        // Zero is valid for all the fields, `init` then sets up the areas.
+        let mut a = unsafe {
+            let layout = std::alloc::Layout::new::<EarlyAllocator<N>>();
+            Box::from_raw(std::alloc::alloc_zeroed(layout) as *mut EarlyAllocator<N>)
+        };
+        a.init(0, N);
+        a
+    }
+
+    #[test]
+    fn alloc_pages_aligns_to_huge_pages() {
+        const HUGE: usize = 0x20_0000;
+        let mut a = boxed_allocator::<{ 2 * HUGE + 2 * PAGE }>();
+        a.alloc_pages(1, PAGE).unwrap();
+        let addr = a.alloc_pages(1, HUGE).unwrap();
+        assert_eq!(addr % HUGE, 0);
+        assert!(addr >= a.arena.as_ptr() as usize);
+        assert!(a.used_pages() >= 2);
+    }
+
+    #[test]
+    fn alloc_fails_on_collision() {
+        let mut a = new_allocator();
+        a.alloc_pages(2, PAGE).unwrap();
+        assert!(a.alloc(Layout::from_size_align(2 * PAGE + 1, 1).unwrap()).is_err());
+        assert!(a.alloc(Layout::from_size_align(PAGE / 2, 1).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn alloc_fails_on_count_overflow() {
+        let mut a = new_allocator();
+        a.b_count = usize::MAX;
+        assert!(matches!(
+            a.alloc(Layout::from_size_align(8, 8).unwrap()),
+            Err(allocator::AllocError::NoMemory)
+        ));
+        assert_eq!(a.b_pos, a.b_start);
+    }
+
+    #[test]
+    fn total_allocations_never_decrease() {
+        let mut a = new_allocator();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let ptr = a.alloc(layout).unwrap();
+        a.alloc_pages(1, PAGE).unwrap();
+        assert!(a.alloc(Layout::from_size_align(SIZE, 8).unwrap()).is_err());
+        assert_eq!(a.b_count, 1);
+        a.dealloc(ptr, layout);
+        assert_eq!(a.b_count, 0);
+        assert_eq!(a.total_allocations(), 2);
+    }
+
+    #[test]
+    fn dealloc_ignores_foreign_pointers() {
+        let mut a = new_allocator();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let ptr = a.alloc(layout).unwrap();
+        let page = a.alloc_pages(1, PAGE).unwrap();
+
+        a.dealloc(NonNull::new(page as *mut u8).unwrap(), layout);
+        let past_end = unsafe { NonNull::new_unchecked(ptr.as_ptr().add(16)) };
+        a.dealloc(past_end, layout);
+        assert_eq!(a.b_count, 1);
+
+        a.dealloc(ptr, layout);
+        assert_eq!(a.b_count, 0);
+        assert_eq!(a.b_pos, a.b_start);
+    }
+
+    #[test]
+    fn region_of_used_areas() {
+        let mut a = new_allocator();
+        let bytes = a.alloc(Layout::from_size_align(16, 8).unwrap()).unwrap();
+        let page = NonNull::new(a.alloc_pages(1, PAGE).unwrap() as *mut u8).unwrap();
+        let avail = unsafe { NonNull::new_unchecked(bytes.as_ptr().add(16)) };
+        assert_eq!(a.region_of(bytes), Some(Region::Bytes));
+        assert_eq!(a.region_of(page), Some(Region::Pages));
+        assert_eq!(a.region_of(avail), None);
+    }
+
+    #[test]
+    fn realloc_grows_last_in_place() {
+        let mut a = new_allocator();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let ptr = a.alloc(layout).unwrap();
+        assert_eq!(a.realloc(ptr, layout, 64).unwrap(), ptr);
+        assert_eq!(a.used_bytes(), a.wasted_bytes() + 64);
+        assert!(a.realloc(ptr, Layout::from_size_align(64, 8).unwrap(), SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn realloc_moves_others() {
+        let mut a = new_allocator();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let first = a.alloc(layout).unwrap();
+        unsafe { first.as_ptr().write_bytes(0xab, 16) };
+        a.alloc(layout).unwrap();
+
+        let moved = a.realloc(first, layout, 32).unwrap();
+        assert_ne!(moved, first);
+        let bytes = unsafe { core::slice::from_raw_parts(moved.as_ptr(), 16) };
+        assert!(bytes.iter().all(|&b| b == 0xab));
+        assert_eq!(a.b_count, 2);
+    }
+
+    #[test]
+    fn new_zeroed_reads_zero() {
+        let mut a = EarlyAllocator::<SIZE>::new_zeroed();
+        a.init(0, SIZE);
+        let ptr = a.alloc(Layout::from_size_align(PAGE, 8).unwrap()).unwrap();
+        let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), PAGE) };
+        assert!(bytes.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn alloc_aligns_pointers() {
+        let mut a = new_allocator();
+        a.alloc(Layout::from_size_align(1, 1).unwrap()).unwrap();
+        let ptr = a.alloc(Layout::from_size_align(8, 64).unwrap()).unwrap();
+        assert_eq!(ptr.as_ptr() as usize % 64, 0);
+        assert_eq!(a.used_bytes(), a.wasted_bytes() + 1 + 8);
+    }
+}
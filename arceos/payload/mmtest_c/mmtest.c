@@ -0,0 +1,1136 @@
+/*
+ * Checks of the memory management syscalls of the sys_map kernel.
+ *
+ * Build the kernel with `SYS_MAP_APP=/sbin/mmtest` to run it instead of
+ * mapfile. The run ends with a fault on purpose, after "MmTest ok!", and
+ * the kernel must report the exit code 139.
+ */
+
+#define _GNU_SOURCE
+#include <errno.h>
+#include <pthread.h>
+#include <sched.h>
+#include <signal.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+#include <fcntl.h>
+#include <linux/futex.h>
+#include <linux/membarrier.h>
+#include <linux/stat.h>
+#include <sys/ioctl.h>
+#include <sys/mman.h>
+#include <sys/prctl.h>
+#include <sys/resource.h>
+#include <sys/syscall.h>
+#include <sys/sysinfo.h>
+#include <sys/times.h>
+#include <sys/uio.h>
+
+#define PAGE_SIZE 4096
+#define HUGE_PAGE_SIZE (512 * PAGE_SIZE)
+
+/* Not defined by libc for riscv64, it is the value of x86_64. */
+#ifndef MAP_32BIT
+#define MAP_32BIT 0x40
+#endif
+
+/* The sys_map syscall reading the mmap regions, like /proc/self/maps. */
+#define SYS_DUMP_MAPS 0x1000
+/* The sys_map syscall counting the page faults of the calling thread. */
+#define SYS_PAGE_FAULTS 0x1001
+/* The sys_map syscall counting the calls of an unimplemented syscall. */
+#define SYS_UNIMPLEMENTED_HITS 0x1002
+
+#define CHECK(cond, msg)                                        \
+    do {                                                        \
+        if (!(cond)) {                                          \
+            printf("%s:%d: %s failed!\n", __func__, __LINE__, msg); \
+            exit(-1);                                           \
+        }                                                       \
+    } while (0)
+
+static unsigned long free_ram(void)
+{
+    struct sysinfo si;
+
+    CHECK(sysinfo(&si) == 0, "sysinfo");
+    return si.freeram * si.mem_unit;
+}
+
+static long page_faults(void)
+{
+    return syscall(SYS_PAGE_FAULTS);
+}
+
+/* Returns whether the mmap regions have a region of [addr, addr + len). */
+static int maps_have(void *addr, size_t len, const char *perms)
+{
+    static char maps[16 * PAGE_SIZE];
+    char line[64];
+    long n;
+
+    n = syscall(SYS_DUMP_MAPS, maps, sizeof(maps) - 1);
+    CHECK(n >= 0, "dump maps");
+    maps[n] = 0;
+    snprintf(line, sizeof(line), "%08lx-%08lx %s ", (unsigned long)addr,
+             (unsigned long)addr + len, perms);
+    return strstr(maps, line) != NULL;
+}
+
+static long futex(uint32_t *uaddr, int op, uint32_t val)
+{
+    return syscall(SYS_futex, uaddr, op, val, NULL, NULL, 0);
+}
+
+static long membarrier(int cmd, unsigned int flags)
+{
+    return syscall(SYS_membarrier, cmd, flags);
+}
+
+/* Anonymous mappings are backed lazily, unless MAP_POPULATE is given. */
+void test_populate(void)
+{
+    long faults;
+    char *addr;
+    int populate, i;
+
+    for (populate = 0; populate < 2; populate++) {
+        addr = mmap(NULL, 8 * PAGE_SIZE, PROT_READ | PROT_WRITE,
+                    MAP_PRIVATE | MAP_ANONYMOUS | (populate ? MAP_POPULATE : 0),
+                    -1, 0);
+        CHECK(addr != MAP_FAILED, "mmap");
+        faults = page_faults();
+        for (i = 0; i < 8; i++)
+            addr[i * PAGE_SIZE] = 'a';
+        faults = page_faults() - faults;
+        if (populate)
+            CHECK(faults == 0, "populated pages don't fault");
+        else
+            CHECK(faults >= 8, "lazy pages fault");
+        CHECK(munmap(addr, 8 * PAGE_SIZE) == 0, "munmap");
+    }
+    printf("%s ok\n", __func__);
+}
+
+/* An unmapped range is reused by the next mapping of the same size. */
+void test_reuse(void)
+{
+    char *addr, *again;
+
+    addr = mmap(NULL, 4 * PAGE_SIZE, PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap");
+    CHECK(munmap(addr, 4 * PAGE_SIZE) == 0, "munmap");
+    again = mmap(NULL, 4 * PAGE_SIZE, PROT_READ | PROT_WRITE,
+                 MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(again == addr, "range reused");
+    CHECK(munmap(again, 4 * PAGE_SIZE) == 0, "munmap again");
+    printf("%s ok\n", __func__);
+}
+
+/* Lengths which overflow when rounded up to pages are rejected. The
+ * syscall is made directly, as libc rejects such lengths itself. */
+void test_mmap_overflow(void)
+{
+    long ret;
+
+    errno = 0;
+    ret = syscall(SYS_mmap, NULL, SIZE_MAX, PROT_READ | PROT_WRITE,
+                  MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(ret == -1 && errno == ENOMEM, "length SIZE_MAX");
+    errno = 0;
+    ret = syscall(SYS_mmap, 0x10000000, SIZE_MAX - PAGE_SIZE + 2, PROT_READ | PROT_WRITE,
+                  MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED, -1, 0);
+    CHECK(ret == -1 && errno == ENOMEM, "fixed length near SIZE_MAX");
+    printf("%s ok\n", __func__);
+}
+
+/* Shrinking keeps the base without MREMAP_MAYMOVE and frees the tail. */
+void test_mremap_shrink(void)
+{
+    unsigned long mapped;
+    char *addr;
+
+    addr = mmap(NULL, 4 * PAGE_SIZE, PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS | MAP_POPULATE, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap");
+    memset(addr, 'a', 4 * PAGE_SIZE);
+    mapped = free_ram();
+
+    CHECK(mremap(addr, 4 * PAGE_SIZE, PAGE_SIZE, 0) == addr, "shrink in place");
+    CHECK(addr[0] == 'a' && addr[PAGE_SIZE - 1] == 'a', "first page kept");
+    CHECK(free_ram() > mapped, "tail freed");
+    /* The tail is free again, so the mapping can grow back in place. */
+    CHECK(mremap(addr, PAGE_SIZE, 4 * PAGE_SIZE, 0) == addr, "grow in place");
+    CHECK(munmap(addr, 4 * PAGE_SIZE) == 0, "munmap");
+    printf("%s ok\n", __func__);
+}
+
+/* Huge pages are aligned, keep some access and are freed by munmap. */
+void test_hugetlb(void)
+{
+    unsigned long before;
+    char *addr;
+
+    before = free_ram();
+    addr = mmap(NULL, HUGE_PAGE_SIZE, PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS | MAP_HUGETLB, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap");
+    CHECK((uintptr_t)addr % HUGE_PAGE_SIZE == 0, "2 MiB aligned");
+    addr[0] = 'a';
+    addr[HUGE_PAGE_SIZE - 1] = 'b';
+
+    errno = 0;
+    CHECK(mprotect(addr, HUGE_PAGE_SIZE, PROT_NONE) == -1 && errno == EINVAL,
+          "PROT_NONE refused");
+    errno = 0;
+    CHECK(mprotect(addr, PAGE_SIZE, PROT_READ) == -1 && errno == EINVAL, "split refused");
+    CHECK(mprotect(addr, HUGE_PAGE_SIZE, PROT_READ) == 0, "mprotect READ");
+    CHECK(addr[0] == 'a' && addr[HUGE_PAGE_SIZE - 1] == 'b', "contents kept");
+
+    CHECK(munmap(addr, HUGE_PAGE_SIZE) == 0, "munmap");
+    CHECK(free_ram() + 16 * PAGE_SIZE >= before, "frames freed");
+    printf("%s ok\n", __func__);
+}
+
+/* A page protected to PROT_NONE keeps its frame, which comes back with
+ * the access and is freed by munmap. */
+void test_mprotect_none(void)
+{
+    unsigned long before;
+    char *addr;
+    int i;
+
+    before = free_ram();
+    for (i = 0; i < 64; i++) {
+        addr = mmap(NULL, 3 * PAGE_SIZE, PROT_READ | PROT_WRITE,
+                    MAP_PRIVATE | MAP_ANONYMOUS | MAP_POPULATE, -1, 0);
+        CHECK(addr != MAP_FAILED, "mmap");
+        memset(addr, 'a', 3 * PAGE_SIZE);
+
+        CHECK(mprotect(addr + PAGE_SIZE, PAGE_SIZE, PROT_NONE) == 0, "mprotect NONE");
+        CHECK(addr[0] == 'a' && addr[2 * PAGE_SIZE] == 'a', "outer pages readable");
+        /* The mapping is split, the outer pages keep their own flags. */
+        addr[0] = 'c';
+        addr[2 * PAGE_SIZE] = 'c';
+
+        CHECK(mprotect(addr + PAGE_SIZE, PAGE_SIZE, PROT_READ | PROT_WRITE) == 0,
+              "mprotect RW");
+        CHECK(addr[PAGE_SIZE] == 'a', "contents kept");
+        addr[PAGE_SIZE] = 'b';
+
+        CHECK(mprotect(addr + PAGE_SIZE, PAGE_SIZE, PROT_NONE) == 0, "mprotect NONE again");
+        CHECK(munmap(addr, 3 * PAGE_SIZE) == 0, "munmap");
+    }
+    /* Leave some slack for the kernel's own allocations. */
+    CHECK(free_ram() + 16 * PAGE_SIZE >= before, "frames freed");
+    printf("%s ok\n", __func__);
+}
+
+/* Anonymous memory reads as zero, even where a previous mapping was. */
+void test_anon_zero(void)
+{
+    char *addr, *again;
+    int i, populate;
+
+    for (populate = 0; populate < 2; populate++) {
+        addr = mmap(NULL, 4 * PAGE_SIZE, PROT_READ | PROT_WRITE,
+                    MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+        CHECK(addr != MAP_FAILED, "mmap");
+        memset(addr, 0x5a, 4 * PAGE_SIZE);
+        CHECK(munmap(addr, 4 * PAGE_SIZE) == 0, "munmap");
+
+        again = mmap(addr, 4 * PAGE_SIZE, PROT_READ | PROT_WRITE,
+                     MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED
+                     | (populate ? MAP_POPULATE : 0), -1, 0);
+        CHECK(again == addr, "mmap at the same spot");
+        for (i = 0; i < 4 * PAGE_SIZE; i++)
+            CHECK(again[i] == 0, "zeroed");
+        CHECK(munmap(again, 4 * PAGE_SIZE) == 0, "munmap again");
+    }
+    printf("%s ok\n", __func__);
+}
+
+static uint32_t futex_word;
+
+static void *futex_waker(void *arg)
+{
+    (void)arg;
+    __atomic_store_n(&futex_word, 1, __ATOMIC_SEQ_CST);
+    futex(&futex_word, FUTEX_WAKE_PRIVATE, 1);
+    return NULL;
+}
+
+/* Waits return at once on a stale value, and wake up on FUTEX_WAKE. */
+void test_futex(void)
+{
+    pthread_t thread;
+    uint32_t word = 1;
+
+    errno = 0;
+    CHECK(futex(&word, FUTEX_WAIT_PRIVATE, 0) == -1 && errno == EAGAIN, "stale value");
+    errno = 0;
+    CHECK(futex((uint32_t *)((char *)&word + 1), FUTEX_WAKE_PRIVATE, 1) == -1
+          && errno == EINVAL, "misaligned");
+    CHECK(futex(&word, FUTEX_WAKE_PRIVATE, 1) == 0, "no waiter");
+
+    futex_word = 0;
+    CHECK(pthread_create(&thread, NULL, futex_waker, NULL) == 0, "pthread_create");
+    while (__atomic_load_n(&futex_word, __ATOMIC_SEQ_CST) == 0)
+        futex(&futex_word, FUTEX_WAIT_PRIVATE, 0);
+    CHECK(pthread_join(thread, NULL) == 0, "pthread_join");
+    printf("%s ok\n", __func__);
+}
+
+static int clone_shared;
+static char clone_stack[16 * PAGE_SIZE] __attribute__((aligned(16)));
+
+static int clone_child(void *arg)
+{
+    clone_shared = *(int *)arg;
+    return 0;
+}
+
+/* A thread made by clone shares the memory of its parent, which joins it
+ * through the futex on the cleared child tid. */
+void test_clone(void)
+{
+    int flags = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD
+                | CLONE_SYSVSEM | CLONE_PARENT_SETTID | CLONE_CHILD_SETTID
+                | CLONE_CHILD_CLEARTID;
+    int value = 42, ptid = 0, ctid, tid;
+
+    tid = clone(clone_child, clone_stack + sizeof(clone_stack), flags, &value,
+                &ptid, NULL, &ctid);
+    CHECK(tid > 0, "clone");
+    CHECK(ptid == tid, "parent tid set");
+    while ((tid = __atomic_load_n(&ctid, __ATOMIC_SEQ_CST)) != 0)
+        futex((uint32_t *)&ctid, FUTEX_WAIT, tid);
+    CHECK(clone_shared == 42, "child write seen");
+    printf("%s ok\n", __func__);
+}
+
+/* Opening files or duplicating past RLIMIT_NOFILE fails. */
+void test_rlimit_nofile(void)
+{
+    struct rlimit old, lim;
+    int fds[8], n, fd, i;
+
+    CHECK(getrlimit(RLIMIT_NOFILE, &old) == 0, "getrlimit");
+    lim.rlim_cur = 8;
+    lim.rlim_max = old.rlim_max;
+    CHECK(setrlimit(RLIMIT_NOFILE, &lim) == 0, "setrlimit");
+    CHECK(getrlimit(RLIMIT_NOFILE, &lim) == 0 && lim.rlim_cur == 8, "limit lowered");
+
+    for (n = 0; n < 8; n++) {
+        fd = open("/sbin/mmtest", O_RDONLY);
+        if (fd < 0)
+            break;
+        CHECK(fd < 8, "fd below the limit");
+        fds[n] = fd;
+    }
+    CHECK(n < 8 && errno == EMFILE, "open past the limit");
+    errno = 0;
+    CHECK(fcntl(0, F_DUPFD, 0) == -1 && errno == EMFILE, "F_DUPFD past the limit");
+    errno = 0;
+    CHECK(fcntl(0, F_DUPFD, 8) == -1 && errno == EINVAL, "F_DUPFD minimum at the limit");
+
+    for (i = 0; i < n; i++)
+        close(fds[i]);
+    CHECK(setrlimit(RLIMIT_NOFILE, &old) == 0, "restore limit");
+
+    lim.rlim_cur = 9;
+    lim.rlim_max = 8;
+    errno = 0;
+    CHECK(setrlimit(RLIMIT_NOFILE, &lim) == -1 && errno == EINVAL,
+          "soft limit above the hard one");
+    printf("%s ok\n", __func__);
+}
+
+/* The cwd is an absolute path, the buffer must hold it and its NUL. */
+void test_getcwd(void)
+{
+    char buf[256];
+
+    CHECK(getcwd(buf, sizeof(buf)) == buf, "getcwd");
+    CHECK(buf[0] == '/', "absolute path");
+    errno = 0;
+    CHECK(getcwd(buf, 1) == NULL && errno == ERANGE, "buffer too small");
+    errno = 0;
+    CHECK(syscall(SYS_getcwd, NULL, sizeof(buf)) == -1 && errno == EFAULT, "NULL buffer");
+    printf("%s ok\n", __func__);
+}
+
+/* Files are owned by the only user, with the owner bits of the fs. */
+void test_faccessat(void)
+{
+    CHECK(access("/sbin/mmtest", F_OK) == 0, "F_OK");
+    CHECK(access("/sbin/mmtest", R_OK | X_OK) == 0, "R_OK | X_OK");
+    errno = 0;
+    CHECK(access("/sbin/no_such_file", F_OK) == -1 && errno == ENOENT, "missing file");
+    errno = 0;
+    CHECK(access("/sbin/mmtest", 0x10) == -1 && errno == EINVAL, "invalid mode");
+    errno = 0;
+    CHECK(faccessat(0, "mmtest", F_OK, 0) == -1 && errno == EINVAL, "dfd not AT_FDCWD");
+    printf("%s ok\n", __func__);
+}
+
+/* Duplicates get the lowest free fd from the minimum, F_SETFL keeps the
+ * access mode. */
+void test_fcntl(void)
+{
+    int fd, dup;
+
+    fd = open("/sbin/mmtest", O_RDONLY);
+    CHECK(fd >= 0, "open");
+    CHECK((fcntl(fd, F_GETFL) & O_ACCMODE) == O_RDONLY, "F_GETFL");
+    CHECK(fcntl(fd, F_SETFL, O_RDWR | O_APPEND) == 0, "F_SETFL");
+    CHECK((fcntl(fd, F_GETFL) & (O_ACCMODE | O_APPEND)) == (O_RDONLY | O_APPEND),
+          "only O_APPEND set");
+
+    dup = fcntl(fd, F_DUPFD, 10);
+    CHECK(dup >= 10, "F_DUPFD");
+    CHECK(fcntl(dup, F_GETFL) & O_APPEND, "status flags copied");
+    CHECK(fcntl(dup, F_GETFD) == 0, "no FD_CLOEXEC");
+    CHECK(close(dup) == 0, "close dup");
+
+    dup = fcntl(fd, F_DUPFD_CLOEXEC, 10);
+    CHECK(dup >= 10, "F_DUPFD_CLOEXEC");
+    CHECK(fcntl(dup, F_GETFD) == FD_CLOEXEC, "FD_CLOEXEC");
+    CHECK(fcntl(dup, F_SETFD, 0) == 0 && fcntl(dup, F_GETFD) == 0, "F_SETFD");
+    CHECK(close(dup) == 0, "close dup");
+
+    errno = 0;
+    CHECK(fcntl(fd, F_DUPFD, -1) == -1 && errno == EINVAL, "negative minimum");
+    CHECK(close(fd) == 0, "close");
+    errno = 0;
+    CHECK(fcntl(fd, F_GETFL) == -1 && errno == EBADF, "closed fd");
+    printf("%s ok\n", __func__);
+}
+
+/* Signals are blocked and unblocked, except SIGKILL and SIGSTOP which are
+ * silently kept unblocked. */
+void test_sigprocmask(void)
+{
+    sigset_t set, old, cur;
+    uint64_t mask = 0;
+
+    sigemptyset(&set);
+    sigaddset(&set, SIGUSR1);
+    sigaddset(&set, SIGKILL);
+    CHECK(sigprocmask(SIG_BLOCK, &set, &old) == 0, "SIG_BLOCK");
+    CHECK(sigprocmask(SIG_BLOCK, NULL, &cur) == 0, "get the mask");
+    CHECK(sigismember(&cur, SIGUSR1) && !sigismember(&cur, SIGKILL), "SIGUSR1 blocked");
+
+    sigemptyset(&set);
+    sigaddset(&set, SIGUSR1);
+    CHECK(sigprocmask(SIG_UNBLOCK, &set, &cur) == 0, "SIG_UNBLOCK");
+    CHECK(sigismember(&cur, SIGUSR1), "old mask returned");
+    CHECK(sigprocmask(SIG_SETMASK, &old, &cur) == 0, "SIG_SETMASK");
+    CHECK(!sigismember(&cur, SIGUSR1), "SIGUSR1 unblocked");
+
+    errno = 0;
+    CHECK(syscall(SYS_rt_sigprocmask, SIG_BLOCK, NULL, &mask, 4) == -1 && errno == EINVAL,
+          "sigsetsize");
+    errno = 0;
+    CHECK(syscall(SYS_rt_sigprocmask, 3, &mask, NULL, sizeof(mask)) == -1 && errno == EINVAL,
+          "invalid how");
+    printf("%s ok\n", __func__);
+}
+
+static void *exit_writer(void *arg)
+{
+    memset(arg, 'a', 4 * PAGE_SIZE);
+    return NULL;
+}
+
+/* The mappings go away with the last thread, not with the first to exit. */
+void test_thread_exit(void)
+{
+    pthread_t thread;
+    char *addr;
+    int i;
+
+    addr = mmap(NULL, 4 * PAGE_SIZE, PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap");
+    CHECK(pthread_create(&thread, NULL, exit_writer, addr) == 0, "pthread_create");
+    CHECK(pthread_join(thread, NULL) == 0, "pthread_join");
+    for (i = 0; i < 4 * PAGE_SIZE; i++)
+        CHECK(addr[i] == 'a', "mapping kept");
+    CHECK(munmap(addr, 4 * PAGE_SIZE) == 0, "munmap");
+    printf("%s ok\n", __func__);
+}
+
+/* Fixed ranges which wrap around or run past the address space fail. */
+void test_fixed_overflow(void)
+{
+    uintptr_t top = UINTPTR_MAX - 16 * PAGE_SIZE + 1;
+
+    errno = 0;
+    CHECK(mmap((void *)top, 32 * PAGE_SIZE, PROT_READ | PROT_WRITE,
+               MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED, -1, 0) == MAP_FAILED
+          && errno == ENOMEM, "wrapping range");
+    errno = 0;
+    CHECK(mmap((void *)top, PAGE_SIZE, PROT_READ | PROT_WRITE,
+               MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED, -1, 0) == MAP_FAILED
+          && errno == ENOMEM, "range past the address space");
+    errno = 0;
+    CHECK(munmap((void *)top, 32 * PAGE_SIZE) == -1 && errno == EINVAL, "munmap wrapping range");
+    printf("%s ok\n", __func__);
+}
+
+/* The default vm.max_map_count of Linux, the limit of sys_map. */
+#define MAX_MAP_COUNT 65530
+
+/* Mappings past the limit fail with ENOMEM. They are fixed side by side in
+ * an unused part of the address space, regions are never merged. */
+void test_max_map_count(void)
+{
+    char *base = (char *)(64UL << 30), *addr;
+    int n;
+
+    for (n = 0; n <= MAX_MAP_COUNT; n++) {
+        addr = mmap(base + n * PAGE_SIZE, PAGE_SIZE, PROT_READ,
+                    MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED, -1, 0);
+        if (addr == MAP_FAILED)
+            break;
+        CHECK(addr == base + n * PAGE_SIZE, "mmap fixed");
+    }
+    CHECK(n <= MAX_MAP_COUNT && errno == ENOMEM, "mmap past the limit");
+    /* libc may have mappings of its own. */
+    CHECK(n > MAX_MAP_COUNT - 64, "mmap below the limit");
+    CHECK(munmap(base, n * PAGE_SIZE) == 0, "munmap");
+    addr = mmap(NULL, PAGE_SIZE, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap after munmap");
+    CHECK(munmap(addr, PAGE_SIZE) == 0, "munmap again");
+    printf("%s ok\n", __func__);
+}
+
+/* The dump has a line per region, with its range and permissions. */
+void test_dump_maps(void)
+{
+    char *addr, *shared;
+
+    addr = mmap(NULL, 2 * PAGE_SIZE, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap");
+    shared = mmap(NULL, PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_SHARED | MAP_ANONYMOUS, -1, 0);
+    CHECK(shared != MAP_FAILED, "mmap shared");
+    CHECK(maps_have(addr, 2 * PAGE_SIZE, "r--p"), "private region listed");
+    CHECK(maps_have(shared, PAGE_SIZE, "rw-s"), "shared region listed");
+    errno = 0;
+    CHECK(syscall(SYS_DUMP_MAPS, addr, 4) == -1 && errno == EFAULT, "read-only buffer");
+
+    CHECK(munmap(addr, 2 * PAGE_SIZE) == 0, "munmap");
+    CHECK(!maps_have(addr, 2 * PAGE_SIZE, "r--p"), "unmapped region gone");
+    CHECK(munmap(shared, PAGE_SIZE) == 0, "munmap shared");
+    printf("%s ok\n", __func__);
+}
+
+/* Each mmap records a region, with the permissions it was mapped with. */
+void test_regions(void)
+{
+    char *first, *second;
+
+    first = mmap(NULL, PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(first != MAP_FAILED, "mmap first");
+    second = mmap(NULL, 3 * PAGE_SIZE, PROT_READ | PROT_EXEC, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(second != MAP_FAILED, "mmap second");
+    CHECK(maps_have(first, PAGE_SIZE, "rw-p"), "first region recorded");
+    CHECK(maps_have(second, 3 * PAGE_SIZE, "r-xp"), "second region recorded");
+    CHECK(munmap(first, PAGE_SIZE) == 0 && munmap(second, 3 * PAGE_SIZE) == 0, "munmap");
+    printf("%s ok\n", __func__);
+}
+
+/* Unmapping the middle of a region leaves its two ends mapped. */
+void test_munmap_split(void)
+{
+    char *addr;
+
+    addr = mmap(NULL, 4 * PAGE_SIZE, PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS | MAP_POPULATE, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap");
+    memset(addr, 'a', 4 * PAGE_SIZE);
+    CHECK(munmap(addr + PAGE_SIZE, 2 * PAGE_SIZE) == 0, "munmap middle");
+    CHECK(maps_have(addr, PAGE_SIZE, "rw-p"), "head kept");
+    CHECK(maps_have(addr + 3 * PAGE_SIZE, PAGE_SIZE, "rw-p"), "tail kept");
+    CHECK(addr[0] == 'a' && addr[4 * PAGE_SIZE - 1] == 'a', "contents kept");
+
+    /* The hole can be mapped again, between the two ends. */
+    CHECK(mmap(addr + PAGE_SIZE, 2 * PAGE_SIZE, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED,
+               -1, 0) == addr + PAGE_SIZE, "mmap the hole");
+    CHECK(addr[PAGE_SIZE] == 0, "hole zeroed");
+    CHECK(munmap(addr, 4 * PAGE_SIZE) == 0, "munmap");
+    CHECK(!maps_have(addr, PAGE_SIZE, "rw-p"), "head gone");
+    printf("%s ok\n", __func__);
+}
+
+/* Syscalls without a handler fail with ENOSYS, both between handled
+ * numbers and past the last one. */
+void test_dispatch(void)
+{
+    CHECK(syscall(SYS_getpid) > 0, "getpid");
+    CHECK(syscall(SYS_PAGE_FAULTS) >= 0, "last handler");
+    errno = 0;
+    CHECK(syscall(SYS_getpid - 1) == -1 && errno == ENOSYS, "unknown below a handler");
+    errno = 0;
+    CHECK(syscall(SYS_PAGE_FAULTS + 0x1000) == -1 && errno == ENOSYS, "unknown past the table");
+    errno = 0;
+    CHECK(syscall(0) == -1 && errno == ENOSYS, "unknown before the table");
+    printf("%s ok\n", __func__);
+}
+
+/* Each call of an unimplemented syscall is counted by its number. */
+void test_unimplemented_hits(void)
+{
+    long first = 0x1f00, second = 0x1f01;
+
+    CHECK(syscall(SYS_UNIMPLEMENTED_HITS, first) == 0, "no hit yet");
+    CHECK(syscall(first) == -1 && errno == ENOSYS, "first unknown");
+    CHECK(syscall(second) == -1 && errno == ENOSYS, "second unknown");
+    CHECK(syscall(SYS_UNIMPLEMENTED_HITS, first) == 1, "first counted");
+    CHECK(syscall(SYS_UNIMPLEMENTED_HITS, second) == 1, "second counted");
+    syscall(first);
+    CHECK(syscall(SYS_UNIMPLEMENTED_HITS, first) == 2, "first counted again");
+    CHECK(syscall(SYS_UNIMPLEMENTED_HITS, SYS_getpid) == 0, "implemented not counted");
+    printf("%s ok\n", __func__);
+}
+
+/* Nothing is ever swapped out, locking only checks the range is mapped. */
+void test_mlock(void)
+{
+    char *addr;
+
+    addr = mmap(NULL, 2 * PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap");
+    CHECK(mlock(addr + 1, PAGE_SIZE) == 0, "mlock");
+    CHECK(munlock(addr, 2 * PAGE_SIZE) == 0, "munlock");
+    CHECK(munmap(addr + PAGE_SIZE, PAGE_SIZE) == 0, "munmap second page");
+    errno = 0;
+    CHECK(mlock(addr, 2 * PAGE_SIZE) == -1 && errno == ENOMEM, "mlock unmapped page");
+    errno = 0;
+    CHECK(munlock(addr + PAGE_SIZE, PAGE_SIZE) == -1 && errno == ENOMEM, "munlock unmapped page");
+    CHECK(munmap(addr, PAGE_SIZE) == 0, "munmap");
+
+    CHECK(mlockall(MCL_CURRENT | MCL_FUTURE) == 0, "mlockall");
+    errno = 0;
+    CHECK(mlockall(0) == -1 && errno == EINVAL, "mlockall no flag");
+    errno = 0;
+    CHECK(mlockall(MCL_ONFAULT) == -1 && errno == EINVAL, "mlockall MCL_ONFAULT alone");
+    printf("%s ok\n", __func__);
+}
+
+/* Shared writes go to the file, which must be open for writing then. */
+void test_shared_readonly(void)
+{
+    char *addr;
+    int fd;
+
+    fd = open("/sbin/mmtest", O_RDONLY);
+    CHECK(fd >= 0, "open");
+    errno = 0;
+    CHECK(mmap(NULL, PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0) == MAP_FAILED
+          && errno == EACCES, "shared writable");
+    addr = mmap(NULL, PAGE_SIZE, PROT_READ, MAP_SHARED, fd, 0);
+    CHECK(addr != MAP_FAILED, "shared read-only");
+    CHECK(munmap(addr, PAGE_SIZE) == 0, "munmap shared");
+    addr = mmap(NULL, PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE, fd, 0);
+    CHECK(addr != MAP_FAILED, "private writable");
+    addr[0] = 'a';
+    CHECK(munmap(addr, PAGE_SIZE) == 0, "munmap private");
+    CHECK(close(fd) == 0, "close");
+    printf("%s ok\n", __func__);
+}
+
+/* Exactly one of MAP_PRIVATE and MAP_SHARED must be given. */
+void test_private_shared(void)
+{
+    errno = 0;
+    CHECK(mmap(NULL, PAGE_SIZE, PROT_READ, MAP_PRIVATE | MAP_SHARED | MAP_ANONYMOUS, -1, 0)
+          == MAP_FAILED && errno == EINVAL, "both");
+    errno = 0;
+    CHECK(mmap(NULL, PAGE_SIZE, PROT_READ, MAP_ANONYMOUS, -1, 0) == MAP_FAILED
+          && errno == EINVAL, "neither");
+    printf("%s ok\n", __func__);
+}
+
+/* The console has a fixed window size, FIONBIO sets O_NONBLOCK. */
+void test_ioctl(void)
+{
+    struct winsize ws;
+    int fd, on = 1;
+
+    CHECK(ioctl(1, TIOCGWINSZ, &ws) == 0, "TIOCGWINSZ");
+    CHECK(ws.ws_row == 24 && ws.ws_col == 80, "window size");
+
+    fd = open("/sbin/mmtest", O_RDONLY);
+    CHECK(fd >= 0, "open");
+    errno = 0;
+    CHECK(ioctl(fd, TIOCGWINSZ, &ws) == -1 && errno == ENOTTY, "file not a tty");
+    CHECK(ioctl(fd, FIONBIO, &on) == 0, "FIONBIO on");
+    CHECK(fcntl(fd, F_GETFL) & O_NONBLOCK, "O_NONBLOCK set");
+    on = 0;
+    CHECK(ioctl(fd, FIONBIO, &on) == 0, "FIONBIO off");
+    CHECK(!(fcntl(fd, F_GETFL) & O_NONBLOCK), "O_NONBLOCK cleared");
+    CHECK(close(fd) == 0, "close");
+    errno = 0;
+    CHECK(ioctl(fd, FIONBIO, &on) == -1 && errno == EBADF, "closed fd");
+    printf("%s ok\n", __func__);
+}
+
+/* A fault the kernel can't handle kills the process, as SIGSEGV would
+ * without a handler: the kernel reports the exit code 139. */
+void test_segv(void)
+{
+    volatile char *addr;
+
+    addr = mmap(NULL, PAGE_SIZE, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap");
+    printf("%s: writing to a read-only page, expect exit code 139\n", __func__);
+    addr[0] = 'a';
+    CHECK(0, "write to a read-only page");
+}
+
+/* File mappings need an open fd. */
+void test_mmap_bad_fd(void)
+{
+    int fd;
+
+    errno = 0;
+    CHECK(mmap(NULL, PAGE_SIZE, PROT_READ, MAP_PRIVATE, -1, 0) == MAP_FAILED
+          && errno == EBADF, "negative fd");
+    fd = open("/sbin/mmtest", O_RDONLY);
+    CHECK(fd >= 0 && close(fd) == 0, "open and close");
+    errno = 0;
+    CHECK(mmap(NULL, PAGE_SIZE, PROT_READ, MAP_PRIVATE, fd, 0) == MAP_FAILED
+          && errno == EBADF, "closed fd");
+    printf("%s ok\n", __func__);
+}
+
+static void *shared_writer(void *arg)
+{
+    strcpy(arg, "shared");
+    return NULL;
+}
+
+/* Shared anonymous memory starts zeroed, is populated at once and is
+ * shared by the threads. */
+void test_shared_anon(void)
+{
+    pthread_t thread;
+    long faults;
+    char *addr;
+
+    addr = mmap(NULL, 2 * PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_SHARED | MAP_ANONYMOUS, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap");
+    faults = page_faults();
+    CHECK(addr[0] == 0 && addr[2 * PAGE_SIZE - 1] == 0, "zeroed");
+    CHECK(page_faults() == faults, "populated");
+    CHECK(pthread_create(&thread, NULL, shared_writer, addr) == 0, "pthread_create");
+    CHECK(pthread_join(thread, NULL) == 0, "pthread_join");
+    CHECK(strcmp(addr, "shared") == 0, "write seen");
+    CHECK(munmap(addr, 2 * PAGE_SIZE) == 0, "munmap");
+    printf("%s ok\n", __func__);
+}
+
+/* Basic stats are reported by path or by fd, the reserved mask bit is
+ * refused. */
+void test_statx(void)
+{
+    struct statx stx;
+    uint64_t size;
+    int fd;
+
+    memset(&stx, 0xff, sizeof(stx));
+    CHECK(syscall(SYS_statx, AT_FDCWD, "/sbin/mmtest", 0, STATX_BASIC_STATS, &stx) == 0,
+          "statx by path");
+    CHECK((stx.stx_mask & STATX_BASIC_STATS & ~STATX_ATIME & ~STATX_MTIME & ~STATX_CTIME)
+          == (STATX_BASIC_STATS & ~STATX_ATIME & ~STATX_MTIME & ~STATX_CTIME), "mask");
+    CHECK((stx.stx_mode & S_IFMT) == S_IFREG && stx.stx_size > 0, "regular file");
+    size = stx.stx_size;
+
+    fd = open("/sbin/mmtest", O_RDONLY);
+    CHECK(fd >= 0, "open");
+    memset(&stx, 0, sizeof(stx));
+    CHECK(syscall(SYS_statx, fd, "", AT_EMPTY_PATH, STATX_SIZE, &stx) == 0, "statx by fd");
+    CHECK(stx.stx_size == size, "size by fd");
+    errno = 0;
+    CHECK(syscall(SYS_statx, fd, "mmtest", 0, STATX_SIZE, &stx) == -1 && errno == EINVAL,
+          "relative to a fd");
+    CHECK(close(fd) == 0, "close");
+
+    errno = 0;
+    CHECK(syscall(SYS_statx, AT_FDCWD, "/sbin/mmtest", 0, STATX__RESERVED, &stx) == -1
+          && errno == EINVAL, "reserved mask");
+    errno = 0;
+    CHECK(syscall(SYS_statx, AT_FDCWD, "/sbin/no_such_file", 0, STATX_SIZE, &stx) == -1
+          && errno == ENOENT, "missing file");
+    printf("%s ok\n", __func__);
+}
+
+/* Bad user pointers make syscalls fail with EFAULT, lazy pages are faulted
+ * in for them. */
+void test_user_access(void)
+{
+    char *addr;
+    int fd;
+
+    addr = mmap(NULL, 2 * PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap");
+    fd = open("/sbin/mmtest", O_RDONLY);
+    CHECK(fd >= 0, "open");
+    CHECK(read(fd, addr + PAGE_SIZE - 2, 4) == 4, "read into lazy pages");
+    CHECK(memcmp(addr + PAGE_SIZE - 2, "\177ELF", 4) == 0, "read contents");
+
+    CHECK(mprotect(addr, PAGE_SIZE, PROT_READ) == 0, "mprotect READ");
+    errno = 0;
+    CHECK(read(fd, addr, 4) == -1 && errno == EFAULT, "read into a read-only page");
+    errno = 0;
+    CHECK(syscall(SYS_getrlimit, RLIMIT_NOFILE, addr) == -1 && errno == EFAULT,
+          "getrlimit into a read-only page");
+    CHECK(munmap(addr, 2 * PAGE_SIZE) == 0, "munmap");
+
+    errno = 0;
+    CHECK(write(1, addr, 4) == -1 && errno == EFAULT, "write from unmapped memory");
+    errno = 0;
+    CHECK(syscall(SYS_setrlimit, RLIMIT_NOFILE, addr) == -1 && errno == EFAULT,
+          "setrlimit from unmapped memory");
+    errno = 0;
+    CHECK(syscall(SYS_sysinfo, addr) == -1 && errno == EFAULT, "sysinfo to unmapped memory");
+    CHECK(close(fd) == 0, "close");
+    printf("%s ok\n", __func__);
+}
+
+/* writev stops at the first bad buffer, and returns what was written
+ * before it. */
+void test_writev_partial(void)
+{
+    struct iovec iov[3];
+    char buf[8];
+    char *bad;
+    int fd;
+
+    bad = mmap(NULL, PAGE_SIZE, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(bad != MAP_FAILED && munmap(bad, PAGE_SIZE) == 0, "unmapped page");
+    fd = open("mmtest_writev", O_RDWR | O_CREAT | O_TRUNC, 0600);
+    CHECK(fd >= 0, "open");
+
+    iov[0].iov_base = "abc";
+    iov[0].iov_len = 3;
+    iov[1].iov_base = bad;
+    iov[1].iov_len = 0;
+    iov[2].iov_base = "de";
+    iov[2].iov_len = 2;
+    CHECK(writev(fd, iov, 3) == 5, "empty buffer skipped");
+    iov[1].iov_len = 4;
+    CHECK(writev(fd, iov, 3) == 3, "stop at the bad buffer");
+    errno = 0;
+    CHECK(writev(fd, iov + 1, 2) == -1 && errno == EFAULT, "first buffer bad");
+    errno = 0;
+    CHECK(syscall(SYS_writev, fd, iov, 1025) == -1 && errno == EINVAL, "more than IOV_MAX");
+
+    CHECK(close(fd) == 0, "close");
+
+    fd = open("mmtest_writev", O_RDONLY);
+    CHECK(fd >= 0, "open again");
+    CHECK(read(fd, buf, sizeof(buf)) == 8 && memcmp(buf, "abcdeabc", 8) == 0, "contents");
+    CHECK(close(fd) == 0, "close again");
+    printf("%s ok\n", __func__);
+}
+
+/* O_CREAT creates missing files, writes go to the end with O_APPEND and to
+ * the offset without. */
+void test_open_create_append(void)
+{
+    char buf[8];
+    int fd;
+
+    errno = 0;
+    CHECK(open("/sbin/no_such_file", O_WRONLY) == -1 && errno == ENOENT, "missing file");
+    /* The file may be left by a previous run, there is no unlink. */
+    fd = open("mmtest_append", O_WRONLY | O_CREAT | O_TRUNC, 0600);
+    CHECK(fd >= 0, "O_CREAT");
+    CHECK(!(fcntl(fd, F_GETFL) & O_CREAT), "O_CREAT not a status flag");
+    CHECK(write(fd, "ab", 2) == 2 && close(fd) == 0, "write");
+    errno = 0;
+    CHECK(open("mmtest_append", O_WRONLY | O_CREAT | O_EXCL, 0600) == -1 && errno == EEXIST,
+          "O_EXCL on an existing file");
+
+    fd = open("mmtest_append", O_WRONLY | O_APPEND);
+    CHECK(fd >= 0, "O_APPEND");
+    CHECK(fcntl(fd, F_GETFL) & O_APPEND, "O_APPEND a status flag");
+    CHECK(write(fd, "cd", 2) == 2 && close(fd) == 0, "append");
+    fd = open("mmtest_append", O_WRONLY);
+    CHECK(fd >= 0, "open O_WRONLY");
+    CHECK(write(fd, "x", 1) == 1 && close(fd) == 0, "write at the start");
+
+    fd = open("mmtest_append", O_RDONLY);
+    CHECK(fd >= 0, "open");
+    CHECK(read(fd, buf, sizeof(buf)) == 4 && memcmp(buf, "xbcd", 4) == 0, "contents");
+    CHECK(close(fd) == 0, "close");
+    printf("%s ok\n", __func__);
+}
+
+/* The CPU times only grow, and the user time with the work done. */
+void test_times(void)
+{
+    struct tms before, after;
+    clock_t start, end;
+    volatile unsigned long n = 0;
+    int i;
+
+    start = times(&before);
+    CHECK(start != (clock_t)-1, "times");
+    /* Spin in user space for some ticks. */
+    do {
+        for (i = 0; i < 1000000; i++)
+            n++;
+        end = times(&after);
+    } while (end - start < 10);
+    CHECK(after.tms_utime > before.tms_utime, "user time spent");
+    CHECK(after.tms_stime >= before.tms_stime, "system time monotonic");
+    CHECK(after.tms_cutime == 0 && after.tms_cstime == 0, "no children");
+    CHECK(times(NULL) >= end, "NULL buffer");
+    errno = 0;
+    CHECK(syscall(SYS_times, (void *)8) == -1 && errno == EFAULT, "bad buffer");
+    printf("%s ok\n", __func__);
+}
+
+/* Everything runs as root. */
+void test_ids(void)
+{
+    CHECK(getuid() == 0 && geteuid() == 0, "user ids");
+    CHECK(getgid() == 0 && getegid() == 0, "group ids");
+    printf("%s ok\n", __func__);
+}
+
+/* /proc/self/exe is the only link, its target isn't NUL-terminated. */
+void test_readlink(void)
+{
+    char buf[64];
+
+    memset(buf, 'x', sizeof(buf));
+    CHECK(readlink("/proc/self/exe", buf, sizeof(buf)) == 12, "readlink");
+    CHECK(memcmp(buf, "/sbin/mmtest", 12) == 0 && buf[12] == 'x', "exe path");
+    CHECK(readlink("/proc/self/exe", buf, 5) == 5 && memcmp(buf, "/sbin", 5) == 0, "truncated");
+    errno = 0;
+    CHECK(readlink("/sbin/mmtest", buf, sizeof(buf)) == -1 && errno == EINVAL, "not a link");
+    errno = 0;
+    CHECK(readlink("/sbin/no_such_file", buf, sizeof(buf)) == -1 && errno == ENOENT,
+          "missing file");
+    errno = 0;
+    CHECK(syscall(SYS_readlinkat, AT_FDCWD, "/proc/self/exe", buf, 0) == -1 && errno == EINVAL,
+          "empty buffer");
+    printf("%s ok\n", __func__);
+}
+
+static pid_t thread_ppid;
+
+static void *ppid_reader(void *arg)
+{
+    (void)arg;
+    thread_ppid = getppid();
+    return NULL;
+}
+
+/* The first process is started by the kernel, which has pid 0. Threads
+ * share the parent of their process. */
+void test_getppid(void)
+{
+    pthread_t thread;
+
+    CHECK(getppid() == 0, "getppid");
+    thread_ppid = -1;
+    CHECK(pthread_create(&thread, NULL, ppid_reader, NULL) == 0, "pthread_create");
+    CHECK(pthread_join(thread, NULL) == 0, "pthread_join");
+    CHECK(thread_ppid == 0, "getppid in a thread");
+    printf("%s ok\n", __func__);
+}
+
+/* Thread names are truncated to 15 bytes and NUL-terminated. */
+void test_prctl_name(void)
+{
+    char name[16];
+
+    CHECK(prctl(PR_SET_NAME, "mmtest") == 0, "PR_SET_NAME");
+    memset(name, 'x', sizeof(name));
+    CHECK(prctl(PR_GET_NAME, name) == 0 && strcmp(name, "mmtest") == 0, "PR_GET_NAME");
+    CHECK(prctl(PR_SET_NAME, "a-name-longer-than-15") == 0, "PR_SET_NAME long");
+    CHECK(prctl(PR_GET_NAME, name) == 0 && strcmp(name, "a-name-longer-t") == 0, "truncated");
+    errno = 0;
+    CHECK(prctl(PR_GET_DUMPABLE) == -1 && errno == EINVAL, "other option");
+    printf("%s ok\n", __func__);
+}
+
+/* The robust list head is kept for the thread, as it was set. */
+void test_robust_list(void)
+{
+    struct {
+        void *next;
+        long futex_offset;
+        void *pending;
+    } head = { &head, 0, NULL };
+    void *old_head, *got;
+    size_t len;
+
+    CHECK(syscall(SYS_get_robust_list, 0, &old_head, &len) == 0, "get old list");
+    CHECK(syscall(SYS_set_robust_list, &head, sizeof(head)) == 0, "set_robust_list");
+    CHECK(syscall(SYS_get_robust_list, getpid(), &got, &len) == 0, "get_robust_list");
+    CHECK(got == &head && len == sizeof(head), "list kept");
+    errno = 0;
+    CHECK(syscall(SYS_set_robust_list, &head, sizeof(head) - 1) == -1 && errno == EINVAL,
+          "wrong head size");
+    errno = 0;
+    CHECK(syscall(SYS_get_robust_list, getpid() + 1, &got, &len) == -1 && errno == ESRCH,
+          "other process");
+    CHECK(syscall(SYS_set_robust_list, old_head, sizeof(head)) == 0, "restore");
+    printf("%s ok\n", __func__);
+}
+
+/* The private expedited barrier needs the process to register first. */
+void test_membarrier(void)
+{
+    long cmds;
+
+    cmds = membarrier(MEMBARRIER_CMD_QUERY, 0);
+    CHECK(cmds > 0, "MEMBARRIER_CMD_QUERY");
+    CHECK(cmds & MEMBARRIER_CMD_GLOBAL && cmds & MEMBARRIER_CMD_PRIVATE_EXPEDITED, "commands");
+    CHECK(membarrier(MEMBARRIER_CMD_GLOBAL, 0) == 0, "MEMBARRIER_CMD_GLOBAL");
+    errno = 0;
+    CHECK(membarrier(MEMBARRIER_CMD_PRIVATE_EXPEDITED, 0) == -1 && errno == EPERM,
+          "private expedited unregistered");
+    CHECK(membarrier(MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED, 0) == 0, "register");
+    CHECK(membarrier(MEMBARRIER_CMD_PRIVATE_EXPEDITED, 0) == 0, "private expedited");
+    errno = 0;
+    CHECK(membarrier(MEMBARRIER_CMD_GLOBAL, 1) == -1 && errno == EINVAL, "flags");
+    errno = 0;
+    CHECK(membarrier(1 << 30, 0) == -1 && errno == EINVAL, "unknown command");
+    printf("%s ok\n", __func__);
+}
+
+/* MAP_32BIT mappings stay below 4 GiB, even with a hint above. */
+void test_map_32bit(void)
+{
+    char *high = (char *)(16UL << 30), *addr;
+
+    addr = mmap(high, PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(addr == high, "hint above 4 GiB");
+    CHECK(munmap(addr, PAGE_SIZE) == 0, "munmap");
+    addr = mmap(high, PAGE_SIZE, PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS | MAP_32BIT, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap MAP_32BIT");
+    CHECK((uintptr_t)addr + PAGE_SIZE <= (1UL << 32), "below 4 GiB");
+    addr[0] = 'a';
+    CHECK(munmap(addr, PAGE_SIZE) == 0, "munmap MAP_32BIT");
+    errno = 0;
+    CHECK(mmap(NULL, 5UL << 30, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS | MAP_32BIT, -1, 0)
+          == MAP_FAILED && errno == ENOMEM, "larger than 4 GiB");
+    printf("%s ok\n", __func__);
+}
+
+/* A mapping past the end of a short file reads as zero after the file
+ * contents, even where a previous mapping left other bytes. */
+void test_file_tail_zero(void)
+{
+    char *addr, *again;
+    int fd, i;
+
+    fd = open("mmtest_short", O_RDWR | O_CREAT | O_TRUNC, 0600);
+    CHECK(fd >= 0, "open");
+    CHECK(write(fd, "short", 5) == 5, "write");
+
+    addr = mmap(NULL, 2 * PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    CHECK(addr != MAP_FAILED, "mmap");
+    memset(addr, 0x5a, 2 * PAGE_SIZE);
+    CHECK(munmap(addr, 2 * PAGE_SIZE) == 0, "munmap");
+
+    again = mmap(addr, 2 * PAGE_SIZE, PROT_READ, MAP_PRIVATE, fd, 0);
+    CHECK(again == addr, "mmap the file at the same spot");
+    CHECK(memcmp(again, "short", 5) == 0, "file contents");
+    for (i = 5; i < 2 * PAGE_SIZE; i++)
+        CHECK(again[i] == 0, "tail zeroed");
+    CHECK(munmap(again, 2 * PAGE_SIZE) == 0, "munmap file");
+    CHECK(close(fd) == 0, "close");
+    printf("%s ok\n", __func__);
+}
+
+/* Shared file mappings can't be both writable and executable. */
+void test_shared_wx(void)
+{
+    int prot = PROT_READ | PROT_WRITE | PROT_EXEC;
+    char *addr;
+    int fd;
+
+    fd = open("mmtest_wx", O_RDWR | O_CREAT | O_TRUNC, 0600);
+    CHECK(fd >= 0, "open");
+    CHECK(write(fd, "wx", 2) == 2, "write");
+    errno = 0;
+    CHECK(mmap(NULL, PAGE_SIZE, prot, MAP_SHARED, fd, 0) == MAP_FAILED && errno == EACCES,
+          "shared writable and executable");
+    addr = mmap(NULL, PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+    CHECK(addr != MAP_FAILED && munmap(addr, PAGE_SIZE) == 0, "shared writable");
+    addr = mmap(NULL, PAGE_SIZE, PROT_READ | PROT_EXEC, MAP_SHARED, fd, 0);
+    CHECK(addr != MAP_FAILED && munmap(addr, PAGE_SIZE) == 0, "shared executable");
+    addr = mmap(NULL, PAGE_SIZE, prot, MAP_PRIVATE, fd, 0);
+    CHECK(addr != MAP_FAILED && munmap(addr, PAGE_SIZE) == 0, "private");
+    addr = mmap(NULL, PAGE_SIZE, prot, MAP_SHARED | MAP_ANONYMOUS, -1, 0);
+    CHECK(addr != MAP_FAILED && munmap(addr, PAGE_SIZE) == 0, "shared anonymous");
+    CHECK(close(fd) == 0, "close");
+    printf("%s ok\n", __func__);
+}
+
+int main()
+{
+    printf("MmTest ...\n");
+
+    test_populate();
+    test_reuse();
+    test_mmap_overflow();
+    test_mremap_shrink();
+    test_hugetlb();
+    test_mprotect_none();
+    test_anon_zero();
+    test_futex();
+    test_clone();
+    test_rlimit_nofile();
+    test_getcwd();
+    test_faccessat();
+    test_fcntl();
+    test_sigprocmask();
+    test_thread_exit();
+    test_fixed_overflow();
+    test_max_map_count();
+    test_dump_maps();
+    test_regions();
+    test_munmap_split();
+    test_dispatch();
+    test_unimplemented_hits();
+    test_mlock();
+    test_shared_readonly();
+    test_private_shared();
+    test_ioctl();
+    test_mmap_bad_fd();
+    test_shared_anon();
+    test_statx();
+    test_user_access();
+    test_writev_partial();
+    test_open_create_append();
+    test_times();
+    test_ids();
+    test_readlink();
+    test_getppid();
+    test_prctl_name();
+    test_robust_list();
+    test_membarrier();
+    test_map_32bit();
+    test_file_tail_zero();
+    test_shared_wx();
+
+    printf("MmTest ok!\n");
+    /* Last, as it ends the process. */
+    test_segv();
+    return 0;
+}
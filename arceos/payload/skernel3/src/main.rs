@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+/// Stops without SBI, as told by the mode word at `0x50`: 0 prints "hc" and
+/// shuts down by hypercall with the code at `0x40`, 1 waits for interrupts
+/// which never come, 2 pushes until it runs off its stack.
+#[no_mangle]
+unsafe extern "C" fn _start() -> ! {
+    core::arch::asm!(
+        "ld t0, 0x50(zero)",
+        "li t1, 2",
+        "beq t0, t1, 3f",
+        "bnez t0, 2f",
+        // HYPERCALL_EID, the call number goes in a6.
+        "li a7, 0x0a000000",
+        "li a6, 0", // HC_PUTCHAR
+        "li a0, 0x68", // 'h'
+        "ecall",
+        "li a0, 0x63", // 'c'
+        "ecall",
+        "li a0, 0x0a", // '\n'
+        "ecall",
+        "ld a0, 0x40(zero)",
+        "li a6, 1", // HC_SHUTDOWN
+        "ecall",
+        "2:",
+        "wfi",
+        "j 2b",
+        "3:",
+        "addi sp, sp, -8",
+        "sd zero, 0(sp)",
+        "j 3b",
+        options(noreturn)
+    )
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
@@ -14,6 +14,7 @@ make disk_img
 
 make payload
 ./update_disk.sh payload/skernel2/skernel2
+./update_disk.sh payload/skernel3/skernel3
 
 make run A=exercises/simple_hv/ BLK=y | tee $tmp_file
 